@@ -0,0 +1,41 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ifl_core::feature::{StructureAnalyzer, PREVIEW_SAMPLE_BYTES};
+use ifl_core::keywords::KeywordDictionary;
+use ifl_core::tokens::TokenizerFamily;
+
+// Multi-megabyte pastes: full analysis runs once on submit, the sampled path
+// runs per keystroke while the paste sits in the draft.
+fn paste_of(bytes: usize) -> String {
+    let block =
+        "2024-05-01 10:42:17 ERROR request failed: HTTP 500 from https://api.example.com/v1/jobs\n\
+                 - retry the job with backoff\n\
+                 - check the deployment config\n    let result = compute(1 + 2);\n\
+                 デプロイ後にタイムアウトが発生しました。\n\n";
+    block.repeat(bytes / block.len() + 1)
+}
+
+fn bench_analyze(c: &mut Criterion) {
+    let keywords = KeywordDictionary::builtin();
+    let mut group = c.benchmark_group("structure_analyze");
+    group.sample_size(10);
+    for mb in [1, 4] {
+        let text = paste_of(mb * 1024 * 1024);
+        group.bench_with_input(BenchmarkId::new("full", mb), &text, |b, text| {
+            b.iter(|| StructureAnalyzer::analyze_with(text, keywords, TokenizerFamily::Generic))
+        });
+        group.bench_with_input(BenchmarkId::new("sampled", mb), &text, |b, text| {
+            b.iter(|| {
+                StructureAnalyzer::analyze_sampled(
+                    text,
+                    keywords,
+                    TokenizerFamily::Generic,
+                    PREVIEW_SAMPLE_BYTES,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_analyze);
+criterion_main!(benches);
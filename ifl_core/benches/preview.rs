@@ -0,0 +1,64 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ifl_core::{DeleteKind, IflCore, InputEvent};
+
+// Preview is called per keystroke, so its cost must not grow with the session length.
+fn session_with_events(count: usize) -> (IflCore, String) {
+    let core = IflCore::new();
+    let id = core.start_message().unwrap();
+    let mut ts = 1000;
+    for i in 0..count {
+        let event = match i % 20 {
+            19 => InputEvent::KeyDelete {
+                kind: DeleteKind::Backspace,
+                count: 1,
+                ts,
+            },
+            13 => InputEvent::GhostText {
+                text: format!("fragment {}", i),
+                ts,
+            },
+            _ => InputEvent::KeyInsert { ch: 'a', ts },
+        };
+        core.push_event(&id, event).unwrap();
+        ts += 120;
+    }
+    (core, id)
+}
+
+/// A message typed key by key with a varying rhythm, as a long draft is.
+fn typed_session(count: usize) -> (IflCore, String) {
+    let core = IflCore::new();
+    let id = core.start_message().unwrap();
+    let mut ts = 1000;
+    for i in 0..count {
+        core.push_event(&id, InputEvent::KeyInsert { ch: 'a', ts })
+            .unwrap();
+        ts += 80 + (i as u64 * 37) % 140;
+    }
+    (core, id)
+}
+
+fn bench_preview(c: &mut Criterion) {
+    let text = "Please summarize the following notes.\n- item one\n- item two\n- item three";
+    let mut group = c.benchmark_group("preview_message");
+    for count in [1_000, 10_000, 50_000] {
+        let (core, id) = session_with_events(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| core.preview_message(&id, text).unwrap())
+        });
+    }
+    group.finish();
+
+    // Only keystrokes: the rhythm features must not grow with them either
+    let mut group = c.benchmark_group("preview_message_typed");
+    for count in [1_000, 10_000, 100_000] {
+        let (core, id) = typed_session(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| core.preview_message(&id, text).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_preview);
+criterion_main!(benches);
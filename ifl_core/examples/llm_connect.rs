@@ -1,7 +1,6 @@
+use ifl_core::backend::MockBackend;
 use ifl_core::llm_client::LlmClient;
 use ifl_core::{IflCore, InputEvent};
-use std::time::Duration;
-use tokio;
 
 #[tokio::main]
 async fn main() {
@@ -9,10 +8,28 @@ async fn main() {
     let core = IflCore::new();
     // Assuming local LLM is running at default URL.
     // If you use a different model or URL, change it here.
-    let llm_client = LlmClient::new(None, None);
+    // With `--mock`, no model is needed: the answer echoes the directives.
+    let llm_client = if std::env::args().any(|arg| arg == "--mock") {
+        LlmClient::new(None, Some("mock".to_string())).with_backend(MockBackend::new())
+    } else {
+        LlmClient::new(None, None)
+    };
+    if let Err(e) = llm_client.health().await {
+        eprintln!(
+            "LLM unavailable: {} (run with --mock to try without one)",
+            e
+        );
+        return;
+    }
 
     // 2. Start Session
-    let session_id = core.start_message();
+    let session_id = match core.start_message() {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("Error starting session: {}", e);
+            return;
+        }
+    };
     println!("Session started: {}", session_id);
 
     // 3. Simulate Input: "Can you summarize this text?" (implies Summarize mode)
@@ -53,12 +70,25 @@ async fn main() {
             return;
         }
     };
-    println!("Analysis: {:?}", profile.tags);
+    let context = llm_client.prompt_context(&profile);
+    println!(
+        "Analysis: tone {}, depth {}, scope {}, modes {}",
+        context.labels.tone, context.labels.depth, context.labels.scope, context.labels.modes
+    );
 
     // 5. Call LLM
     println!("Sending to LLM...");
-    match llm_client.generate_response(text, &profile.tags).await {
-        Ok(response) => println!("LLM Response:\n{}", response),
+    match llm_client.generate_response(text, &profile).await {
+        Ok(response) => {
+            println!("LLM Response:\n{}", response.content);
+            println!(
+                "({} prompt + {} completion tokens, {} ms, {})",
+                response.prompt_tokens,
+                response.completion_tokens,
+                response.latency_ms,
+                response.model
+            );
+        }
         Err(e) => println!("Error calling LLM: {}", e),
     }
 }
@@ -1,13 +1,11 @@
 use ifl_core::{IflCore, InputEvent};
-use std::thread;
-use std::time::Duration;
 
 fn main() {
     // 1. IFL Coreのインスタンスを作成
     let core = IflCore::new();
 
     // 2. 新しいメッセージセッションを開始
-    let session_id = core.start_message();
+    let session_id = core.start_message().unwrap();
     println!("Session started: {}", session_id);
 
     // 3. ユーザーの入力をシミュレーション
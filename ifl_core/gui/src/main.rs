@@ -1,8 +1,24 @@
 #![allow(non_snake_case)]
 use chrono::Utc;
 use dioxus::prelude::*;
-use ifl_core::llm_client::LlmClient;
+use ifl_core::backend::MockBackend;
+use ifl_core::llm_client::{Conversation, LlmClient, LlmResponse};
 use ifl_core::{profile::AnswerTags, DeleteKind, IflCore, InputEvent};
+use std::sync::Arc;
+
+/// Local Ollama server; its native API is used when available.
+const OLLAMA_URL: &str = "http://localhost:11434";
+/// Model name that answers with `MockBackend`, for trying the UI offline.
+const MOCK_MODEL: &str = "mock";
+
+/// The client for `model`: the mock backend, or the local server.
+async fn connect(model: String) -> LlmClient {
+    if model == MOCK_MODEL {
+        LlmClient::new(None, Some(model)).with_backend(MockBackend::new())
+    } else {
+        LlmClient::detect(OLLAMA_URL, Some(model)).await
+    }
+}
 
 fn main() {
     launch(App);
@@ -10,7 +26,7 @@ fn main() {
 
 fn App() -> Element {
     // Global State
-    let mut core = use_signal(|| IflCore::new());
+    let mut core = use_signal(|| IflCore::new().with_rule_trace(true));
     let mut session_id = use_signal(|| {
         core.read()
             .start_message()
@@ -19,6 +35,11 @@ fn App() -> Element {
     let mut text = use_signal(|| String::new());
     let mut messages = use_signal(|| Vec::<(String, bool)>::new());
     let mut analysis = use_signal(|| None::<ifl_core::profile::InputProfile>);
+    let mut conversation = use_signal(Conversation::new);
+    let mut generation = use_signal(|| None::<LlmResponse>);
+    // The client for the chosen model, detected once per model and reused
+    // for the health check, the prompt preview and every message
+    let mut llm = use_signal(|| None::<Arc<LlmClient>>);
 
     // Handlers
     let mut submit_message = move |input_text: String, model_name: String| {
@@ -52,15 +73,44 @@ fn App() -> Element {
                         let profile_clone = profile.clone();
                         let prompt_text = input_text.clone();
                         let model = model_name.clone();
+                        let detected = llm.read().clone();
                         spawn(async move {
-                            let llm_client = LlmClient::new(None, Some(model));
-                            match llm_client
-                                .generate_response(&prompt_text, &profile_clone)
-                                .await
-                            {
-                                Ok(response) => messages.write().push((response, false)),
+                            // Sent before detection finished: connect for this one
+                            let llm_client = match detected {
+                                Some(client) => client,
+                                None => Arc::new(connect(model).await),
+                            };
+                            // Render tokens into one reply bubble as they arrive
+                            let reply = {
+                                let mut messages = messages.write();
+                                messages.push((String::new(), false));
+                                messages.len() - 1
+                            };
+                            // Earlier turns go along so follow-ups keep their context
+                            let mut history = conversation.peek().clone();
+                            // Tokens come through a channel: the callback must be
+                            // `Send`, and signals are not
+                            let (tokens, mut received) =
+                                tokio::sync::mpsc::unbounded_channel::<String>();
+                            let send = history.send_stream(
+                                &llm_client,
+                                &prompt_text,
+                                &profile_clone,
+                                move |token| {
+                                    let _ = tokens.send(token.to_string());
+                                },
+                            );
+                            let render = async {
+                                while let Some(token) = received.recv().await {
+                                    messages.write()[reply].0.push_str(&token);
+                                }
+                            };
+                            let (result, ()) = tokio::join!(send, render);
+                            conversation.set(history);
+                            match result {
+                                Ok(response) => generation.set(Some(response)),
                                 Err(e) => {
-                                    messages.write().push((format!("LLM Error: {}", e), false))
+                                    messages.write()[reply].0 = format!("LLM Error: {}", e);
                                 }
                             }
                         });
@@ -106,7 +156,14 @@ fn App() -> Element {
             if diff > 1 {
                 // Paste detected (heuristic)
                 println!("Paste detected: length={}", diff);
-                if let Err(e) = core_ref.push_event(&id, InputEvent::Paste { length: diff, ts }) {
+                if let Err(e) = core_ref.push_event(
+                    &id,
+                    InputEvent::Paste {
+                        length: diff,
+                        ts,
+                        content_hash: None,
+                    },
+                ) {
                     println!("Input Error (ignored): {}", e);
                 }
             } else {
@@ -164,13 +221,24 @@ fn App() -> Element {
 
     let mut model_name = use_signal(|| "llama3.1".to_string());
 
+    // Check the LLM on startup and when the model changes, so a missing
+    // server or model is reported up front rather than mid-chat
+    let health = use_resource(move || async move {
+        llm.set(None);
+        let client = Arc::new(connect(model_name()).await);
+        llm.set(Some(client.clone()));
+        client.health().await.err()
+    });
+    let backend_error = health.read().clone().flatten();
+
     rsx! {
         div { class: "flex h-screen bg-gray-900 text-white font-sans",
             // Tailwind
             script { src: "https://cdn.tailwindcss.com" }
 
-            Sidebar { analysis: analysis, model_name: model_name }
+            Sidebar { analysis: analysis, generation: generation, model_name: model_name, llm: llm }
             ChatArea {
+                backend_error: backend_error,
                 messages: messages,
                 text: text,
                 on_submit: move |input_text| {
@@ -185,16 +253,40 @@ fn App() -> Element {
 #[component]
 fn Sidebar(
     analysis: Signal<Option<ifl_core::profile::InputProfile>>,
+    generation: Signal<Option<LlmResponse>>,
     model_name: Signal<String>,
+    llm: Signal<Option<Arc<LlmClient>>>,
 ) -> Element {
-    let system_prompt = use_memo(move || {
-        if let Some(profile) = analysis.read().as_ref() {
-            let client = LlmClient::new(None, Some(model_name.read().clone()));
-            client.build_system_prompt(profile)
-        } else {
-            "Waiting for input...".to_string()
+    // Tags the prompt only hedges on are marked with "?"
+    let prompt = use_memo(move || {
+        let hint = |label: &str, tentative: bool| {
+            if tentative {
+                format!("{}?", label)
+            } else {
+                label.to_string()
+            }
+        };
+        match analysis.read().as_ref() {
+            Some(profile) => {
+                // A stand-in for the model while its client is detected
+                let client = llm.read().clone().unwrap_or_else(|| {
+                    Arc::new(LlmClient::new(None, Some(model_name.read().clone())))
+                });
+                let context = client.prompt_context(profile);
+                (
+                    client.render_context(&context).system_prompt,
+                    hint(&context.labels.tone, context.tentative.tone),
+                    hint(&context.labels.depth, context.tentative.depth),
+                )
+            }
+            None => (
+                "Waiting for input...".to_string(),
+                String::new(),
+                String::new(),
+            ),
         }
     });
+    let (system_prompt, tone, depth) = prompt();
 
     rsx! {
         div { class: "w-1/3 p-4 bg-gray-900 border-r border-blue-900 flex flex-col gap-4 overflow-y-auto font-mono",
@@ -210,6 +302,7 @@ fn Sidebar(
                 input {
                     class: "bg-gray-800 border border-gray-700 rounded px-2 py-1 text-xs text-gray-300 focus:border-blue-500 outline-none",
                     value: "{model_name}",
+                    title: "\"{MOCK_MODEL}\" answers without a model",
                     oninput: move |evt| model_name.set(evt.value())
                 }
             }
@@ -234,7 +327,7 @@ fn Sidebar(
                 // Metrics HUD
                 div { class: "grid grid-cols-2 gap-3",
                     MetricCard { label: "SPEED", value: format!("{:.1}", profile.timing.avg_chars_per_sec), unit: "CPS", color: "text-cyan-400" }
-                    MetricCard { label: "CONFIDENCE", value: format!("{:.0}%", profile.tags.confidence * 100.0), unit: "", color: "text-green-400" }
+                    MetricCard { label: "CONFIDENCE", value: format!("{:.0}%", profile.tags.confidence.mode * 100.0), unit: "", color: "text-green-400" }
                     MetricCard { label: "BURSTS", value: format!("{}", profile.timing.typing_bursts), unit: "", color: "text-yellow-400" }
                     MetricCard { label: "EDITS", value: format!("{}", profile.editing.backspace_count), unit: "", color: "text-red-400" }
                 }
@@ -248,8 +341,30 @@ fn Sidebar(
                         }
                     }
                     div { class: "flex justify-between text-xs text-gray-400",
-                        span { "Tone: {profile.tags.tone_hint:?}" }
-                        span { "Depth: {profile.tags.depth_hint:?}" }
+                        span { "Tone: {tone}" }
+                        span { "Depth: {depth}" }
+                    }
+                }
+
+                // Cost of the last reply
+                if let Some(response) = generation.read().as_ref() {
+                    div { class: "p-4 bg-gray-800/50 border border-cyan-500/30 rounded-lg",
+                        h3 { class: "text-xs text-cyan-300 uppercase mb-2 tracking-wider", "Last Generation" }
+                        div { class: "grid grid-cols-2 gap-3",
+                            MetricCard { label: "LATENCY", value: format!("{}", response.latency_ms), unit: "MS", color: "text-cyan-400" }
+                            MetricCard {
+                                label: "OUTPUT",
+                                value: format!("{:.1}", response.completion_tokens as f64 * 1000.0 / response.latency_ms.max(1) as f64),
+                                unit: "TOK/S",
+                                color: "text-green-400"
+                            }
+                            MetricCard { label: "PROMPT", value: format!("{}", response.prompt_tokens), unit: "TOKENS", color: "text-yellow-400" }
+                            MetricCard { label: "REPLY", value: format!("{}", response.completion_tokens), unit: "TOKENS", color: "text-red-400" }
+                        }
+                        div { class: "flex justify-between text-xs text-gray-400 mt-2",
+                            span { "{response.model}" }
+                            span { {response.finish_reason.clone().unwrap_or_else(|| "-".to_string())} }
+                        }
                     }
                 }
 
@@ -314,7 +429,20 @@ fn AnalysisDetails(tags: AnswerTags) -> Element {
             }
             div { class: "p-4 bg-gray-700 rounded-lg",
                 h3 { class: "text-sm text-gray-400 uppercase", "Confidence" }
-                div { class: "text-xl", "{tags.confidence:.2}" }
+                div { class: "text-xl", "Mode {tags.confidence.mode:.2}" }
+                div { class: "text-sm text-gray-400",
+                    "Tone {tags.confidence.tone:.2} · Depth {tags.confidence.depth:.2} · State {tags.confidence.user_state:.2}"
+                }
+            }
+            div { class: "p-4 bg-gray-700 rounded-lg",
+                h3 { class: "text-sm text-gray-400 uppercase", "Fired Rules" }
+                ul {
+                    for fire in &tags.rule_trace {
+                        li { class: "text-xs", title: "{fire.description}",
+                            "{fire.rule_id}: {fire.contribution:?}"
+                        }
+                    }
+                }
             }
         }
     }
@@ -322,6 +450,7 @@ fn AnalysisDetails(tags: AnswerTags) -> Element {
 
 #[component]
 fn ChatArea(
+    backend_error: Option<String>,
     messages: Signal<Vec<(String, bool)>>,
     text: Signal<String>,
     on_submit: EventHandler<String>,
@@ -329,6 +458,11 @@ fn ChatArea(
 ) -> Element {
     rsx! {
         div { class: "flex-1 flex flex-col",
+            if let Some(error) = backend_error {
+                div { class: "m-4 mb-0 p-3 rounded-lg bg-red-900 border border-red-700 text-sm text-red-200",
+                    "{error}"
+                }
+            }
             MessageList { messages: messages }
             InputArea { text: text, on_submit: on_submit, on_input: on_input }
         }
@@ -1,26 +1,177 @@
+use crate::baseline::UserBaseline;
+use crate::calibration::Calibrator;
 use crate::event::InputEvent;
-use crate::feature::{FeatureExtractor, StructureAnalyzer};
-use crate::profile::InputProfile;
-use crate::rules::RuleEngine;
-use std::collections::HashMap;
+use crate::feature::{ExtractorConfig, FeatureExtractor, StructureAnalyzer, PREVIEW_SAMPLE_BYTES};
+use crate::keywords::KeywordDictionary;
+use crate::llm_client::{LlmClient, PromptContext};
+#[cfg(feature = "ml")]
+use crate::ml::MlRuleEngine;
+use crate::profile::{ClockContext, InputProfile, RephraseSignal, TurnSummary};
+#[cfg(feature = "ml")]
+use crate::rules::RuleConfig;
+use crate::rules::{Features, Rule, RuleEngine, RuleExperiment};
+use crate::tokens::TokenizerFamily;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
+/// Which backend produces the answer tags.
+#[derive(Debug, Clone, Default)]
+pub enum Engine {
+    /// The declarative rule set.
+    #[default]
+    Rules,
+    /// Only the trained model.
+    #[cfg(feature = "ml")]
+    Ml(Arc<MlRuleEngine>),
+    /// The rule set, then the model on top of it.
+    #[cfg(feature = "ml")]
+    Hybrid(Arc<MlRuleEngine>),
+}
+
+/// A message's experiment arm ("experiment:variant") and the rules it applies.
+type RuleArm = (String, Arc<RuleEngine>);
+
 #[derive(Clone)]
 pub struct IflCore {
     sessions: Arc<Mutex<HashMap<String, FeatureExtractor>>>,
+    baseline: Arc<Mutex<UserBaseline>>,
+    calibrator: Arc<Mutex<Calibrator>>,
+    extractor_config: ExtractorConfig,
+    keywords: Arc<KeywordDictionary>,
+    rules: Arc<RuleEngine>,
+    rule_experiment: Option<Arc<RuleExperiment>>,
+    rule_assignments: Arc<Mutex<HashMap<String, RuleArm>>>,
+    engine: Engine,
+    custom_rules: Arc<Mutex<Vec<Arc<dyn Rule>>>>,
+    tokenizer: TokenizerFamily,
+    rule_trace: bool,
+    clarify_threshold: f32,
+    paste_history: Arc<Mutex<VecDeque<u64>>>,
+    turn_history: Arc<Mutex<VecDeque<TurnSummary>>>,
+    clock_contexts: Arc<Mutex<HashMap<String, ClockContext>>>,
+    rephrase_signals: Arc<Mutex<HashMap<String, RephraseSignal>>>,
+}
+
+/// Paste hashes remembered across the conversation.
+const PASTE_HISTORY_CAPACITY: usize = 64;
+/// Earlier messages visible to conversation-context rules.
+const TURN_HISTORY_CAPACITY: usize = 16;
+/// Overall tag confidence below which the model should ask before answering.
+/// With the rules' base confidence of 0.5 this catches messages that only
+/// weak hints (mode confidence +0.1) recognized.
+const DEFAULT_CLARIFY_THRESHOLD: f32 = 0.53;
+
+impl Default for IflCore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IflCore {
     pub fn new() -> Self {
+        Self::with_extractor_config(ExtractorConfig::default())
+    }
+
+    pub fn with_extractor_config(extractor_config: ExtractorConfig) -> Self {
         Self {
             sessions: Arc::new(Mutex::new(HashMap::new())),
+            baseline: Arc::new(Mutex::new(UserBaseline::new())),
+            calibrator: Arc::new(Mutex::new(Calibrator::new())),
+            extractor_config,
+            keywords: Arc::new(KeywordDictionary::default()),
+            rules: Arc::new(RuleEngine::default()),
+            rule_experiment: None,
+            rule_assignments: Arc::new(Mutex::new(HashMap::new())),
+            engine: Engine::default(),
+            custom_rules: Arc::new(Mutex::new(Vec::new())),
+            tokenizer: TokenizerFamily::default(),
+            rule_trace: false,
+            clarify_threshold: DEFAULT_CLARIFY_THRESHOLD,
+            paste_history: Arc::new(Mutex::new(VecDeque::new())),
+            turn_history: Arc::new(Mutex::new(VecDeque::new())),
+            clock_contexts: Arc::new(Mutex::new(HashMap::new())),
+            rephrase_signals: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Replace the built-in request keyword dictionaries (e.g. loaded from TOML).
+    pub fn with_keywords(mut self, keywords: KeywordDictionary) -> Self {
+        self.keywords = Arc::new(keywords);
+        self
+    }
+
+    /// Replace the built-in rule set (e.g. loaded with `RuleEngine::from_config`).
+    pub fn with_rule_engine(mut self, rules: RuleEngine) -> Self {
+        self.rules = Arc::new(rules);
+        self
+    }
+
+    /// Tag each message with the rule set the experiment assigns to its
+    /// message id, recording the arm as `rule_variant` in its profiles. All
+    /// variants stay loaded, so one core compares them side by side.
+    pub fn with_rule_experiment(mut self, experiment: &RuleExperiment) -> Result<Self, String> {
+        if experiment.variant_names().is_empty() {
+            return Err(format!("Experiment {} has no variants", experiment.id));
+        }
+        self.rule_experiment = Some(Arc::new(experiment.clone()));
+        Ok(self)
+    }
+
+    /// Select the tagging backend, e.g. a model loaded with `MlRuleEngine::from_file`.
+    pub fn with_engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// Estimate `estimated_tokens` with the heuristic for the target model family.
+    pub fn with_tokenizer(mut self, tokenizer: TokenizerFamily) -> Self {
+        self.tokenizer = tokenizer;
+        self
+    }
+
+    /// Record which rules fired in `tags.rule_trace` (off by default).
+    pub fn with_rule_trace(mut self, enabled: bool) -> Self {
+        self.rule_trace = enabled;
+        self
+    }
+
+    /// Overall confidence below which profiles set `clarify_before_answering`;
+    /// 0.0 disables it.
+    pub fn with_clarify_threshold(mut self, threshold: f32) -> Self {
+        self.clarify_threshold = threshold;
+        self
+    }
+
+    /// Register a rule that runs after the built-in rules for every later message.
+    pub fn register_rule(&self, rule: impl Rule + 'static) -> Result<(), String> {
+        let mut custom_rules = self
+            .custom_rules
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?;
+        let id = rule.id();
+        if self.rules.config().rules.iter().any(|r| r.id == id)
+            || custom_rules.iter().any(|r| r.id() == id)
+        {
+            return Err(format!("Rule {} is already registered", id));
         }
+        custom_rules.push(Arc::new(rule));
+        Ok(())
     }
 
     pub fn start_message(&self) -> Result<String, String> {
         let id = Uuid::new_v4().to_string();
-        let extractor = FeatureExtractor::new();
+        let extractor = FeatureExtractor::with_config(self.extractor_config);
+        if let Some(experiment) = &self.rule_experiment {
+            let (variant, rules) = experiment.assign(&id)?;
+            self.rule_assignments
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?
+                .insert(
+                    id.clone(),
+                    (format!("{}:{}", experiment.id, variant), rules),
+                );
+        }
         self.sessions
             .lock()
             .map_err(|_| "Mutex poisoned".to_string())?
@@ -41,32 +192,111 @@ impl IflCore {
         }
     }
 
+    /// Local time for a message, enabling the time-of-day and cadence rules
+    /// (`clock.*`). Without it those rules never fire.
+    pub fn set_clock_context(&self, message_id: &str, clock: ClockContext) -> Result<(), String> {
+        if clock.local_hour > 23 {
+            return Err(format!("Invalid local hour {}", clock.local_hour));
+        }
+        if !self
+            .sessions
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .contains_key(message_id)
+        {
+            return Err(format!("Message ID {} not found", message_id));
+        }
+        self.clock_contexts
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .insert(message_id.to_string(), clock);
+        Ok(())
+    }
+
+    /// Mark a message as a rephrase of an earlier one, e.g. from
+    /// `RephraseDetector::check`, for the `history.rephrase.*` rules and the
+    /// prompt.
+    pub fn set_rephrase_signal(
+        &self,
+        message_id: &str,
+        signal: RephraseSignal,
+    ) -> Result<(), String> {
+        if !self
+            .sessions
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .contains_key(message_id)
+        {
+            return Err(format!("Message ID {} not found", message_id));
+        }
+        self.rephrase_signals
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .insert(message_id.to_string(), signal);
+        Ok(())
+    }
+
     pub fn finalize_message(&self, message_id: &str, final_text: &str) -> Result<String, String> {
         let mut sessions = self
             .sessions
             .lock()
             .map_err(|_| "Mutex poisoned".to_string())?;
         if let Some(extractor) = sessions.remove(message_id) {
-            // 1. Extract features
-            let source = extractor.extract_source_features(0u64);
-            let timing = extractor.extract_timing_features();
-            let structure = StructureAnalyzer::analyze(final_text);
-            let editing = extractor.extract_editing_features(structure.char_count);
-
-            let tags = RuleEngine::apply(&source, &timing, &editing, &structure);
-
-            // Extract Ghost Text
-            let ghost_text = extractor.extract_ghost_text();
-
-            let profile = InputProfile {
-                message_id: message_id.to_string(),
-                source,
-                timing,
-                editing,
-                structure,
-                tags,
-                ghost_text,
-            };
+            let profile = self.build_profile(message_id, &extractor, final_text, false)?;
+            self.clock_contexts
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?
+                .remove(message_id);
+            self.rephrase_signals
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?
+                .remove(message_id);
+            self.rule_assignments
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?
+                .remove(message_id);
+
+            // Fold this message into the user's baseline after normalizing against it
+            self.baseline
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?
+                .update(
+                    &profile.source,
+                    &profile.timing,
+                    &profile.editing,
+                    &profile.structure,
+                );
+
+            let mut history = self
+                .paste_history
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?;
+            for hash in extractor.paste_hashes() {
+                if !history.contains(hash) {
+                    history.push_back(*hash);
+                }
+            }
+            while history.len() > PASTE_HISTORY_CAPACITY {
+                history.pop_front();
+            }
+
+            let events = extractor.get_events();
+            let mut turns = self
+                .turn_history
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?;
+            turns.push_back(TurnSummary {
+                started_at: events.first().map_or(0, |e| e.ts()),
+                ended_at: events.iter().map(|e| e.ts()).max().unwrap_or(0),
+                char_count: profile.structure.char_count,
+                question_like: profile.structure.question_like,
+                repeated_paste: profile.source.repeated_paste,
+                answer_mode: profile.tags.answer_mode.clone(),
+                depth_hint: profile.tags.depth_hint.clone(),
+            });
+            while turns.len() > TURN_HISTORY_CAPACITY {
+                turns.pop_front();
+            }
 
             serde_json::to_string_pretty(&profile).map_err(|e| e.to_string())
         } else {
@@ -74,39 +304,221 @@ impl IflCore {
         }
     }
 
+    /// Drop a message that will never be sent, e.g. when the client went
+    /// away mid-draft. Unlike `finalize_message` it leaves the baseline and
+    /// turn history untouched. False if the message was not open.
+    pub fn discard_message(&self, message_id: &str) -> Result<bool, String> {
+        let removed = self
+            .sessions
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .remove(message_id)
+            .is_some();
+        self.clock_contexts
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .remove(message_id);
+        self.rephrase_signals
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .remove(message_id);
+        self.rule_assignments
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .remove(message_id);
+        Ok(removed)
+    }
+
     pub fn preview_message(&self, message_id: &str, current_text: &str) -> Result<String, String> {
         let sessions = self
             .sessions
             .lock()
             .map_err(|_| "Mutex poisoned".to_string())?;
         if let Some(extractor) = sessions.get(message_id) {
-            // 1. Extract features (non-destructive)
-            let source = extractor.extract_source_features(0u64);
-            let timing = extractor.extract_timing_features();
-            let structure = StructureAnalyzer::analyze(current_text);
-            let editing = extractor.extract_editing_features(structure.char_count);
-
-            let tags = RuleEngine::apply(&source, &timing, &editing, &structure);
-
-            // Extract Ghost Text
-            let ghost_text = extractor.extract_ghost_text();
-
-            let profile = InputProfile {
-                message_id: message_id.to_string(),
-                source,
-                timing,
-                editing,
-                structure,
-                tags,
-                ghost_text,
-            };
-
+            // Non-destructive: the baseline is left untouched
+            let profile = self.build_profile(message_id, extractor, current_text, true)?;
             serde_json::to_string_pretty(&profile).map_err(|e| e.to_string())
         } else {
             Err(format!("Message ID {} not found", message_id))
         }
     }
 
+    fn build_profile(
+        &self,
+        message_id: &str,
+        extractor: &FeatureExtractor,
+        text: &str,
+        preview: bool,
+    ) -> Result<InputProfile, String> {
+        // 1. Extract features
+        let mut source = extractor.extract_source_features(0u64);
+        {
+            let history = self
+                .paste_history
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?;
+            source.repeated_paste = extractor.paste_hashes().iter().any(|h| history.contains(h));
+        }
+        let timing = extractor.extract_timing_features();
+        let structure = if preview {
+            StructureAnalyzer::analyze_sampled(
+                text,
+                &self.keywords,
+                self.tokenizer,
+                PREVIEW_SAMPLE_BYTES,
+            )
+        } else {
+            StructureAnalyzer::analyze_with(text, &self.keywords, self.tokenizer)
+        };
+        let editing = extractor.extract_editing_features(structure.char_count);
+
+        let normalized = self
+            .baseline
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .normalize(&timing, &editing, &structure);
+
+        let history: Vec<TurnSummary> = self
+            .turn_history
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .iter()
+            .cloned()
+            .collect();
+        let clock = self
+            .clock_contexts
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .get(message_id)
+            .cloned();
+        let rephrase = self
+            .rephrase_signals
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .get(message_id)
+            .cloned();
+        let (rule_variant, rules) = match self
+            .rule_assignments
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .get(message_id)
+        {
+            Some((variant, rules)) => (Some(variant.clone()), rules.clone()),
+            None => (None, self.rules.clone()),
+        };
+
+        let mut tags = {
+            let custom_rules = self
+                .custom_rules
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?;
+            let features = Features {
+                source: &source,
+                timing: &timing,
+                editing: &editing,
+                structure: &structure,
+                normalized: normalized.as_ref(),
+                history: &history,
+                started_at: extractor.get_events().first().map(|e| e.ts()),
+                clock: clock.as_ref(),
+                rephrase: rephrase.as_ref(),
+            };
+            match &self.engine {
+                Engine::Rules => rules.apply(&features, &custom_rules, self.rule_trace),
+                #[cfg(feature = "ml")]
+                Engine::Ml(model) => {
+                    let empty = RuleEngine::new(RuleConfig {
+                        base_confidence: model.model().threshold,
+                        rules: Vec::new(),
+                    })?;
+                    let mut plugins: Vec<Arc<dyn Rule>> = vec![model.clone()];
+                    plugins.extend(custom_rules.iter().cloned());
+                    empty.apply(&features, &plugins, self.rule_trace)
+                }
+                #[cfg(feature = "ml")]
+                Engine::Hybrid(model) => {
+                    let mut plugins: Vec<Arc<dyn Rule>> = vec![model.clone()];
+                    plugins.extend(custom_rules.iter().cloned());
+                    rules.apply(&features, &plugins, self.rule_trace)
+                }
+            }
+        };
+
+        // Report confidence calibrated against user feedback; feedback itself
+        // is matched against the raw value
+        {
+            let mut calibrator = self
+                .calibrator
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())?;
+            if !preview {
+                calibrator.track(message_id, tags.confidence);
+            }
+            tags.confidence = calibrator.calibrate(tags.confidence);
+        }
+        tags.clarify_before_answering = tags.confidence.overall() < self.clarify_threshold;
+
+        // Extract Ghost Text
+        let ghost_text = extractor.extract_ghost_text();
+
+        Ok(InputProfile {
+            message_id: message_id.to_string(),
+            source,
+            timing,
+            editing,
+            structure,
+            tags,
+            ghost_text,
+            normalized,
+            rule_variant,
+            clock,
+            rephrase,
+        })
+    }
+
+    pub fn export_baseline(&self) -> Result<String, String> {
+        let baseline = self
+            .baseline
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?;
+        serde_json::to_string_pretty(&*baseline).map_err(|e| e.to_string())
+    }
+
+    pub fn import_baseline(&self, json: &str) -> Result<(), String> {
+        let baseline: UserBaseline = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        *self
+            .baseline
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())? = baseline;
+        Ok(())
+    }
+
+    /// Record whether the answer to a finalized message helped. Confidence is
+    /// recalibrated periodically once enough feedback has been collected.
+    pub fn record_feedback(&self, message_id: &str, helpful: bool) -> Result<(), String> {
+        self.calibrator
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?
+            .record(message_id, helpful)
+    }
+
+    pub fn export_calibration(&self) -> Result<String, String> {
+        let calibrator = self
+            .calibrator
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?;
+        serde_json::to_string_pretty(&*calibrator).map_err(|e| e.to_string())
+    }
+
+    pub fn import_calibration(&self, json: &str) -> Result<(), String> {
+        let calibrator: Calibrator = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        *self
+            .calibrator
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())? = calibrator;
+        Ok(())
+    }
+
     pub fn export_events(&self, id: &str) -> Result<String, String> {
         let sessions = self
             .sessions
@@ -133,6 +545,18 @@ impl IflCore {
         Ok(id)
     }
 
+    /// The system prompt the built-in templates make for the message so far,
+    /// with `text` and a descriptor of tags and template version, as JSON
+    /// (see `llm_client::PromptArtifact`) — to paste into another chat tool.
+    /// Like `preview_message`, it leaves the message open.
+    pub fn export_prompt(&self, id: &str, text: &str) -> Result<String, String> {
+        let profile: InputProfile =
+            serde_json::from_str(&self.preview_message(id, text)?).map_err(|e| e.to_string())?;
+        let mut artifact = LlmClient::render_default_context(&PromptContext::new(&profile));
+        artifact.user_text = Some(text.to_string());
+        serde_json::to_string_pretty(&artifact).map_err(|e| e.to_string())
+    }
+
     pub fn export_snapshot(&self, id: &str, final_text: &str) -> Result<String, String> {
         // 1. Get events (clone them)
         let events_json = self.export_events(id)?;
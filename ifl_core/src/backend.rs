@@ -0,0 +1,2246 @@
+use crate::event::{content_hash, image_media_type};
+use crate::llm_client::{LlmError, StreamDecoder};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Proxy, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// Receives streamed tokens; dropping it tells the consumer the stream ended.
+pub type TokenSender = UnboundedSender<String>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    /// Tools the assistant asked to call in this turn.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// On a `tool` message, the id of the call it answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Images for a vision model to look at with the text.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageData>,
+}
+
+impl ChatMessage {
+    fn new(role: &str, content: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.to_string(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+        }
+    }
+
+    pub fn system(content: &str) -> Self {
+        Self::new("system", content)
+    }
+
+    pub fn user(content: &str) -> Self {
+        Self::new("user", content)
+    }
+
+    pub fn assistant(content: &str) -> Self {
+        Self::new("assistant", content)
+    }
+
+    pub fn with_images(mut self, images: Vec<ImageData>) -> Self {
+        self.images = images;
+        self
+    }
+
+    /// The result of the tool call `call_id`.
+    pub fn tool(call_id: &str, content: &str) -> Self {
+        Self {
+            tool_call_id: Some(call_id.to_string()),
+            ..Self::new("tool", content)
+        }
+    }
+}
+
+/// An image for a vision model such as llava or llama3.2-vision, base64
+/// encoded as the APIs take it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageData {
+    /// e.g. `image/png`.
+    pub media_type: String,
+    /// The image file, base64 encoded.
+    pub data: String,
+}
+
+impl ImageData {
+    pub fn from_bytes(media_type: &str, bytes: &[u8]) -> Self {
+        Self {
+            media_type: media_type.to_string(),
+            data: BASE64.encode(bytes),
+        }
+    }
+
+    /// Read an image file; PNG, JPEG, GIF and WebP are accepted.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let media_type =
+            image_media_type(path).ok_or_else(|| format!("{} is not a supported image", path))?;
+        let bytes = std::fs::read(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        Ok(Self::from_bytes(media_type, &bytes))
+    }
+
+    /// As a `data:` URL, the form OpenAI-compatible servers take.
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub sampling: SamplingParams,
+    #[serde(default, skip_serializing_if = "GenerationOptions::is_empty")]
+    pub options: GenerationOptions,
+    /// Tools the model may call instead of answering directly.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolSpec>,
+}
+
+/// A function the model may call, with its arguments as a JSON schema.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+/// A call the model asked for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+/// A response with what the server reported about it: token usage, why
+/// generation stopped, and the model that answered. Unreported values stay
+/// unset.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Completion {
+    pub content: String,
+    pub prompt_tokens: Option<usize>,
+    pub completion_tokens: Option<usize>,
+    /// "stop", "length" or "tool_calls", or the server's own reason.
+    pub finish_reason: Option<String>,
+    pub model: Option<String>,
+    /// Name of the backend that answered, when a `FallbackChain` chose it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+}
+
+impl Completion {
+    pub fn new(content: &str) -> Self {
+        Self {
+            content: content.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Pick up usage, finish reason and model from a response body or
+    /// stream event of the OpenAI, Anthropic or Ollama API. Values the
+    /// object does not carry are left as they are, so events can be read
+    /// one after another.
+    pub fn read_metadata(&mut self, value: &Value) {
+        let count = |v: &Value| v.as_u64().map(|n| n as usize);
+        let usage = if value["usage"].is_object() {
+            &value["usage"]
+        } else {
+            // Anthropic's `message_start` event
+            &value["message"]["usage"]
+        };
+        if let Some(n) = count(&usage["prompt_tokens"])
+            .or_else(|| count(&usage["input_tokens"]))
+            .or_else(|| count(&value["prompt_eval_count"]))
+        {
+            self.prompt_tokens = Some(n);
+        }
+        if let Some(n) = count(&usage["completion_tokens"])
+            .or_else(|| count(&usage["output_tokens"]))
+            .or_else(|| count(&value["eval_count"]))
+        {
+            self.completion_tokens = Some(n);
+        }
+        if let Some(reason) = value["choices"][0]["finish_reason"]
+            .as_str()
+            .or_else(|| value["stop_reason"].as_str())
+            .or_else(|| value["delta"]["stop_reason"].as_str())
+            .or_else(|| value["done_reason"].as_str())
+        {
+            self.finish_reason = Some(
+                match reason {
+                    "end_turn" | "stop_sequence" => "stop",
+                    "max_tokens" => "length",
+                    "tool_use" => "tool_calls",
+                    other => other,
+                }
+                .to_string(),
+            );
+        }
+        if let Some(model) = value["model"]
+            .as_str()
+            .or_else(|| value["message"]["model"].as_str())
+        {
+            self.model = Some(model.to_string());
+        }
+    }
+}
+
+/// Generation settings of one request; unset values keep the backend's own
+/// defaults, set ones override them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SamplingParams {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+/// Settings fixed by the caller rather than derived from the profile: a
+/// seed, to get the same answer to the same request again (as far as the
+/// server is deterministic), stop sequences that end the answer at a
+/// natural break, and the top-k/top-p cutoffs. Unset values keep the
+/// backend's defaults; `top_p` here wins over `SamplingParams::top_p`.
+///
+/// Anthropic has no seed and ignores it. OpenAI's own API rejects `top_k`,
+/// which other OpenAI-compatible servers (vLLM, llama.cpp, LM Studio) take.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+}
+
+impl GenerationOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// End the answer where the model writes `sequence`, which is left out.
+    pub fn with_stop(mut self, sequence: &str) -> Self {
+        self.stop.push(sequence.to_string());
+        self
+    }
+
+    pub fn with_top_k(mut self, top_k: u32) -> Self {
+        self.top_k = Some(top_k);
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A server or library that answers chat requests. `LlmClient` builds the
+/// prompt from the profile and hands the request to its backend; implement
+/// this to talk to vLLM, LM Studio, or a custom gateway.
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    /// The complete response to `request`.
+    async fn chat(&self, request: &ChatRequest) -> Result<String, Box<dyn Error>>;
+
+    /// Send each token to `tokens` as it arrives; returns the complete response.
+    async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<String, Box<dyn Error>>;
+
+    /// `chat`, with the usage, finish reason and model the server reported.
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, Box<dyn Error>> {
+        Ok(Completion::new(&self.chat(request).await?))
+    }
+
+    /// `chat_stream`, with the usage, finish reason and model the server
+    /// reported.
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<Completion, Box<dyn Error>> {
+        Ok(Completion::new(&self.chat_stream(request, tokens).await?))
+    }
+
+    /// The assistant turn answering a request that declares `tools`: text,
+    /// tool calls, or both. Backends without tool support answer in text.
+    async fn chat_with_tools(&self, request: &ChatRequest) -> Result<ChatMessage, Box<dyn Error>> {
+        Ok(ChatMessage::assistant(&self.chat(request).await?))
+    }
+
+    /// Models the backend can serve.
+    async fn list_models(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// `text` as a vector from the embedding model `model`, for comparing
+    /// texts by meaning. Backends without an embeddings API say so.
+    async fn embed(&self, model: &str, text: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+        let _ = (model, text);
+        Err(format!("The {} backend has no embeddings API", self.name()).into())
+    }
+
+    /// Ok if the backend is reachable and ready to answer; otherwise an
+    /// error saying what to do about it.
+    async fn health(&self) -> Result<(), Box<dyn Error>>;
+
+    /// Whether requests stay on this machine. Text bound elsewhere may have
+    /// its PII redacted, so only claim this when it holds.
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    /// Short label for logs and the UI.
+    fn name(&self) -> &str {
+        "custom"
+    }
+}
+
+/// Chat completions endpoint of the OpenAI API.
+pub const OPENAI_URL: &str = "https://api.openai.com/v1/chat/completions";
+/// Root of the Anthropic API.
+pub const ANTHROPIC_URL: &str = "https://api.anthropic.com";
+/// Root of a local Ollama server.
+pub const OLLAMA_URL: &str = "http://localhost:11434";
+/// How long a health check waits before reporting the server as down.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How to reach a server behind a reverse proxy or gateway: extra headers
+/// on every request (e.g. a gateway's auth token), an HTTP(S) proxy, and
+/// whether to trust a self-signed certificate. Without a proxy here the
+/// usual `HTTPS_PROXY` / `HTTP_PROXY` variables still apply.
+///
+/// The connection pool settings keep connections open between turns; unset,
+/// reqwest's defaults apply (idle connections kept for 90 s).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpOptions {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// e.g. `http://proxy.internal:3128`; used for both HTTP and HTTPS.
+    pub proxy: Option<String>,
+    /// Skip certificate checks. Only for self-hosted gateways whose
+    /// certificate cannot be installed; anyone in between can read the
+    /// traffic.
+    pub accept_invalid_certs: bool,
+    /// Idle connections kept open per host.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Seconds an idle connection is kept open.
+    pub pool_idle_timeout_secs: Option<u64>,
+    /// Interval of TCP keep-alive probes, in seconds, so a gateway does not
+    /// drop a connection idle between turns.
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Speak HTTP/2 without negotiating it first, for servers and gateways
+    /// known to support it; several requests then share one connection.
+    pub http2_prior_knowledge: bool,
+}
+
+impl HttpOptions {
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: Option<&str>) -> Self {
+        self.proxy = proxy.map(str::to_string);
+        self
+    }
+
+    pub fn with_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    pub fn with_pool_max_idle_per_host(mut self, max: Option<usize>) -> Self {
+        self.pool_max_idle_per_host = max;
+        self
+    }
+
+    pub fn with_pool_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.pool_idle_timeout_secs = timeout.map(|timeout| timeout.as_secs());
+        self
+    }
+
+    pub fn with_tcp_keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.tcp_keepalive_secs = interval.map(|interval| interval.as_secs());
+        self
+    }
+
+    pub fn with_http2_prior_knowledge(mut self, enabled: bool) -> Self {
+        self.http2_prior_knowledge = enabled;
+        self
+    }
+
+    /// An HTTP client sending requests as configured.
+    pub fn client(&self) -> Result<Client, String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("Invalid header name '{}'", name))?;
+            let mut value = HeaderValue::from_str(value)
+                .map_err(|_| format!("Invalid value for header '{}'", name))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        let mut builder = Client::builder()
+            .default_headers(headers)
+            .danger_accept_invalid_certs(self.accept_invalid_certs);
+        if let Some(proxy) = &self.proxy {
+            builder = builder
+                .proxy(Proxy::all(proxy).map_err(|e| format!("Invalid proxy '{}': {}", proxy, e))?);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(secs) = self.pool_idle_timeout_secs {
+            builder = builder.pool_idle_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.tcp_keepalive_secs {
+            builder = builder.tcp_keepalive(Duration::from_secs(secs));
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+}
+
+/// A backend's HTTP client, and with it its connection pool: the one given,
+/// or else one built on the first request, since building one loads the TLS
+/// roots and a client that only renders prompts never needs it.
+#[derive(Default)]
+struct HttpClient(OnceLock<Client>);
+
+impl HttpClient {
+    fn new(client: Client) -> Self {
+        Self(OnceLock::from(client))
+    }
+
+    fn http(&self) -> &Client {
+        self.0.get_or_init(Client::new)
+    }
+}
+
+/// An OpenAI-compatible `/chat/completions` endpoint, which Ollama, vLLM,
+/// LM Studio and llama.cpp's server all provide, as does OpenAI itself
+/// given an API key.
+pub struct OpenAiCompatBackend {
+    client: HttpClient,
+    base_url: String,
+    api_key: Option<String>,
+    organization: Option<String>,
+    project: Option<String>,
+}
+
+impl OpenAiCompatBackend {
+    /// `base_url` is the full chat completions URL,
+    /// e.g. `http://localhost:11434/v1/chat/completions`.
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            client: HttpClient::default(),
+            base_url: base_url.to_string(),
+            api_key: None,
+            organization: None,
+            project: None,
+        }
+    }
+
+    /// Send requests with `client`, e.g. one from `HttpOptions::client`.
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.client = HttpClient::new(client);
+        self
+    }
+
+    /// Sent as a bearer token.
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// OpenAI organization to bill (`OpenAI-Organization` header).
+    pub fn with_organization(mut self, organization: &str) -> Self {
+        self.organization = Some(organization.to_string());
+        self
+    }
+
+    /// OpenAI project to bill (`OpenAI-Project` header).
+    pub fn with_project(mut self, project: &str) -> Self {
+        self.project = Some(project.to_string());
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn authorize(&self, builder: RequestBuilder) -> RequestBuilder {
+        let mut builder = builder;
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        if let Some(organization) = &self.organization {
+            builder = builder.header("OpenAI-Organization", organization);
+        }
+        if let Some(project) = &self.project {
+            builder = builder.header("OpenAI-Project", project);
+        }
+        builder
+    }
+
+    /// The `/models` endpoint next to the chat completions URL.
+    fn models_url(&self) -> String {
+        self.sibling_url("models")
+    }
+
+    /// An endpoint next to the chat completions URL.
+    fn sibling_url(&self, endpoint: &str) -> String {
+        let root = self.base_url.trim_end_matches('/');
+        let root = root.strip_suffix("/chat/completions").unwrap_or(root);
+        format!("{}/{}", root, endpoint)
+    }
+
+    async fn post(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, Box<dyn Error>> {
+        let mut body = json!({
+            "model": request.model,
+            "messages": openai_messages(&request.messages, false),
+            "stream": stream
+        });
+        if !request.tools.is_empty() {
+            body["tools"] = openai_tools(&request.tools);
+        }
+        let sampling = request.sampling;
+        let options = &request.options;
+        if let Some(temperature) = sampling.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = options.top_p.or(sampling.top_p) {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(max_tokens) = sampling.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if let Some(seed) = options.seed {
+            body["seed"] = json!(seed);
+        }
+        if !options.stop.is_empty() {
+            body["stop"] = json!(options.stop);
+        }
+        if let Some(top_k) = options.top_k {
+            body["top_k"] = json!(top_k);
+        }
+        let res = self
+            .authorize(self.client.http().post(&self.base_url))
+            .json(&body)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(status_error(res).await.into());
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiCompatBackend {
+    async fn chat(&self, request: &ChatRequest) -> Result<String, Box<dyn Error>> {
+        Ok(self.complete(request).await?.content)
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, Box<dyn Error>> {
+        let res = self.post(request, false).await?;
+        let json_res: serde_json::Value = res.json().await?;
+
+        // Extract content from OpenAI-compatible response
+        let content = json_res["choices"][0]["message"]["content"]
+            .as_str()
+            .ok_or("Failed to parse response content")?;
+
+        let mut completion = Completion::new(content);
+        completion.read_metadata(&json_res);
+        Ok(completion)
+    }
+
+    async fn chat_with_tools(&self, request: &ChatRequest) -> Result<ChatMessage, Box<dyn Error>> {
+        let res = self.post(request, false).await?;
+        let json_res: Value = res.json().await?;
+        let message = &json_res["choices"][0]["message"];
+        if !message.is_object() {
+            return Err("Failed to parse response content".into());
+        }
+        Ok(openai_reply(message))
+    }
+
+    async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(self.complete_stream(request, tokens).await?.content)
+    }
+
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<Completion, Box<dyn Error>> {
+        let mut res = self.post(request, true).await?;
+
+        let mut decoder = StreamDecoder::new();
+        let mut content = String::new();
+        while !decoder.is_done() {
+            let decoded = match res.chunk().await? {
+                Some(chunk) => decoder.push(&chunk),
+                None => decoder.finish(),
+            };
+            for token in decoded {
+                content.push_str(&token);
+                // A consumer that stopped listening still gets the full text
+                let _ = tokens.send(token);
+            }
+        }
+        Ok(Completion {
+            content,
+            ..decoder.metadata().clone()
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let res = self
+            .authorize(self.client.http().get(self.models_url()))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(status_error(res).await.into());
+        }
+        let json_res: serde_json::Value = res.json().await?;
+        let models = json_res["data"]
+            .as_array()
+            .ok_or("Failed to parse model list")?
+            .iter()
+            .filter_map(|model| model["id"].as_str().map(str::to_string))
+            .collect();
+        Ok(models)
+    }
+
+    /// The `/embeddings` endpoint next to the chat completions URL.
+    async fn embed(&self, model: &str, text: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+        let res = self
+            .authorize(self.client.http().post(self.sibling_url("embeddings")))
+            .json(&json!({"model": model, "input": text}))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(status_error(res).await.into());
+        }
+        let json_res: Value = res.json().await?;
+        Ok(parse_embedding(&json_res["data"][0]["embedding"])?)
+    }
+
+    async fn health(&self) -> Result<(), Box<dyn Error>> {
+        // Ollama's OpenAI shim is the default setup
+        let (server, start) = if host_of(&self.base_url).ends_with(":11434") {
+            ("Ollama", "start it with `ollama serve`")
+        } else {
+            ("LLM server", "start it")
+        };
+        let request = self.authorize(self.client.http().get(self.models_url()));
+        probe(request, server, &self.base_url, start).await
+    }
+
+    fn is_local(&self) -> bool {
+        is_local_url(&self.base_url)
+    }
+
+    fn name(&self) -> &str {
+        "openai-compatible"
+    }
+}
+
+/// Which of Ollama's native endpoints `OllamaBackend` calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OllamaApi {
+    /// `/api/chat`: the messages as sent.
+    #[default]
+    Chat,
+    /// `/api/generate`: system and user text as one prompt; can carry the
+    /// model's context over to the next request.
+    Generate,
+}
+
+/// Ollama's native API, which unlike its OpenAI shim takes `keep_alive`,
+/// model `options` (`num_ctx`, `temperature`, ...) and generation context.
+pub struct OllamaBackend {
+    client: HttpClient,
+    base_url: String,
+    api: OllamaApi,
+    keep_alive: Option<String>,
+    options: serde_json::Map<String, Value>,
+    reuse_context: bool,
+    context: Mutex<Option<Value>>,
+}
+
+impl OllamaBackend {
+    /// `base_url` is the server root, e.g. `http://localhost:11434`.
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            client: HttpClient::default(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api: OllamaApi::default(),
+            keep_alive: None,
+            options: serde_json::Map::new(),
+            reuse_context: false,
+            context: Mutex::new(None),
+        }
+    }
+
+    /// Send requests with `client`, e.g. one from `HttpOptions::client`.
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.client = HttpClient::new(client);
+        self
+    }
+
+    pub fn with_api(mut self, api: OllamaApi) -> Self {
+        self.api = api;
+        self
+    }
+
+    /// How long the model stays loaded after a request, e.g. `"30m"`, or
+    /// `"-1"` to keep it until the server stops.
+    pub fn with_keep_alive(mut self, keep_alive: &str) -> Self {
+        self.keep_alive = Some(keep_alive.to_string());
+        self
+    }
+
+    /// Set a model option such as `num_ctx` or `temperature`. A request's
+    /// sampling parameters take precedence over the same options here.
+    pub fn with_option(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.options.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Continue from the previous response's context instead of starting
+    /// fresh, so follow-ups don't re-send the conversation. Only the
+    /// `Generate` API returns a context.
+    pub fn with_context_reuse(mut self, enabled: bool) -> Self {
+        self.reuse_context = enabled;
+        self
+    }
+
+    /// Start the next request without the previous context.
+    pub fn reset_context(&self) -> Result<(), String> {
+        *self
+            .context
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())? = None;
+        Ok(())
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Version of the server; fails if `base_url` does not speak the native API.
+    pub async fn version(&self) -> Result<String, Box<dyn Error>> {
+        let res = self
+            .client
+            .http()
+            .get(format!("{}/api/version", self.base_url))
+            .timeout(Duration::from_secs(3))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(status_error(res).await.into());
+        }
+        let json_res: Value = res.json().await?;
+        let version = json_res["version"]
+            .as_str()
+            .ok_or("Failed to parse Ollama version")?;
+        Ok(version.to_string())
+    }
+
+    /// Requests declaring tools need the chat API, which alone supports them.
+    fn api_for(&self, request: &ChatRequest) -> OllamaApi {
+        if request.tools.is_empty() {
+            self.api
+        } else {
+            OllamaApi::Chat
+        }
+    }
+
+    fn body(&self, request: &ChatRequest, stream: bool) -> Result<Value, Box<dyn Error>> {
+        let mut body = match self.api_for(request) {
+            OllamaApi::Chat => {
+                let mut body = json!({
+                    "model": request.model,
+                    "messages": openai_messages(&request.messages, true),
+                });
+                if !request.tools.is_empty() {
+                    body["tools"] = openai_tools(&request.tools);
+                }
+                body
+            }
+            OllamaApi::Generate => {
+                let text = |role: &str| {
+                    request
+                        .messages
+                        .iter()
+                        .filter(|m| m.role == role)
+                        .map(|m| m.content.as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n\n")
+                };
+                let mut body = json!({
+                    "model": request.model,
+                    "system": text("system"),
+                    "prompt": text("user"),
+                });
+                let images: Vec<&str> = request
+                    .messages
+                    .iter()
+                    .flat_map(|m| &m.images)
+                    .map(|image| image.data.as_str())
+                    .collect();
+                if !images.is_empty() {
+                    body["images"] = json!(images);
+                }
+                if self.reuse_context {
+                    let context = self
+                        .context
+                        .lock()
+                        .map_err(|_| "Mutex poisoned".to_string())?;
+                    if let Some(context) = context.as_ref() {
+                        body["context"] = context.clone();
+                    }
+                }
+                body
+            }
+        };
+        body["stream"] = json!(stream);
+        if let Some(keep_alive) = &self.keep_alive {
+            // A bare number is seconds; Ollama rejects it as a string
+            body["keep_alive"] = keep_alive
+                .parse::<i64>()
+                .map_or_else(|_| json!(keep_alive), |seconds| json!(seconds));
+        }
+        let mut options = self.options.clone();
+        let sampling = request.sampling;
+        if let Some(temperature) = sampling.temperature {
+            options.insert("temperature".to_string(), json!(temperature));
+        }
+        if let Some(top_p) = request.options.top_p.or(sampling.top_p) {
+            options.insert("top_p".to_string(), json!(top_p));
+        }
+        if let Some(max_tokens) = sampling.max_tokens {
+            options.insert("num_predict".to_string(), json!(max_tokens));
+        }
+        if let Some(seed) = request.options.seed {
+            options.insert("seed".to_string(), json!(seed));
+        }
+        if !request.options.stop.is_empty() {
+            options.insert("stop".to_string(), json!(request.options.stop));
+        }
+        if let Some(top_k) = request.options.top_k {
+            options.insert("top_k".to_string(), json!(top_k));
+        }
+        if !options.is_empty() {
+            body["options"] = Value::Object(options);
+        }
+        Ok(body)
+    }
+
+    fn endpoint(&self, api: OllamaApi) -> String {
+        match api {
+            OllamaApi::Chat => format!("{}/api/chat", self.base_url),
+            OllamaApi::Generate => format!("{}/api/generate", self.base_url),
+        }
+    }
+
+    async fn post(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, Box<dyn Error>> {
+        let body = self.body(request, stream)?;
+        let res = self
+            .client
+            .http()
+            .post(self.endpoint(self.api_for(request)))
+            .json(&body)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(status_error(res).await.into());
+        }
+        Ok(res)
+    }
+
+    /// Keep the context of a finished `Generate` response for the next request.
+    fn remember_context(&self, response: &Value) -> Result<(), Box<dyn Error>> {
+        if self.reuse_context && response["context"].is_array() {
+            *self
+                .context
+                .lock()
+                .map_err(|_| "Mutex poisoned".to_string())? = Some(response["context"].clone());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OllamaBackend {
+    async fn chat(&self, request: &ChatRequest) -> Result<String, Box<dyn Error>> {
+        Ok(self.complete(request).await?.content)
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, Box<dyn Error>> {
+        let res = self.post(request, false).await?;
+        let json_res: Value = res.json().await?;
+        self.remember_context(&json_res)?;
+
+        let content = match self.api_for(request) {
+            OllamaApi::Chat => json_res["message"]["content"].as_str(),
+            OllamaApi::Generate => json_res["response"].as_str(),
+        };
+        let mut completion = Completion::new(content.ok_or("Failed to parse response content")?);
+        completion.read_metadata(&json_res);
+        Ok(completion)
+    }
+
+    async fn chat_with_tools(&self, request: &ChatRequest) -> Result<ChatMessage, Box<dyn Error>> {
+        if request.tools.is_empty() {
+            return Ok(ChatMessage::assistant(&self.chat(request).await?));
+        }
+        let res = self.post(request, false).await?;
+        let json_res: Value = res.json().await?;
+        let message = &json_res["message"];
+        if !message.is_object() {
+            return Err("Failed to parse response content".into());
+        }
+        Ok(openai_reply(message))
+    }
+
+    async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(self.complete_stream(request, tokens).await?.content)
+    }
+
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<Completion, Box<dyn Error>> {
+        let mut res = self.post(request, true).await?;
+
+        let mut decoder = StreamDecoder::new();
+        let mut content = String::new();
+        while !decoder.is_done() {
+            let decoded = match res.chunk().await? {
+                Some(chunk) => decoder.push(&chunk),
+                None => decoder.finish(),
+            };
+            for token in decoded {
+                content.push_str(&token);
+                let _ = tokens.send(token);
+            }
+        }
+        if let Some(last) = decoder.final_chunk() {
+            self.remember_context(last)?;
+        }
+        Ok(Completion {
+            content,
+            ..decoder.metadata().clone()
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let res = self
+            .client
+            .http()
+            .get(format!("{}/api/tags", self.base_url))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(status_error(res).await.into());
+        }
+        let json_res: Value = res.json().await?;
+        let models = json_res["models"]
+            .as_array()
+            .ok_or("Failed to parse model list")?
+            .iter()
+            .filter_map(|model| model["name"].as_str().map(str::to_string))
+            .collect();
+        Ok(models)
+    }
+
+    async fn embed(&self, model: &str, text: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+        let res = self
+            .client
+            .http()
+            .post(format!("{}/api/embeddings", self.base_url))
+            .json(&json!({"model": model, "prompt": text}))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(status_error(res).await.into());
+        }
+        let json_res: Value = res.json().await?;
+        Ok(parse_embedding(&json_res["embedding"])?)
+    }
+
+    async fn health(&self) -> Result<(), Box<dyn Error>> {
+        let request = self
+            .client
+            .http()
+            .get(format!("{}/api/version", self.base_url));
+        probe(
+            request,
+            "Ollama",
+            &self.base_url,
+            "start it with `ollama serve`",
+        )
+        .await
+    }
+
+    fn is_local(&self) -> bool {
+        is_local_url(&self.base_url)
+    }
+
+    fn name(&self) -> &str {
+        "ollama"
+    }
+}
+
+/// `messages` in the OpenAI chat format, which Ollama's chat API shares
+/// except that it takes tool call arguments as an object, not a JSON string,
+/// and images as a list of base64 strings beside the text rather than as
+/// content parts.
+fn openai_messages(messages: &[ChatMessage], ollama: bool) -> Vec<Value> {
+    messages
+        .iter()
+        .map(|message| {
+            let mut value = json!({ "role": message.role, "content": message.content });
+            if !message.images.is_empty() {
+                if ollama {
+                    value["images"] = message.images.iter().map(|i| json!(i.data)).collect();
+                } else {
+                    let mut parts = vec![json!({ "type": "text", "text": message.content })];
+                    parts.extend(message.images.iter().map(|image| {
+                        json!({ "type": "image_url", "image_url": { "url": image.data_url() } })
+                    }));
+                    value["content"] = json!(parts);
+                }
+            }
+            if !message.tool_calls.is_empty() {
+                value["tool_calls"] = message
+                    .tool_calls
+                    .iter()
+                    .map(|call| {
+                        let arguments = if ollama {
+                            call.arguments.clone()
+                        } else {
+                            json!(call.arguments.to_string())
+                        };
+                        json!({
+                            "id": call.id,
+                            "type": "function",
+                            "function": { "name": call.name, "arguments": arguments },
+                        })
+                    })
+                    .collect();
+            }
+            if let Some(call_id) = &message.tool_call_id {
+                value["tool_call_id"] = json!(call_id);
+            }
+            value
+        })
+        .collect()
+}
+
+fn openai_tools(tools: &[ToolSpec]) -> Value {
+    tools
+        .iter()
+        .map(|tool| {
+            json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": tool.parameters,
+                },
+            })
+        })
+        .collect()
+}
+
+/// The assistant turn in an OpenAI-format response `message`. Arguments
+/// arrive as a JSON string (OpenAI) or an object (Ollama); calls without an
+/// id (older Ollama) are numbered.
+fn openai_reply(message: &Value) -> ChatMessage {
+    let mut reply = ChatMessage::assistant(message["content"].as_str().unwrap_or_default());
+    if let Some(calls) = message["tool_calls"].as_array() {
+        reply.tool_calls = calls
+            .iter()
+            .enumerate()
+            .map(|(i, call)| {
+                let function = &call["function"];
+                let arguments = match &function["arguments"] {
+                    Value::String(text) => {
+                        serde_json::from_str(text).unwrap_or_else(|_| json!(text))
+                    }
+                    Value::Null => json!({}),
+                    arguments => arguments.clone(),
+                };
+                ToolCall {
+                    id: call["id"]
+                        .as_str()
+                        .map_or_else(|| format!("call_{}", i), str::to_string),
+                    name: function["name"].as_str().unwrap_or_default().to_string(),
+                    arguments,
+                }
+            })
+            .collect();
+    }
+    reply
+}
+
+/// Pick the backend for `base_url` by the API it speaks. Endpoint URLs
+/// (`.../v1/chat/completions`, `.../api/chat`, `.../api/generate`) decide
+/// directly; for a server root, a native Ollama server is detected by
+/// asking for its version, and anything else gets the OpenAI-compatible
+/// endpoint under `/v1`.
+pub async fn detect_backend(base_url: &str) -> Box<dyn LlmBackend> {
+    detect_backend_with(base_url, Client::new()).await
+}
+
+/// Like `detect_backend`, sending requests with `client`.
+pub async fn detect_backend_with(base_url: &str, client: Client) -> Box<dyn LlmBackend> {
+    detect(base_url, client, None).await
+}
+
+/// `detect_backend_with`, giving a native Ollama backend `keep_alive`.
+async fn detect(base_url: &str, client: Client, keep_alive: Option<&str>) -> Box<dyn LlmBackend> {
+    let url = base_url.trim_end_matches('/');
+    let openai =
+        |url: &str| Box::new(OpenAiCompatBackend::new(url).with_http_client(client.clone()));
+    let ollama = |root: &str| {
+        let backend = OllamaBackend::new(root).with_http_client(client.clone());
+        match keep_alive {
+            Some(keep_alive) => backend.with_keep_alive(keep_alive),
+            None => backend,
+        }
+    };
+    if url.ends_with("/chat/completions") {
+        return openai(url);
+    }
+    if let Some(root) = url.strip_suffix("/api/chat") {
+        return Box::new(ollama(root));
+    }
+    if let Some(root) = url.strip_suffix("/api/generate") {
+        return Box::new(ollama(root).with_api(OllamaApi::Generate));
+    }
+    if url.ends_with("/v1") {
+        return openai(&format!("{}/chat/completions", url));
+    }
+    let native = ollama(url);
+    if native.version().await.is_ok() {
+        return Box::new(native);
+    }
+    openai(&format!("{}/v1/chat/completions", url))
+}
+
+/// Send a health check, turning failures into advice: `server` names what
+/// should be answering and `start` how to get it running.
+async fn probe(
+    request: RequestBuilder,
+    server: &str,
+    url: &str,
+    start: &str,
+) -> Result<(), Box<dyn Error>> {
+    let host = host_of(url);
+    let res = request.timeout(HEALTH_TIMEOUT).send().await.map_err(|e| {
+        if e.is_timeout() {
+            format!(
+                "{} on {} did not answer within {} s; it may still be starting. Retry, or change the base URL",
+                server,
+                host,
+                HEALTH_TIMEOUT.as_secs()
+            )
+        } else if e.is_connect() {
+            format!(
+                "{} not running on {} — {} or change the base URL",
+                server, host, start
+            )
+        } else {
+            format!("Cannot reach {} on {}: {}", server, host, e)
+        }
+    })?;
+    match res.status().as_u16() {
+        200..=299 => Ok(()),
+        401 | 403 => Err(format!(
+            "{} on {} rejected the API key — check that it is set and valid",
+            server, host
+        )
+        .into()),
+        404 => Err(format!(
+            "{} does not look like the {} API — check the base URL",
+            url, server
+        )
+        .into()),
+        _ => Err(format!(
+            "{} on {} answered the health check with status {}",
+            server,
+            host,
+            res.status()
+        )
+        .into()),
+    }
+}
+
+/// The error for a failed request, with the server's own message if it
+/// sent one. A 404 naming a model means the server lacks that model.
+async fn status_error(res: reqwest::Response) -> LlmError {
+    let status = res.status();
+    let body = res.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|json| {
+            json["error"]["message"]
+                .as_str()
+                .or_else(|| json["error"].as_str())
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| body.trim().chars().take(200).collect());
+    let message = if message.is_empty() {
+        status.canonical_reason().unwrap_or_default().to_string()
+    } else {
+        message
+    };
+    if status.as_u16() == 404 && message.to_lowercase().contains("model") {
+        LlmError::ModelUnavailable(message)
+    } else {
+        LlmError::Status {
+            status: status.as_u16(),
+            message,
+        }
+    }
+}
+
+/// A JSON array of numbers as an embedding.
+fn parse_embedding(value: &Value) -> Result<Vec<f32>, LlmError> {
+    value
+        .as_array()
+        .filter(|values| !values.is_empty())
+        .and_then(|values| {
+            values
+                .iter()
+                .map(|v| v.as_f64().map(|v| v as f32))
+                .collect()
+        })
+        .ok_or_else(|| LlmError::BadResponse("No embedding in the response".to_string()))
+}
+
+/// `host:port` of `url`.
+fn host_of(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.split('/').next().unwrap_or(rest)
+}
+
+/// Whether `url` points at this machine: its host is exactly `localhost`
+/// or a loopback or unspecified address, so `localhost.example.com` is not.
+pub fn is_local_url(url: &str) -> bool {
+    let url = if url.contains("://") {
+        reqwest::Url::parse(url)
+    } else {
+        reqwest::Url::parse(&format!("http://{}", url))
+    };
+    let Some(host) = url.ok().and_then(|url| url.host_str().map(str::to_string)) else {
+        return false;
+    };
+    match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback() || ip.is_unspecified(),
+        Err(_) => host.eq_ignore_ascii_case("localhost"),
+    }
+}
+
+/// Anthropic's Messages API.
+pub struct AnthropicBackend {
+    client: HttpClient,
+    base_url: String,
+    api_key: String,
+    version: String,
+    max_tokens: u32,
+}
+
+impl AnthropicBackend {
+    /// `base_url` is the API root, normally `ANTHROPIC_URL`.
+    pub fn new(base_url: &str, api_key: &str) -> Self {
+        Self {
+            client: HttpClient::default(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            version: "2023-06-01".to_string(),
+            max_tokens: 4096,
+        }
+    }
+
+    /// Send requests with `client`, e.g. one from `HttpOptions::client`.
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.client = HttpClient::new(client);
+        self
+    }
+
+    /// Value of the `anthropic-version` header.
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// Response length limit for requests that set none; the API requires one.
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    fn authorize(&self, builder: RequestBuilder) -> RequestBuilder {
+        builder
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", &self.version)
+    }
+
+    /// System messages go in the top-level `system` field; the API only
+    /// accepts user and assistant turns in `messages`.
+    fn body(&self, request: &ChatRequest, stream: bool) -> Value {
+        let system: Vec<&str> = request
+            .messages
+            .iter()
+            .filter(|m| m.role == "system")
+            .map(|m| m.content.as_str())
+            .collect();
+        let sampling = request.sampling;
+        let mut body = json!({
+            "model": request.model,
+            "system": system.join("\n\n"),
+            "messages": Self::messages(&request.messages),
+            "max_tokens": sampling.max_tokens.unwrap_or(self.max_tokens),
+            "stream": stream
+        });
+        if !request.tools.is_empty() {
+            body["tools"] = request
+                .tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "name": tool.name,
+                        "description": tool.description,
+                        "input_schema": tool.parameters,
+                    })
+                })
+                .collect();
+        }
+        if let Some(temperature) = sampling.temperature {
+            // The Messages API accepts 0 to 1
+            body["temperature"] = json!(temperature.clamp(0.0, 1.0));
+        }
+        if let Some(top_p) = request.options.top_p.or(sampling.top_p) {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(top_k) = request.options.top_k {
+            body["top_k"] = json!(top_k);
+        }
+        if !request.options.stop.is_empty() {
+            body["stop_sequences"] = json!(request.options.stop);
+        }
+        body
+    }
+
+    /// Tool calls become `tool_use` blocks of the assistant turn, and tool
+    /// results `tool_result` blocks of a single user turn.
+    fn messages(messages: &[ChatMessage]) -> Vec<Value> {
+        let mut out: Vec<Value> = Vec::new();
+        for message in messages.iter().filter(|m| m.role != "system") {
+            if let Some(call_id) = &message.tool_call_id {
+                let result = json!({
+                    "type": "tool_result",
+                    "tool_use_id": call_id,
+                    "content": message.content,
+                });
+                match out.last_mut() {
+                    Some(last) if last["role"] == "user" && last["content"].is_array() => {
+                        if let Some(blocks) = last["content"].as_array_mut() {
+                            blocks.push(result);
+                        }
+                    }
+                    _ => out.push(json!({ "role": "user", "content": [result] })),
+                }
+            } else if !message.tool_calls.is_empty() {
+                let mut blocks = Vec::new();
+                if !message.content.is_empty() {
+                    blocks.push(json!({ "type": "text", "text": message.content }));
+                }
+                for call in &message.tool_calls {
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.name,
+                        "input": call.arguments,
+                    }));
+                }
+                out.push(json!({ "role": message.role, "content": blocks }));
+            } else if !message.images.is_empty() {
+                let mut blocks: Vec<Value> = message
+                    .images
+                    .iter()
+                    .map(|image| {
+                        json!({
+                            "type": "image",
+                            "source": {
+                                "type": "base64",
+                                "media_type": image.media_type,
+                                "data": image.data,
+                            },
+                        })
+                    })
+                    .collect();
+                blocks.push(json!({ "type": "text", "text": message.content }));
+                out.push(json!({ "role": message.role, "content": blocks }));
+            } else {
+                out.push(json!({ "role": message.role, "content": message.content }));
+            }
+        }
+        out
+    }
+
+    async fn post(&self, body: &Value) -> Result<reqwest::Response, Box<dyn Error>> {
+        let res = self
+            .authorize(
+                self.client
+                    .http()
+                    .post(format!("{}/v1/messages", self.base_url)),
+            )
+            .json(body)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(status_error(res).await.into());
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl LlmBackend for AnthropicBackend {
+    async fn chat(&self, request: &ChatRequest) -> Result<String, Box<dyn Error>> {
+        Ok(self.complete(request).await?.content)
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, Box<dyn Error>> {
+        let res = self.post(&self.body(request, false)).await?;
+        let json_res: Value = res.json().await?;
+
+        let content: String = json_res["content"]
+            .as_array()
+            .ok_or("Failed to parse response content")?
+            .iter()
+            .filter(|block| block["type"] == "text")
+            .filter_map(|block| block["text"].as_str())
+            .collect();
+        let mut completion = Completion::new(&content);
+        completion.read_metadata(&json_res);
+        Ok(completion)
+    }
+
+    async fn chat_with_tools(&self, request: &ChatRequest) -> Result<ChatMessage, Box<dyn Error>> {
+        let res = self.post(&self.body(request, false)).await?;
+        let json_res: Value = res.json().await?;
+        let blocks = json_res["content"]
+            .as_array()
+            .ok_or("Failed to parse response content")?;
+
+        let content: String = blocks
+            .iter()
+            .filter(|block| block["type"] == "text")
+            .filter_map(|block| block["text"].as_str())
+            .collect();
+        let mut reply = ChatMessage::assistant(&content);
+        reply.tool_calls = blocks
+            .iter()
+            .filter(|block| block["type"] == "tool_use")
+            .map(|block| ToolCall {
+                id: block["id"].as_str().unwrap_or_default().to_string(),
+                name: block["name"].as_str().unwrap_or_default().to_string(),
+                arguments: block["input"].clone(),
+            })
+            .collect();
+        Ok(reply)
+    }
+
+    async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(self.complete_stream(request, tokens).await?.content)
+    }
+
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<Completion, Box<dyn Error>> {
+        let mut res = self.post(&self.body(request, true)).await?;
+
+        let mut decoder = StreamDecoder::new();
+        let mut content = String::new();
+        while !decoder.is_done() {
+            let decoded = match res.chunk().await? {
+                Some(chunk) => decoder.push(&chunk),
+                None => decoder.finish(),
+            };
+            for token in decoded {
+                content.push_str(&token);
+                let _ = tokens.send(token);
+            }
+        }
+        Ok(Completion {
+            content,
+            ..decoder.metadata().clone()
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let res = self
+            .authorize(
+                self.client
+                    .http()
+                    .get(format!("{}/v1/models", self.base_url)),
+            )
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(status_error(res).await.into());
+        }
+        let json_res: Value = res.json().await?;
+        let models = json_res["data"]
+            .as_array()
+            .ok_or("Failed to parse model list")?
+            .iter()
+            .filter_map(|model| model["id"].as_str().map(str::to_string))
+            .collect();
+        Ok(models)
+    }
+
+    async fn health(&self) -> Result<(), Box<dyn Error>> {
+        let request = self.authorize(
+            self.client
+                .http()
+                .get(format!("{}/v1/models", self.base_url)),
+        );
+        probe(
+            request,
+            "Anthropic API",
+            &self.base_url,
+            "check the network connection",
+        )
+        .await
+    }
+
+    fn name(&self) -> &str {
+        "anthropic"
+    }
+}
+
+/// Prompt lines `MockBackend` echoes: its label, and the line prefixes of
+/// the English and Japanese templates.
+const MOCK_DIRECTIVES: &[(&str, &[&str])] = &[
+    (
+        "Modes",
+        &["- Modes (primary first):", "- 回答モード（優先順）:"],
+    ),
+    ("Tone", &["- Tone:", "- トーン:"]),
+    ("Depth", &["- Depth:", "- 詳しさ:"]),
+    ("User state", &["- User State:", "- ユーザーの状態:"]),
+];
+const MOCK_GHOST_HEADERS: &[&str] = &["GHOST TEXT", "ゴーストテキスト"];
+/// Characters of the user's message quoted in a mock answer.
+const MOCK_QUOTE_CHARS: usize = 120;
+/// Length of the mock backend's word-count embeddings.
+const MOCK_EMBEDDING_DIMENSIONS: usize = 256;
+
+/// Answers without a model, by echoing what the system prompt asks for:
+/// the modes, tone, depth and user state, and any ghost text. The answer
+/// depends only on the request, so examples, the GUI and tests run the
+/// whole pipeline with nothing installed. Streams word by word.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MockBackend;
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// The answer to `request`. A custom prompt template that words the
+    /// directives differently only gets the message echoed.
+    pub fn answer(request: &ChatRequest) -> String {
+        let prompt = request
+            .messages
+            .iter()
+            .find(|m| m.role == "system")
+            .map_or("", |m| m.content.as_str());
+
+        let directives: Vec<String> = MOCK_DIRECTIVES
+            .iter()
+            .filter_map(|(label, prefixes)| {
+                let value = prompt.lines().find_map(|line| {
+                    prefixes.iter().find_map(|prefix| line.strip_prefix(prefix))
+                })?;
+                // Drop the "(tentative; ...)" hedge and list brackets
+                let value = value.split(" (").next().unwrap_or_default();
+                let value = value.split('（').next().unwrap_or_default();
+                let value = value.trim().trim_start_matches('[').trim_end_matches(']');
+                Some(format!("{}: {}", label, value))
+            })
+            .collect();
+
+        let ghost_text: Vec<&str> = prompt
+            .lines()
+            .skip_while(|line| !MOCK_GHOST_HEADERS.iter().any(|h| line.starts_with(h)))
+            .skip_while(|line| *line != "<untrusted>")
+            .skip(1)
+            .take_while(|line| line.starts_with("  "))
+            .filter_map(|line| line.trim().split_once('.').map(|(_, text)| text.trim()))
+            .collect();
+
+        let message = request
+            .messages
+            .iter()
+            .rev()
+            .find(|m| m.role == "user")
+            .map_or("", |m| m.content.as_str());
+        let mut quote: String = message.chars().take(MOCK_QUOTE_CHARS).collect();
+        if quote.len() < message.len() {
+            quote.push('…');
+        }
+
+        let mut answer = String::from("[mock]");
+        if !directives.is_empty() {
+            answer.push(' ');
+            answer.push_str(&directives.join(" | "));
+        }
+        if !ghost_text.is_empty() {
+            answer.push_str("\nGhost text: ");
+            answer.push_str(&ghost_text.join("; "));
+        }
+        answer.push_str("\nMessage: ");
+        answer.push_str(&quote);
+        answer
+    }
+}
+
+#[async_trait]
+impl LlmBackend for MockBackend {
+    async fn chat(&self, request: &ChatRequest) -> Result<String, Box<dyn Error>> {
+        Ok(Self::answer(request))
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, Box<dyn Error>> {
+        Ok(Completion {
+            finish_reason: Some("stop".to_string()),
+            ..Completion::new(&Self::answer(request))
+        })
+    }
+
+    async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<String, Box<dyn Error>> {
+        let answer = Self::answer(request);
+        for word in answer.split_inclusive(' ') {
+            let _ = tokens.send(word.to_string());
+        }
+        Ok(answer)
+    }
+
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<Completion, Box<dyn Error>> {
+        Ok(Completion {
+            finish_reason: Some("stop".to_string()),
+            ..Completion::new(&self.chat_stream(request, tokens).await?)
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(vec!["mock".to_string()])
+    }
+
+    /// Bag of words: texts sharing most of their words come out similar,
+    /// whatever the model.
+    async fn embed(&self, _model: &str, text: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+        let mut vector = vec![0.0f32; MOCK_EMBEDDING_DIMENSIONS];
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let index = content_hash(&word.to_lowercase()) as usize % MOCK_EMBEDDING_DIMENSIONS;
+            vector[index] += 1.0;
+        }
+        Ok(vector)
+    }
+
+    async fn health(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        "mock"
+    }
+}
+
+/// How long a link of a `FallbackChain` may take, or go quiet while
+/// streaming, before the next one is tried.
+const DEFAULT_LINK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Backends tried in order, each with its own model: when one is down, lacks
+/// the model or times out, the request goes to the next. A stream that has
+/// already produced tokens is not restarted elsewhere. The response records
+/// which backend served it.
+pub struct FallbackChain {
+    links: Vec<(Box<dyn LlmBackend>, Option<String>)>,
+    timeout: Duration,
+}
+
+impl Default for FallbackChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FallbackChain {
+    pub fn new() -> Self {
+        Self {
+            links: Vec::new(),
+            timeout: DEFAULT_LINK_TIMEOUT,
+        }
+    }
+
+    /// Try `backend` after the ones already added, asking it for `model`,
+    /// or with `None` for the model of the request.
+    pub fn with_backend(mut self, backend: Box<dyn LlmBackend>, model: Option<&str>) -> Self {
+        self.links.push((backend, model.map(str::to_string)));
+        self
+    }
+
+    /// Per-link limit; keep it below the client's timeout so the chain
+    /// gets to try the next backend.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// `request` as sent to the link at `index`.
+    fn request_for(&self, index: usize, request: &ChatRequest) -> ChatRequest {
+        let mut request = request.clone();
+        if let Some(model) = &self.links[index].1 {
+            request.model = model.clone();
+        }
+        request
+    }
+
+    /// Record which link served `completion`.
+    fn served(
+        &self,
+        index: usize,
+        request: &ChatRequest,
+        mut completion: Completion,
+    ) -> Completion {
+        completion.backend = Some(self.links[index].0.name().to_string());
+        completion
+            .model
+            .get_or_insert_with(|| request.model.clone());
+        completion
+    }
+
+    /// Whether a failure is the backend's rather than the request's, so
+    /// another backend may succeed.
+    fn falls_over(error: &LlmError) -> bool {
+        error.is_retryable() || matches!(error, LlmError::ModelUnavailable(_))
+    }
+
+    fn exhausted(last_error: Option<LlmError>) -> Box<dyn Error> {
+        last_error
+            .unwrap_or_else(|| LlmError::Connection("No backends configured".to_string()))
+            .into()
+    }
+}
+
+#[async_trait]
+impl LlmBackend for FallbackChain {
+    async fn chat(&self, request: &ChatRequest) -> Result<String, Box<dyn Error>> {
+        Ok(self.complete(request).await?.content)
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, Box<dyn Error>> {
+        let mut last_error = None;
+        for (index, (backend, _)) in self.links.iter().enumerate() {
+            let request = self.request_for(index, request);
+            let result = match tokio::time::timeout(self.timeout, backend.complete(&request)).await
+            {
+                Ok(result) => result.map_err(LlmError::from_backend),
+                Err(_) => Err(LlmError::Timeout),
+            };
+            match result {
+                Ok(completion) => return Ok(self.served(index, &request, completion)),
+                Err(e) if Self::falls_over(&e) => last_error = Some(e),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(Self::exhausted(last_error))
+    }
+
+    async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(self.complete_stream(request, tokens).await?.content)
+    }
+
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<Completion, Box<dyn Error>> {
+        let mut last_error = None;
+        for (index, (backend, _)) in self.links.iter().enumerate() {
+            let request = self.request_for(index, request);
+            // Relay tokens to see whether this link produced any
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            let attempt = async {
+                backend
+                    .complete_stream(&request, sender)
+                    .await
+                    .map_err(LlmError::from_backend)
+            };
+            tokio::pin!(attempt);
+            let mut received = false;
+            let result = loop {
+                tokio::select! {
+                    result = &mut attempt => {
+                        while let Ok(token) = receiver.try_recv() {
+                            received = true;
+                            let _ = tokens.send(token);
+                        }
+                        break result;
+                    }
+                    token = tokio::time::timeout(self.timeout, receiver.recv()) => match token {
+                        Ok(Some(token)) => {
+                            received = true;
+                            let _ = tokens.send(token);
+                        }
+                        Ok(None) => {
+                            break match tokio::time::timeout(self.timeout, &mut attempt).await {
+                                Ok(result) => result,
+                                Err(_) => Err(LlmError::Timeout),
+                            };
+                        }
+                        Err(_) => break Err(LlmError::Timeout),
+                    },
+                }
+            };
+            match result {
+                Ok(completion) => return Ok(self.served(index, &request, completion)),
+                Err(e) if !received && Self::falls_over(&e) => last_error = Some(e),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(Self::exhausted(last_error))
+    }
+
+    async fn chat_with_tools(&self, request: &ChatRequest) -> Result<ChatMessage, Box<dyn Error>> {
+        let mut last_error = None;
+        for (index, (backend, _)) in self.links.iter().enumerate() {
+            let request = self.request_for(index, request);
+            let result =
+                match tokio::time::timeout(self.timeout, backend.chat_with_tools(&request)).await {
+                    Ok(result) => result.map_err(LlmError::from_backend),
+                    Err(_) => Err(LlmError::Timeout),
+                };
+            match result {
+                Ok(reply) => return Ok(reply),
+                Err(e) if Self::falls_over(&e) => last_error = Some(e),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(Self::exhausted(last_error))
+    }
+
+    /// From the first link that embeds; unlike chat, any failure moves
+    /// on, since some links have no embeddings API.
+    async fn embed(&self, model: &str, text: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+        let mut last_error = None;
+        for (backend, _) in &self.links {
+            match tokio::time::timeout(self.timeout, backend.embed(model, text)).await {
+                Ok(Ok(embedding)) => return Ok(embedding),
+                Ok(Err(e)) => last_error = Some(LlmError::from_backend(e)),
+                Err(_) => last_error = Some(LlmError::Timeout),
+            }
+        }
+        Err(Self::exhausted(last_error))
+    }
+
+    /// Models of every reachable backend.
+    async fn list_models(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut models = Vec::new();
+        let mut last_error = None;
+        for (backend, _) in &self.links {
+            match backend.list_models().await {
+                Ok(listed) => models.extend(listed),
+                Err(e) => last_error = Some(e.to_string()),
+            }
+        }
+        match last_error {
+            Some(e) if models.is_empty() => Err(e.into()),
+            _ => {
+                models.sort();
+                models.dedup();
+                Ok(models)
+            }
+        }
+    }
+
+    /// Ok if any backend is; otherwise the first one's error.
+    async fn health(&self) -> Result<(), Box<dyn Error>> {
+        let mut first_error = None;
+        for (backend, _) in &self.links {
+            match backend.health().await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    first_error.get_or_insert(e.to_string());
+                }
+            }
+        }
+        Err(first_error
+            .unwrap_or_else(|| "No backends configured".to_string())
+            .into())
+    }
+
+    /// Only if every backend is: the request may reach any of them.
+    fn is_local(&self) -> bool {
+        !self.links.is_empty() && self.links.iter().all(|(backend, _)| backend.is_local())
+    }
+
+    fn name(&self) -> &str {
+        "fallback"
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    /// A local server if one answers, else a cloud provider with a key set.
+    #[default]
+    Auto,
+    Ollama,
+    OpenaiCompatible,
+    Openai,
+    Anthropic,
+}
+
+impl Provider {
+    /// Model used when the configuration names none; local providers keep
+    /// `LlmClient`'s default.
+    pub fn default_model(self) -> Option<&'static str> {
+        match self {
+            Provider::Openai => Some("gpt-4o-mini"),
+            Provider::Anthropic => Some("claude-3-5-haiku-latest"),
+            _ => None,
+        }
+    }
+
+    /// Environment variable read for the API key when none is configured.
+    pub fn api_key_env(self) -> Option<&'static str> {
+        match self {
+            Provider::Openai => Some("OPENAI_API_KEY"),
+            Provider::Anthropic => Some("ANTHROPIC_API_KEY"),
+            _ => None,
+        }
+    }
+}
+
+/// Which backend `LlmClient::from_config` connects to, read from TOML:
+///
+/// ```toml
+/// provider = "anthropic"
+/// model = "claude-3-5-haiku-latest"
+/// api_key_env = "MY_ANTHROPIC_KEY"
+/// ```
+///
+/// or from `IFL_LLM_*` environment variables (see `from_env`). Keys are best
+/// kept out of files: name a variable with `api_key_env`, or leave both
+/// unset to use `OPENAI_API_KEY` / `ANTHROPIC_API_KEY`.
+///
+/// Behind a gateway, `proxy`, `accept_invalid_certs` and a `[headers]`
+/// table apply as in `HttpOptions`.
+///
+/// `[[fallback]]` tables with the same keys name backends to try, in order,
+/// when this one is down or lacks the model.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackendConfig {
+    pub provider: Provider,
+    /// Endpoint override; each provider has a default.
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    pub api_key: Option<String>,
+    pub api_key_env: Option<String>,
+    /// OpenAI organization and project headers.
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    /// Anthropic `anthropic-version` header and response length cap.
+    pub anthropic_version: Option<String>,
+    pub max_tokens: Option<u32>,
+    /// How long Ollama keeps the model loaded after a request, e.g. `"30m"`
+    /// or `"-1"` for as long as the server runs (see
+    /// `OllamaBackend::with_keep_alive`). Only the native Ollama backend
+    /// sends it.
+    pub keep_alive: Option<String>,
+    /// Headers, proxy and certificate checks for every request.
+    #[serde(flatten)]
+    pub http: HttpOptions,
+    /// Backends tried in order when this one fails (see `FallbackChain`),
+    /// as `[[fallback]]` tables; their own `fallback` is ignored.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<BackendConfig>,
+}
+
+impl BackendConfig {
+    pub fn from_toml_str(toml_str: &str) -> Result<Self, String> {
+        toml::from_str(toml_str).map_err(|e| e.to_string())
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_toml_str(&content)
+    }
+
+    /// Read `IFL_LLM_PROVIDER`, `IFL_LLM_BASE_URL`, `IFL_LLM_MODEL`,
+    /// `IFL_LLM_API_KEY`, `IFL_LLM_MAX_TOKENS`, `OPENAI_ORG_ID`,
+    /// `OPENAI_PROJECT_ID`, `IFL_LLM_KEEP_ALIVE`, `IFL_LLM_PROXY`,
+    /// `IFL_LLM_ACCEPT_INVALID_CERTS` (`true` or `1`) and `IFL_LLM_HEADERS`
+    /// (`Name: value` pairs separated by newlines or `;`).
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Like `from_env`, with variables looked up by `var`.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let provider = match var("IFL_LLM_PROVIDER") {
+            Some(name) => serde_json::from_value(Value::String(name.replace('-', "_")))
+                .map_err(|_| format!("Unknown LLM provider '{}'", name))?,
+            None => Provider::default(),
+        };
+        let max_tokens = match var("IFL_LLM_MAX_TOKENS") {
+            Some(value) => Some(
+                value
+                    .parse()
+                    .map_err(|_| format!("Invalid IFL_LLM_MAX_TOKENS '{}'", value))?,
+            ),
+            None => None,
+        };
+        let mut http = HttpOptions::default()
+            .with_proxy(var("IFL_LLM_PROXY").as_deref())
+            .with_accept_invalid_certs(
+                var("IFL_LLM_ACCEPT_INVALID_CERTS").is_some_and(|v| v == "true" || v == "1"),
+            );
+        for header in var("IFL_LLM_HEADERS")
+            .iter()
+            .flat_map(|h| h.split([';', '\n']))
+        {
+            if header.trim().is_empty() {
+                continue;
+            }
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid IFL_LLM_HEADERS entry '{}'", header.trim()))?;
+            http = http.with_header(name.trim(), value.trim());
+        }
+        Ok(Self {
+            provider,
+            base_url: var("IFL_LLM_BASE_URL"),
+            model: var("IFL_LLM_MODEL"),
+            api_key: var("IFL_LLM_API_KEY"),
+            api_key_env: None,
+            organization: var("OPENAI_ORG_ID"),
+            project: var("OPENAI_PROJECT_ID"),
+            anthropic_version: None,
+            max_tokens,
+            keep_alive: var("IFL_LLM_KEEP_ALIVE"),
+            http,
+            fallback: Vec::new(),
+        })
+    }
+
+    /// The configured key, else the one in `api_key_env` or the provider's
+    /// usual variable.
+    pub fn api_key(&self, provider: Provider) -> Option<String> {
+        self.api_key_with(provider, |name| std::env::var(name).ok())
+    }
+
+    fn api_key_with(
+        &self,
+        provider: Provider,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Option<String> {
+        self.api_key.clone().or_else(|| {
+            self.api_key_env
+                .as_deref()
+                .or(provider.api_key_env())
+                .and_then(var)
+        })
+    }
+
+    /// Build the backend. `Auto` uses the local server at `base_url`
+    /// (Ollama's default if unset) when it is healthy, and otherwise falls
+    /// back to Anthropic or OpenAI, whichever has a key in the environment.
+    /// Returns the backend and the provider it resolved to.
+    pub async fn connect(&self) -> Result<(Box<dyn LlmBackend>, Provider), String> {
+        self.connect_with(|name| std::env::var(name).ok()).await
+    }
+
+    /// Like `connect`, with variables looked up by `var`.
+    pub async fn connect_with(
+        &self,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<(Box<dyn LlmBackend>, Provider), String> {
+        // One client, and so one connection pool, for detection and the
+        // backend
+        let client = self.http.client()?;
+        let provider = match self.provider {
+            Provider::Auto => {
+                let local = detect(
+                    self.base_url.as_deref().unwrap_or(OLLAMA_URL),
+                    client.clone(),
+                    self.keep_alive.as_deref(),
+                )
+                .await;
+                let cloud = [Provider::Anthropic, Provider::Openai]
+                    .into_iter()
+                    .find(|&p| self.api_key_with(p, &var).is_some());
+                match cloud {
+                    Some(cloud) if local.health().await.is_err() => cloud,
+                    _ => {
+                        let provider = match local.name() {
+                            "ollama" => Provider::Ollama,
+                            _ => Provider::OpenaiCompatible,
+                        };
+                        return Ok((local, provider));
+                    }
+                }
+            }
+            provider => provider,
+        };
+        // A cloud fallback from Auto ignores the local base URL
+        let base_url = match self.provider {
+            Provider::Auto => None,
+            _ => self.base_url.as_deref(),
+        };
+
+        let backend: Box<dyn LlmBackend> = match provider {
+            Provider::Auto => unreachable!("resolved above"),
+            Provider::Ollama => {
+                let backend =
+                    OllamaBackend::new(base_url.unwrap_or(OLLAMA_URL)).with_http_client(client);
+                match &self.keep_alive {
+                    Some(keep_alive) => Box::new(backend.with_keep_alive(keep_alive)),
+                    None => Box::new(backend),
+                }
+            }
+            Provider::OpenaiCompatible | Provider::Openai => {
+                let default_url = if provider == Provider::Openai {
+                    OPENAI_URL.to_string()
+                } else {
+                    format!("{}/v1/chat/completions", OLLAMA_URL)
+                };
+                let mut backend = OpenAiCompatBackend::new(base_url.unwrap_or(&default_url))
+                    .with_http_client(client);
+                match self.api_key_with(provider, &var) {
+                    Some(api_key) => backend = backend.with_api_key(&api_key),
+                    None if provider == Provider::Openai => {
+                        return Err(
+                            "OpenAI needs an API key: set OPENAI_API_KEY or `api_key_env`"
+                                .to_string(),
+                        )
+                    }
+                    None => {}
+                }
+                if let Some(organization) = &self.organization {
+                    backend = backend.with_organization(organization);
+                }
+                if let Some(project) = &self.project {
+                    backend = backend.with_project(project);
+                }
+                Box::new(backend)
+            }
+            Provider::Anthropic => {
+                let api_key = self
+                    .api_key_with(provider, &var)
+                    .ok_or("Anthropic needs an API key: set ANTHROPIC_API_KEY or `api_key_env`")?;
+                let mut backend =
+                    AnthropicBackend::new(base_url.unwrap_or(ANTHROPIC_URL), &api_key)
+                        .with_http_client(client);
+                if let Some(version) = &self.anthropic_version {
+                    backend = backend.with_version(version);
+                }
+                if let Some(max_tokens) = self.max_tokens {
+                    backend = backend.with_max_tokens(max_tokens);
+                }
+                Box::new(backend)
+            }
+        };
+        Ok((backend, provider))
+    }
+}
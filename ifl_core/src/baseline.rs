@@ -0,0 +1,116 @@
+use crate::profile::{
+    EditingFeatures, InputModality, NormalizedFeatures, SourceFeatures, SourceType,
+    StructureFeatures, TimingFeatures,
+};
+use serde::{Deserialize, Serialize};
+
+/// Messages needed before z-scores are considered meaningful.
+const MIN_BASELINE_SAMPLES: u64 = 3;
+
+/// Running mean/variance (Welford) for a single metric.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunningStat {
+    pub count: u64,
+    pub mean: f64,
+    m2: f64,
+}
+
+impl RunningStat {
+    pub fn push(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        if self.count < 2 {
+            0.0
+        } else {
+            (self.m2 / (self.count - 1) as f64).sqrt()
+        }
+    }
+
+    pub fn z_score(&self, value: f64) -> f32 {
+        let sd = self.std_dev();
+        if sd > f64::EPSILON {
+            ((value - self.mean) / sd) as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Per-user distribution of behavioral metrics, accumulated across messages.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserBaseline {
+    pub speed: RunningStat,
+    pub backspace_rate: RunningStat,
+    pub long_pauses: RunningStat,
+}
+
+impl UserBaseline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.speed.count
+    }
+
+    pub fn update(
+        &mut self,
+        source: &SourceFeatures,
+        timing: &TimingFeatures,
+        editing: &EditingFeatures,
+        structure: &StructureFeatures,
+    ) {
+        // Pure pastes carry no typing rhythm, and assistive input a different one
+        if source.source_type == SourceType::PasteOnly
+            || source.input_modality != InputModality::Keyboard
+            || timing.total_duration_ms == 0
+        {
+            return;
+        }
+        self.speed.push(timing.avg_chars_per_sec as f64);
+        self.backspace_rate
+            .push(Self::backspace_rate(editing, structure));
+        self.long_pauses.push(timing.long_pause_count as f64);
+    }
+
+    pub fn normalize(
+        &self,
+        timing: &TimingFeatures,
+        editing: &EditingFeatures,
+        structure: &StructureFeatures,
+    ) -> Option<NormalizedFeatures> {
+        if self.samples() < MIN_BASELINE_SAMPLES {
+            return None;
+        }
+        Some(NormalizedFeatures {
+            speed_z: self.speed.z_score(timing.avg_chars_per_sec as f64),
+            backspace_rate_z: self
+                .backspace_rate
+                .z_score(Self::backspace_rate(editing, structure)),
+            long_pause_z: self.long_pauses.z_score(timing.long_pause_count as f64),
+            baseline_samples: self.samples(),
+        })
+    }
+
+    /// Share of the user's messages expected below a z-score (normal CDF), 0-100.
+    pub fn percentile(z: f32) -> f32 {
+        // Abramowitz & Stegun 7.1.26 approximation of erf
+        let x = (z as f64 / std::f64::consts::SQRT_2).abs();
+        let t = 1.0 / (1.0 + 0.3275911 * x);
+        let poly = t
+            * (0.254829592
+                + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+        let erf = 1.0 - poly * (-x * x).exp();
+        let cdf = if z >= 0.0 { 1.0 + erf } else { 1.0 - erf };
+        (cdf * 50.0) as f32
+    }
+
+    fn backspace_rate(editing: &EditingFeatures, structure: &StructureFeatures) -> f64 {
+        editing.backspace_count as f64 / structure.char_count.max(1) as f64
+    }
+}
@@ -0,0 +1,211 @@
+use crate::format::{profile_row, render_one, render_rows, Format, Row};
+use crate::replay::{read_events, replay, Replay};
+use clap::{Args, ValueEnum};
+use ifl_core::simulate::{simulate, Persona};
+use ifl_core::{IflCore, InputEvent, InputProfile};
+use rayon::prelude::*;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug)]
+pub struct AnalyzeArgs {
+    /// Input text to analyze; read from stdin if missing
+    #[arg(short, long, conflicts_with = "dir")]
+    text: Option<String>,
+
+    /// Analyze every event log (`.json` or `.jsonl`, as `ifl replay`
+    /// reads them) under this directory instead, one row per file
+    #[arg(short, long)]
+    dir: Option<String>,
+
+    /// File for the rows of `--dir`, printed if missing
+    #[arg(short, long, requires = "dir")]
+    out: Option<String>,
+
+    /// Output format; for `--dir`, the default follows the `--out`
+    /// extension (`.json`, `.jsonl`, `.yaml`), else CSV
+    #[arg(short, long, value_enum)]
+    format: Option<Format>,
+
+    /// Simulation mode
+    #[arg(short, long, value_enum, default_value_t = Mode::Typed)]
+    mode: Mode,
+
+    /// Typing speed in WPM (only for Typed mode)
+    #[arg(long, default_value_t = 60)]
+    wpm: u64,
+
+    /// Type the text as a kind of writer would, with their pauses and
+    /// edits: hesitant, flowing, editing, scattered or bilingual (IME for
+    /// Japanese)
+    #[arg(long, conflicts_with_all = ["mode", "wpm", "dir"])]
+    persona: Option<Persona>,
+
+    /// Seed for `--persona`; the same seed gives the same events
+    #[arg(long, default_value_t = 0, requires = "persona")]
+    seed: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum Mode {
+    Typed,
+    Paste,
+    Mixed,
+}
+
+/// Feed the text to the analyzer as the simulated user would enter it and
+/// print the profile, or with `--dir`, replay a directory of event logs.
+pub fn run(args: AnalyzeArgs) -> Result<(), String> {
+    if let Some(dir) = &args.dir {
+        let format = args
+            .format
+            .or(args.out.as_deref().and_then(Format::for_file))
+            .unwrap_or(Format::Csv);
+        return run_dir(Path::new(dir), args.out.as_deref(), format);
+    }
+    // Get input text (arg or stdin)
+    let text = match args.text {
+        Some(t) => t,
+        None => {
+            let mut buffer = String::new();
+            io::stdin()
+                .read_to_string(&mut buffer)
+                .map_err(|e| format!("cannot read stdin: {}", e))?;
+            buffer
+        }
+    };
+    if text.trim().is_empty() {
+        return Err("No input text provided.".to_string());
+    }
+
+    let core = IflCore::new();
+    let id = core.start_message()?;
+    if let Some(persona) = args.persona {
+        for event in simulate(&text, persona, args.seed) {
+            core.push_event(&id, event)?;
+        }
+    } else {
+        let mut ts = 1000; // Start at 1s
+        let char_delay_ms = (60_000.0 / (args.wpm.max(1) as f64 * 5.0)) as u64;
+
+        match args.mode {
+            Mode::Typed => {
+                // Simulate typing
+                for ch in text.chars() {
+                    core.push_event(&id, InputEvent::KeyInsert { ch, ts })?;
+                    ts += char_delay_ms;
+                }
+            }
+            Mode::Paste => {
+                // Simulate paste
+                core.push_event(&id, InputEvent::paste(&text, ts))?;
+                ts += 100;
+            }
+            Mode::Mixed => {
+                // Simulate mixed (half typed, half pasted)
+                let split = text
+                    .char_indices()
+                    .nth(text.chars().count() / 2)
+                    .map_or(text.len(), |(i, _)| i);
+                let (first, second) = text.split_at(split);
+
+                // Type first half
+                for ch in first.chars() {
+                    core.push_event(&id, InputEvent::KeyInsert { ch, ts })?;
+                    ts += char_delay_ms;
+                }
+
+                // Paste second half
+                core.push_event(&id, InputEvent::paste(second, ts))?;
+                ts += 500;
+            }
+        }
+
+        core.push_event(&id, InputEvent::Submit { ts })?;
+    }
+    let profile: InputProfile =
+        serde_json::from_str(&core.finalize_message(&id, &text)?).map_err(|e| e.to_string())?;
+    let format = args.format.unwrap_or(Format::Json);
+    print!(
+        "{}",
+        render_one(format, &profile, &profile_row(&profile, &text))?
+    );
+    Ok(())
+}
+
+/// Replay each event log under `dir` in a session of its own, in parallel,
+/// and write one flat row per profile.
+fn run_dir(dir: &Path, out: Option<&str>, format: Format) -> Result<(), String> {
+    let mut files = Vec::new();
+    find_event_files(dir, &mut files)
+        .map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+    files.sort();
+    if files.is_empty() {
+        return Err(format!("No .json or .jsonl files in {}", dir.display()));
+    }
+
+    let results: Vec<(String, Result<Replay, String>)> = files
+        .par_iter()
+        .map(|path| {
+            let name = path.strip_prefix(dir).unwrap_or(path).display().to_string();
+            let result = std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| read_events(&json))
+                .and_then(|events| replay(events, None));
+            (name, result)
+        })
+        .collect();
+
+    let mut rows = Vec::new();
+    for (name, result) in results {
+        match result {
+            Ok(replayed) => rows.push(file_row(&name, &replayed)),
+            Err(e) => eprintln!("Skipping {}: {}", name, e),
+        }
+    }
+    if rows.is_empty() {
+        return Err("None of the files could be analyzed".to_string());
+    }
+
+    let content = render_rows(format, &rows)?;
+    match out {
+        Some(path) => {
+            std::fs::write(path, content).map_err(|e| format!("cannot write {}: {}", path, e))?;
+            eprintln!(
+                "Analyzed {} of {} files to {}",
+                rows.len(),
+                files.len(),
+                path
+            );
+        }
+        None => print!("{}", content),
+    }
+    Ok(())
+}
+
+/// The `.json` and `.jsonl` files under `dir`, at any depth.
+fn find_event_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_event_files(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext == "json" || ext == "jsonl")
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The row of `profile_row` after the file and whether the events gave
+/// the whole text.
+fn file_row(file: &str, replayed: &Replay) -> Row {
+    let mut row: Row = vec![
+        ("file", file.into()),
+        ("exact_text", replayed.reconstruction.is_exact().into()),
+    ];
+    row.extend(profile_row(&replayed.profile, &replayed.text));
+    row
+}
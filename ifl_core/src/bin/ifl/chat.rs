@@ -0,0 +1,423 @@
+//! `ifl chat`: talk to a model in the terminal while the analyzer watches
+//! the keystrokes, with the profile of the message being written alongside.
+
+use crate::editor::{Action, Editor};
+use crate::llm::LlmArgs;
+use crate::record::TerminalGuard;
+use clap::Args;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use ifl_core::llm_client::{Conversation, LlmClient, LlmError, LlmResponse};
+use ifl_core::{IflCore, InputProfile};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use std::future::Future;
+use std::io::{self, IsTerminal};
+use std::pin::Pin;
+use tokio::sync::mpsc;
+
+const HELP: &str = "Enter sends · Alt+Enter new line · PgUp/PgDn scroll · Esc quits";
+/// Width of the profile panel.
+const PANEL_WIDTH: u16 = 34;
+/// Rows the input box grows to before it scrolls.
+const INPUT_ROWS: usize = 6;
+
+#[derive(Args, Debug)]
+pub struct ChatArgs {
+    #[command(flatten)]
+    llm: LlmArgs,
+}
+
+/// A conversation handed to the answer being generated, and back with it.
+type Generation<'a> =
+    Pin<Box<dyn Future<Output = (Conversation, Result<LlmResponse, LlmError>)> + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Speaker {
+    User,
+    Model,
+    Error,
+}
+
+struct Turn {
+    speaker: Speaker,
+    text: String,
+}
+
+/// Everything on screen.
+struct Chat {
+    core: IflCore,
+    /// The message being written.
+    message_id: String,
+    editor: Editor,
+    turns: Vec<Turn>,
+    /// The profile of the message being written, or of the last one sent
+    /// until the next is started.
+    profile: Option<InputProfile>,
+    last_response: Option<LlmResponse>,
+    /// Transcript lines scrolled back from the bottom.
+    scroll_back: usize,
+}
+
+impl Chat {
+    fn new() -> Result<Self, String> {
+        let core = IflCore::new();
+        let message_id = core.start_message()?;
+        Ok(Self {
+            core,
+            message_id,
+            editor: Editor::new(),
+            turns: Vec::new(),
+            profile: None,
+            last_response: None,
+            scroll_back: 0,
+        })
+    }
+
+    /// Hand the editor's new events to the analyzer and refresh the
+    /// preview.
+    fn sync(&mut self) {
+        let events = self.editor.take_events();
+        if events.is_empty() {
+            return;
+        }
+        for event in events {
+            // An event the session rejects is left out of the profile
+            let _ = self.core.push_event(&self.message_id, event);
+        }
+        let text = self.editor.text();
+        if let Ok(json) = self.core.preview_message(&self.message_id, &text) {
+            self.profile = serde_json::from_str(&json).ok();
+        }
+    }
+
+    /// Finish the message and start the next one, returning the text and
+    /// its profile.
+    fn submit(&mut self) -> Result<(String, InputProfile), String> {
+        let text = self.editor.text();
+        for event in self.editor.take_events() {
+            let _ = self.core.push_event(&self.message_id, event);
+        }
+        let json = self.core.finalize_message(&self.message_id, &text)?;
+        let profile: InputProfile = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+        self.message_id = self.core.start_message()?;
+        self.editor.clear();
+        self.profile = Some(profile.clone());
+        self.turns.push(Turn {
+            speaker: Speaker::User,
+            text: text.clone(),
+        });
+        self.turns.push(Turn {
+            speaker: Speaker::Model,
+            text: String::new(),
+        });
+        self.scroll_back = 0;
+        Ok((text, profile))
+    }
+
+    /// Add a streamed token to the answer being written.
+    fn append(&mut self, token: &str) {
+        if let Some(turn) = self.turns.last_mut() {
+            turn.text.push_str(token);
+        }
+    }
+
+    /// Replace the streamed answer with the finished one, or the error.
+    fn finish(&mut self, result: Result<LlmResponse, LlmError>) {
+        match result {
+            Ok(response) => {
+                if let Some(turn) = self.turns.last_mut() {
+                    turn.text = response.content.clone();
+                }
+                self.last_response = Some(response);
+            }
+            Err(e) => {
+                if self
+                    .turns
+                    .last()
+                    .is_some_and(|turn| turn.speaker == Speaker::Model && turn.text.is_empty())
+                {
+                    self.turns.pop();
+                }
+                self.turns.push(Turn {
+                    speaker: Speaker::Error,
+                    text: e.to_string(),
+                });
+            }
+        }
+    }
+}
+
+/// Chat with the configured model, analyzing each message as it is typed.
+pub fn run(args: ChatArgs) -> Result<(), String> {
+    if !io::stderr().is_terminal() {
+        return Err("`ifl chat` needs a terminal to type in".to_string());
+    }
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    runtime.block_on(async {
+        let client = args.llm.connect().await?;
+        let mut chat = Chat::new()?;
+        // Report a missing server or model up front rather than mid-chat
+        if let Err(e) = client.health().await {
+            chat.turns.push(Turn {
+                speaker: Speaker::Error,
+                text: format!(
+                    "LLM unavailable: {} (run with --mock to try without one)",
+                    e
+                ),
+            });
+        }
+        let _guard = TerminalGuard::enter().map_err(|e| e.to_string())?;
+        let mut terminal =
+            Terminal::new(CrosstermBackend::new(io::stderr())).map_err(|e| e.to_string())?;
+        converse(&mut terminal, &client, &mut chat)
+            .await
+            .map_err(|e| e.to_string())
+    })
+}
+
+async fn converse(
+    terminal: &mut Terminal<CrosstermBackend<io::Stderr>>,
+    client: &LlmClient,
+    chat: &mut Chat,
+) -> io::Result<()> {
+    let mut events = EventStream::new();
+    let (tokens, mut streamed) = mpsc::unbounded_channel::<String>();
+    let mut conversation = Some(Conversation::new());
+    let mut generation: Option<Generation> = None;
+    loop {
+        let busy = generation.is_some();
+        terminal.draw(|frame| draw(frame, chat, client, busy))?;
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    return Ok(());
+                };
+                let event = event?;
+                if let Event::Key(key) = &event {
+                    if scroll(chat, key) {
+                        continue;
+                    }
+                    // One answer at a time; the next message can be written
+                    // meanwhile
+                    if busy && is_send(key) {
+                        continue;
+                    }
+                }
+                match chat.editor.handle(&event) {
+                    Action::Continue => chat.sync(),
+                    Action::Cancel => return Ok(()),
+                    Action::Submit => {
+                        let (text, profile) = match chat.submit() {
+                            Ok(submitted) => submitted,
+                            Err(e) => {
+                                chat.turns.push(Turn { speaker: Speaker::Error, text: e });
+                                continue;
+                            }
+                        };
+                        let mut sending = conversation.take().expect("no answer is being generated");
+                        let tokens = tokens.clone();
+                        generation = Some(Box::pin(async move {
+                            let result = sending
+                                .send_stream(client, &text, &profile, |token| {
+                                    let _ = tokens.send(token.to_string());
+                                })
+                                .await;
+                            (sending, result)
+                        }));
+                    }
+                }
+            }
+            Some(token) = streamed.recv() => chat.append(&token),
+            (returned, result) = async {
+                match generation.as_mut() {
+                    Some(answer) => answer.await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                generation = None;
+                conversation = Some(returned);
+                // Tokens sent before the answer finished are still queued
+                while let Ok(token) = streamed.try_recv() {
+                    chat.append(&token);
+                }
+                chat.finish(result);
+            }
+        }
+    }
+}
+
+/// Page through the transcript; true if `key` did.
+fn scroll(chat: &mut Chat, key: &KeyEvent) -> bool {
+    if key.kind == KeyEventKind::Release {
+        return false;
+    }
+    match key.code {
+        KeyCode::PageUp => chat.scroll_back += 10,
+        KeyCode::PageDown => chat.scroll_back = chat.scroll_back.saturating_sub(10),
+        _ => return false,
+    }
+    true
+}
+
+/// Enter without Alt, which the editor takes as sending the message.
+fn is_send(key: &KeyEvent) -> bool {
+    key.code == KeyCode::Enter && !key.modifiers.contains(KeyModifiers::ALT)
+}
+
+fn draw(frame: &mut Frame, chat: &mut Chat, client: &LlmClient, busy: bool) {
+    let [main, panel] = Layout::horizontal([Constraint::Min(20), Constraint::Length(PANEL_WIDTH)])
+        .areas(frame.area());
+    let (rows, row, column) = chat
+        .editor
+        .layout(main.width.saturating_sub(2).max(1) as usize);
+    let input_rows = rows.len().min(INPUT_ROWS);
+    let [transcript, input, help] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(input_rows as u16 + 2),
+        Constraint::Length(1),
+    ])
+    .areas(main);
+
+    draw_transcript(frame, chat, client, transcript);
+
+    // Keep the cursor's row in the box
+    let first = (row + 1).saturating_sub(input_rows);
+    let lines: Vec<Line> = rows
+        .into_iter()
+        .skip(first)
+        .take(input_rows)
+        .map(Line::from)
+        .collect();
+    let title = if busy {
+        " Message · answering… "
+    } else {
+        " Message "
+    };
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(title)),
+        input,
+    );
+    frame.set_cursor_position(Position::new(
+        input.x + 1 + column as u16,
+        input.y + 1 + (row - first) as u16,
+    ));
+    frame.render_widget(Paragraph::new(HELP.dim()), help);
+
+    draw_profile(frame, chat, client, panel);
+}
+
+fn draw_transcript(frame: &mut Frame, chat: &mut Chat, client: &LlmClient, area: Rect) {
+    let mut text = Text::default();
+    for turn in &chat.turns {
+        let (name, color) = match turn.speaker {
+            Speaker::User => ("you", Color::Cyan),
+            Speaker::Model => (client.model(), Color::Green),
+            Speaker::Error => ("error", Color::Red),
+        };
+        text.push_line(Line::from(Span::styled(
+            name.to_string(),
+            Style::default().fg(color).add_modifier(Modifier::BOLD),
+        )));
+        for line in turn.text.lines() {
+            let line = Line::from(line.replace('\t', "    "));
+            text.push_line(match turn.speaker {
+                Speaker::Error => line.red(),
+                _ => line,
+            });
+        }
+        text.push_line(Line::default());
+    }
+    let block = Block::bordered().title(format!(" ifl chat · {} ", client.model()));
+    let paragraph = Paragraph::new(text).wrap(Wrap { trim: false });
+    // Follow the answer as it streams, unless scrolled back
+    let height = area.height.saturating_sub(2) as usize;
+    let total = paragraph.line_count(area.width.saturating_sub(2));
+    let bottom = total.saturating_sub(height);
+    chat.scroll_back = chat.scroll_back.min(bottom);
+    let top = (bottom - chat.scroll_back).min(u16::MAX as usize) as u16;
+    frame.render_widget(paragraph.block(block).scroll((top, 0)), area);
+}
+
+fn draw_profile(frame: &mut Frame, chat: &Chat, client: &LlmClient, area: Rect) {
+    let block = Block::bordered().title(" Profile ");
+    let Some(profile) = chat.profile.as_ref() else {
+        let waiting = Paragraph::new("Start typing to see how the message reads.".dim())
+            .wrap(Wrap { trim: true });
+        frame.render_widget(waiting.block(block), area);
+        return;
+    };
+    // Tags the prompt only hedges on are marked with "?"
+    let context = client.prompt_context(profile);
+    let hint = |label: &str, tentative: bool| {
+        if tentative {
+            format!("{}?", label)
+        } else {
+            label.to_string()
+        }
+    };
+    let field = |name: &str, value: String| {
+        Line::from(vec![
+            Span::raw(format!("{:<11}", name)).dim(),
+            Span::raw(value),
+        ])
+    };
+    let tags = &profile.tags;
+    let mut lines = vec![
+        field("Modes", hint(&context.labels.modes, context.tentative.mode)),
+        field("Tone", hint(&context.labels.tone, context.tentative.tone)),
+        field(
+            "Depth",
+            hint(&context.labels.depth, context.tentative.depth),
+        ),
+        field("Scope", context.labels.scope.clone()),
+        field(
+            "User state",
+            hint(&context.labels.user_state, context.tentative.user_state),
+        ),
+        field("Intents", context.labels.intents.clone()),
+        field(
+            "Confidence",
+            format!(
+                "mode {:.0}% tone {:.0}%",
+                tags.confidence.mode * 100.0,
+                tags.confidence.tone * 100.0
+            ),
+        ),
+    ];
+    if tags.clarify_before_answering {
+        lines.push(Line::from("Will ask a clarifying question".yellow()));
+    }
+    lines.push(Line::default());
+    lines.push(field(
+        "Speed",
+        format!("{:.1} chars/s", profile.timing.avg_chars_per_sec),
+    ));
+    lines.push(field("Bursts", profile.timing.typing_bursts.to_string()));
+    lines.push(field(
+        "Backspaces",
+        profile.editing.backspace_count.to_string(),
+    ));
+    lines.push(field(
+        "Pasted",
+        format!("{:.0}%", profile.source.paste_ratio * 100.0),
+    ));
+    if let Some(response) = &chat.last_response {
+        lines.push(Line::default());
+        lines.push(Line::from("Last answer".bold()));
+        lines.push(field("Latency", format!("{} ms", response.latency_ms)));
+        lines.push(field(
+            "Tokens",
+            format!(
+                "{} in, {} out",
+                response.prompt_tokens, response.completion_tokens
+            ),
+        ));
+    }
+    let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false });
+    frame.render_widget(paragraph.block(block), area);
+}
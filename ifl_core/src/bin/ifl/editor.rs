@@ -0,0 +1,274 @@
+//! A one-message text buffer driven by terminal input, recording what the
+//! user does as `InputEvent`s with real timestamps.
+
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ifl_core::{DeleteKind, InputEvent};
+use std::time::{SystemTime, UNIX_EPOCH};
+use unicode_width::UnicodeWidthChar;
+
+/// What the last key asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Continue,
+    Submit,
+    Cancel,
+}
+
+/// The message being written: its characters, the cursor and selection
+/// (in characters, as `event::reconstruct_text` reads them), and the
+/// events so far.
+#[derive(Debug, Default)]
+pub struct Editor {
+    text: Vec<char>,
+    cursor: usize,
+    /// The other end of the selection, while Shift is held.
+    anchor: Option<usize>,
+    events: Vec<InputEvent>,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The events since the last call; after a submit, the last is
+    /// `InputEvent::Submit`.
+    pub fn take_events(&mut self) -> Vec<InputEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn text(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    /// Start the next message, dropping any events not yet taken.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Apply a terminal event. Enter submits and Alt+Enter starts a new
+    /// line; Esc and Ctrl+C cancel. Shift with the arrows, Home or End
+    /// selects, and Ctrl+X cuts the selection.
+    pub fn handle(&mut self, event: &Event) -> Action {
+        match event {
+            Event::Key(key) if key.kind != KeyEventKind::Release => self.key(key),
+            Event::Paste(text) => {
+                // Terminals send pasted line breaks as carriage returns
+                let text = text.replace("\r\n", "\n").replace('\r', "\n");
+                self.delete_selection();
+                self.push(InputEvent::paste(&text, now_ms()));
+                let chars: Vec<char> = text.chars().collect();
+                self.text
+                    .splice(self.cursor..self.cursor, chars.iter().copied());
+                self.cursor += chars.len();
+                Action::Continue
+            }
+            _ => Action::Continue,
+        }
+    }
+
+    fn key(&mut self, key: &KeyEvent) -> Action {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        match key.code {
+            KeyCode::Esc => return Action::Cancel,
+            KeyCode::Char('c') if ctrl => return Action::Cancel,
+            KeyCode::Char('x') if ctrl => {
+                if let Some((start, end)) = self.selection() {
+                    self.push(InputEvent::Cut {
+                        length: end - start,
+                        ts: now_ms(),
+                    });
+                    self.text.drain(start..end);
+                    self.cursor = start;
+                    self.anchor = None;
+                }
+            }
+            KeyCode::Enter if key.modifiers.contains(KeyModifiers::ALT) => self.insert('\n'),
+            // Nothing to send yet
+            KeyCode::Enter if self.text.iter().all(|c| c.is_whitespace()) => {}
+            KeyCode::Enter => {
+                self.push(InputEvent::Submit { ts: now_ms() });
+                return Action::Submit;
+            }
+            KeyCode::Char(ch) if !ctrl => self.insert(ch),
+            KeyCode::Tab => self.insert('\t'),
+            KeyCode::Backspace => self.delete(DeleteKind::Backspace),
+            KeyCode::Delete => self.delete(DeleteKind::Delete),
+            KeyCode::Left => self.move_to(self.cursor.saturating_sub(1), shift),
+            KeyCode::Right => self.move_to((self.cursor + 1).min(self.text.len()), shift),
+            KeyCode::Home => self.move_to(self.line_start(), shift),
+            KeyCode::End => self.move_to(self.line_end(), shift),
+            KeyCode::Up => self.move_to(self.vertical(false), shift),
+            KeyCode::Down => self.move_to(self.vertical(true), shift),
+            _ => {}
+        }
+        Action::Continue
+    }
+
+    fn push(&mut self, event: InputEvent) {
+        self.events.push(event);
+    }
+
+    /// The selection as `start..end`, when it is not empty.
+    fn selection(&self) -> Option<(usize, usize)> {
+        let anchor = self.anchor?;
+        let (start, end) = (anchor.min(self.cursor), anchor.max(self.cursor));
+        (start < end).then_some((start, end))
+    }
+
+    /// Remove the selection as typing over it does; the event that replaces
+    /// it already tells the analyzer.
+    fn delete_selection(&mut self) -> bool {
+        let selection = self.selection();
+        self.anchor = None;
+        match selection {
+            Some((start, end)) => {
+                self.text.drain(start..end);
+                self.cursor = start;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, ch: char) {
+        self.delete_selection();
+        self.push(InputEvent::KeyInsert { ch, ts: now_ms() });
+        self.text.insert(self.cursor, ch);
+        self.cursor += 1;
+    }
+
+    fn delete(&mut self, kind: DeleteKind) {
+        let ts = now_ms();
+        if self.selection().is_some() {
+            self.push(InputEvent::KeyDelete { kind, count: 1, ts });
+            self.delete_selection();
+            return;
+        }
+        self.anchor = None;
+        match kind {
+            DeleteKind::Backspace if self.cursor > 0 => {
+                self.push(InputEvent::KeyDelete { kind, count: 1, ts });
+                self.cursor -= 1;
+                self.text.remove(self.cursor);
+            }
+            DeleteKind::Delete if self.cursor < self.text.len() => {
+                self.push(InputEvent::KeyDelete { kind, count: 1, ts });
+                self.text.remove(self.cursor);
+            }
+            _ => {}
+        }
+    }
+
+    /// Move the cursor, extending the selection if `select`.
+    fn move_to(&mut self, position: usize, select: bool) {
+        let ts = now_ms();
+        if select {
+            let anchor = *self.anchor.get_or_insert(self.cursor);
+            self.cursor = position;
+            self.push(InputEvent::SelectionChange {
+                start: anchor,
+                end: position,
+                ts,
+            });
+        } else {
+            let had_selection = self.anchor.take().is_some();
+            if position != self.cursor || had_selection {
+                self.cursor = position;
+                self.push(InputEvent::CursorMove { position, ts });
+            }
+        }
+    }
+
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor]
+            .iter()
+            .rposition(|&c| c == '\n')
+            .map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.text[self.cursor..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(self.text.len(), |i| self.cursor + i)
+    }
+
+    /// The position one line up or down at the same column, or the start
+    /// or end of the text past the first or last line.
+    fn vertical(&self, down: bool) -> usize {
+        let start = self.line_start();
+        let column = self.cursor - start;
+        let (line_start, line_end) = if down {
+            let end = self.line_end();
+            if end == self.text.len() {
+                return end;
+            }
+            let next = end + 1;
+            let next_end = self.text[next..]
+                .iter()
+                .position(|&c| c == '\n')
+                .map_or(self.text.len(), |i| next + i);
+            (next, next_end)
+        } else {
+            if start == 0 {
+                return 0;
+            }
+            let previous = self.text[..start - 1]
+                .iter()
+                .rposition(|&c| c == '\n')
+                .map_or(0, |i| i + 1);
+            (previous, start - 1)
+        };
+        (line_start + column).min(line_end)
+    }
+
+    /// The text as screen rows at most `width` columns wide, with the
+    /// cursor's row and column.
+    pub fn layout(&self, width: usize) -> (Vec<String>, usize, usize) {
+        let width = width.max(1);
+        let mut rows = vec![String::new()];
+        let mut column = 0;
+        let mut cursor = (0, 0);
+        for (i, &ch) in self.text.iter().enumerate() {
+            if ch == '\n' {
+                if i == self.cursor {
+                    cursor = (rows.len() - 1, column);
+                }
+                rows.push(String::new());
+                column = 0;
+                continue;
+            }
+            // Tabs show as a space so the columns stay right
+            let (shown, char_width) = match ch {
+                '\t' => (' ', 1),
+                ch => (ch, ch.width().unwrap_or(0)),
+            };
+            if column + char_width > width {
+                rows.push(String::new());
+                column = 0;
+            }
+            if i == self.cursor {
+                cursor = (rows.len() - 1, column);
+            }
+            rows.last_mut().expect("rows start non-empty").push(shown);
+            column += char_width;
+        }
+        if self.cursor == self.text.len() {
+            if column >= width {
+                rows.push(String::new());
+                column = 0;
+            }
+            cursor = (rows.len() - 1, column);
+        }
+        (rows, cursor.0, cursor.1)
+    }
+}
+
+/// Milliseconds since the Unix epoch, the timestamps of recorded events.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
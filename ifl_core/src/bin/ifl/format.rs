@@ -0,0 +1,260 @@
+//! How `analyze` and `replay` print what they found: the whole document as
+//! JSON or YAML, or its key metrics and tags as flat rows for a table or
+//! CSV.
+
+use clap::ValueEnum;
+use ifl_core::InputProfile;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Named columns, in display order.
+pub type Row = Vec<(&'static str, Value)>;
+
+/// Columns of a table with one line per file; the rest do not fit.
+const TABLE_COLUMNS: &[&str] = &[
+    "file",
+    "source",
+    "chars_per_sec",
+    "backspaces",
+    "answer_mode",
+    "tone",
+    "depth",
+    "user_state",
+    "clarify",
+    "text",
+];
+/// Text longer than this is cut short in a table.
+const TABLE_TEXT_WIDTH: usize = 40;
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum Format {
+    /// Pretty-printed JSON
+    Json,
+    /// One line of JSON per document, or per row for several
+    JsonCompact,
+    Yaml,
+    /// The key metrics and tags, aligned for reading
+    Table,
+    /// The key metrics and tags under a header line
+    Csv,
+}
+
+impl Format {
+    /// The format a file name asks for, by its extension.
+    pub fn for_file(path: &str) -> Option<Self> {
+        let extension = path.rsplit_once('.')?.1;
+        match extension.to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "jsonl" | "ndjson" => Some(Format::JsonCompact),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// The features and tags worth comparing across messages, flattened: lists
+/// stay lists in JSON and are joined in tables and CSV.
+pub fn profile_row(profile: &InputProfile, text: &str) -> Row {
+    let tags = &profile.tags;
+    vec![
+        ("source", label(&profile.source.source_type)),
+        ("paste_ratio", rounded(profile.source.paste_ratio)),
+        ("chars", profile.structure.char_count.into()),
+        ("duration_ms", profile.timing.total_duration_ms.into()),
+        ("chars_per_sec", rounded(profile.timing.avg_chars_per_sec)),
+        ("typing_bursts", profile.timing.typing_bursts.into()),
+        ("long_pauses", profile.timing.long_pause_count.into()),
+        ("backspaces", profile.editing.backspace_count.into()),
+        ("typo_corrections", profile.editing.typo_corrections.into()),
+        ("undos", profile.editing.undo_count.into()),
+        ("answer_mode", label(&tags.answer_mode)),
+        ("tone", label(&tags.tone_hint)),
+        ("depth", label(&tags.depth_hint)),
+        ("scope", label(&tags.scope_hint)),
+        ("user_state", label(&tags.user_state)),
+        ("pragmatic_intent", label(&tags.pragmatic_intent)),
+        ("mode_confidence", rounded(tags.confidence.mode)),
+        ("tone_confidence", rounded(tags.confidence.tone)),
+        ("depth_confidence", rounded(tags.confidence.depth)),
+        ("user_state_confidence", rounded(tags.confidence.user_state)),
+        ("clarify", tags.clarify_before_answering.into()),
+        ("text", text.into()),
+    ]
+}
+
+/// A tag or list of tags by its serialized name.
+fn label(value: &impl Serialize) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+/// Three decimals are plenty to compare runs, and keep `f32` noise out.
+fn rounded(value: f32) -> Value {
+    ((value as f64 * 1000.0).round() / 1000.0).into()
+}
+
+/// One result: all of `document` for JSON and YAML, `row` for a table or
+/// CSV.
+pub fn render_one(format: Format, document: &impl Serialize, row: &Row) -> Result<String, String> {
+    match format {
+        Format::Table => Ok(vertical_table(row)),
+        Format::Csv => Ok(csv(std::slice::from_ref(row))),
+        _ => serialize(format, document),
+    }
+}
+
+/// One row per result: a JSON array, JSON lines, a YAML list, a table or
+/// CSV.
+pub fn render_rows(format: Format, rows: &[Row]) -> Result<String, String> {
+    let objects: Vec<Value> = rows.iter().map(object).collect();
+    match format {
+        Format::JsonCompact => {
+            let mut lines = String::new();
+            for object in &objects {
+                lines.push_str(&object.to_string());
+                lines.push('\n');
+            }
+            Ok(lines)
+        }
+        Format::Table => Ok(table(rows)),
+        Format::Csv => Ok(csv(rows)),
+        _ => serialize(format, &objects),
+    }
+}
+
+fn serialize(format: Format, document: &impl Serialize) -> Result<String, String> {
+    let text = match format {
+        Format::JsonCompact => serde_json::to_string(document).map(|json| json + "\n"),
+        Format::Yaml => return serde_yaml::to_string(document).map_err(|e| e.to_string()),
+        _ => serde_json::to_string_pretty(document).map(|json| json + "\n"),
+    };
+    text.map_err(|e| e.to_string())
+}
+
+fn object(row: &Row) -> Value {
+    let object: Map<String, Value> = row
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect();
+    Value::Object(object)
+}
+
+/// A value as text, with list items joined by `separator`.
+fn cell(value: &Value, separator: &str) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| cell(item, separator))
+            .collect::<Vec<_>>()
+            .join(separator),
+        other => other.to_string(),
+    }
+}
+
+fn csv(rows: &[Row]) -> String {
+    let mut csv = String::new();
+    let Some(first) = rows.first() else {
+        return csv;
+    };
+    let header: Vec<&str> = first.iter().map(|(name, _)| *name).collect();
+    csv.push_str(&header.join(","));
+    csv.push('\n');
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .map(|(_, value)| csv_field(&cell(value, ";")))
+            .collect();
+        csv.push_str(&cells.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quoted when it holds a comma, quote or line break.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// One line per column of a single row: the name, then the value.
+fn vertical_table(row: &Row) -> String {
+    let width = row.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let mut table = String::new();
+    for (name, value) in row {
+        let value = shorten(&cell(value, ", "), TABLE_TEXT_WIDTH * 2);
+        table.push_str(&format!("{:<width$}  {}\n", name, value, width = width));
+    }
+    table
+}
+
+/// One line per row under a header, with the columns in `TABLE_COLUMNS`.
+fn table(rows: &[Row]) -> String {
+    let Some(first) = rows.first() else {
+        return String::new();
+    };
+    let columns: Vec<&str> = first
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| TABLE_COLUMNS.contains(name))
+        .collect();
+    let lines: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .filter(|(name, _)| columns.contains(name))
+                .map(|(_, value)| shorten(&cell(value, ","), TABLE_TEXT_WIDTH))
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            lines
+                .iter()
+                .map(|line| line[i].width())
+                .chain([name.len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let mut table = String::new();
+    let header: Vec<String> = columns.iter().map(|name| name.to_uppercase()).collect();
+    for line in std::iter::once(&header).chain(&lines) {
+        let cells: Vec<String> = line
+            .iter()
+            .zip(&widths)
+            .map(|(text, &width)| format!("{}{}", text, " ".repeat(width - text.width())))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// `text` on one line, cut to `width` columns with an ellipsis.
+fn shorten(text: &str, width: usize) -> String {
+    let text = text.replace(['\n', '\r', '\t'], " ");
+    if text.width() <= width {
+        return text;
+    }
+    let mut short = String::new();
+    let mut used = 0;
+    for ch in text.chars() {
+        let ch_width = ch.width().unwrap_or(0);
+        if used + ch_width + 1 > width {
+            break;
+        }
+        short.push(ch);
+        used += ch_width;
+    }
+    short.push('…');
+    short
+}
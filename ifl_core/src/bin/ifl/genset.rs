@@ -0,0 +1,222 @@
+//! `ifl genset`: a dataset of simulated event logs labeled with the tags
+//! they were written to produce, for regression-testing rule changes and
+//! training `ml::MlRuleEngine`.
+
+use crate::format::{render_rows, Format, Row};
+use crate::replay::replay;
+use clap::Args;
+use ifl_core::profile::AnswerMode;
+use ifl_core::simulate::{simulate, Persona};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Args, Debug)]
+pub struct GensetArgs {
+    /// Event logs to generate
+    #[arg(short, long, default_value_t = 500)]
+    count: usize,
+
+    /// Directory to write `events/` and `labels.csv` to
+    #[arg(short, long)]
+    out: String,
+
+    /// Seed of the first log; each next one adds one
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// A kind of request and the answer mode it asks for, in English and, for
+/// the bilingual persona, in Japanese with English mixed in.
+struct Scenario {
+    name: &'static str,
+    mode: AnswerMode,
+    english: &'static [&'static str],
+    japanese: &'static [&'static str],
+}
+
+const SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "summarize",
+        mode: AnswerMode::Summarize,
+        english: &[
+            "Can you summarize the main points of the article on remote work in a few lines?",
+            "Please summarize what changed in the new release.",
+            "Summarize the pros and cons of working from home in a short list.",
+        ],
+        japanese: &[
+            "この記事の要点を三行でまとめてください。",
+            "新しいリリースで何が変わったか要約してください。",
+        ],
+    },
+    Scenario {
+        name: "implement",
+        mode: AnswerMode::Complete,
+        english: &[
+            "Write a function in Rust that parses a date string and returns the weekday.",
+            "Implement a Python script that renames every file in a folder.",
+        ],
+        japanese: &[
+            "Rustで日付を解析する関数を書いてください。",
+            "Pythonでフォルダ内のファイル名を一括で変更するスクリプトを書いてください。",
+        ],
+    },
+    Scenario {
+        name: "translate",
+        mode: AnswerMode::Translate,
+        english: &[
+            "Translate this into Japanese: the meeting moved to Thursday afternoon.",
+            "Translate into Japanese: thank you for your quick reply.",
+        ],
+        japanese: &[
+            "Translate to English: 明日の会議は午後三時からです。",
+            "この英文を日本語に翻訳してください: The build failed again last night.",
+        ],
+    },
+    Scenario {
+        name: "brainstorm",
+        mode: AnswerMode::Brainstorm,
+        english: &[
+            "Give me some ideas for a birthday party in the park.",
+            "Brainstorm names for a small coffee shop near the station.",
+            "Suggest a few ideas for a weekend side project with a Raspberry Pi.",
+        ],
+        japanese: &["東京で週末に行ける場所のアイデアをいくつか出してください。"],
+    },
+    Scenario {
+        name: "question",
+        mode: AnswerMode::ClarifyQuestion,
+        english: &[
+            "How does garbage collection work in Java?",
+            "What is the difference between a process and a thread?",
+            "What should I check first when a Docker container keeps restarting?",
+        ],
+        japanese: &["Rustのライフタイムはどういう仕組みですか？"],
+    },
+];
+
+/// One generated log and whether the rules found its labels. The file is
+/// named as in `ifl analyze --dir <out>/events`, so the two join on it.
+struct Sample {
+    row: Row,
+    state: Option<(String, bool)>,
+    mode: (String, bool),
+}
+
+/// Write `count` logs, cycling through the personas and then the
+/// scenarios, with their labels; then report how often the rules agree.
+pub fn run(args: GensetArgs) -> Result<(), String> {
+    let out = Path::new(&args.out);
+    let events_dir = out.join("events");
+    if events_dir
+        .read_dir()
+        .is_ok_and(|mut entries| entries.next().is_some())
+    {
+        return Err(format!(
+            "{} already holds a dataset; remove it or choose another --out",
+            events_dir.display()
+        ));
+    }
+    std::fs::create_dir_all(&events_dir)
+        .map_err(|e| format!("cannot create {}: {}", events_dir.display(), e))?;
+
+    let width = args.count.to_string().len().max(4);
+    let samples: Vec<Sample> = (0..args.count)
+        .into_par_iter()
+        .map(|i| {
+            let persona = Persona::ALL[i % Persona::ALL.len()];
+            let scenario = &SCENARIOS[(i / Persona::ALL.len()) % SCENARIOS.len()];
+            let texts = match persona {
+                Persona::Bilingual => scenario.japanese,
+                _ => scenario.english,
+            };
+            let text = texts[(i / (Persona::ALL.len() * SCENARIOS.len())) % texts.len()];
+            let seed = args.seed.wrapping_add(i as u64);
+
+            let events = simulate(text, persona, seed);
+            let file = format!(
+                "{:0width$}-{}-{}.json",
+                i + 1,
+                persona.name(),
+                scenario.name,
+                width = width
+            );
+            let json = serde_json::to_string_pretty(&events).map_err(|e| e.to_string())? + "\n";
+            std::fs::write(events_dir.join(&file), json)
+                .map_err(|e| format!("cannot write {}: {}", file, e))?;
+
+            let profile = replay(events, None)?.profile;
+            let state = persona.expected_state().map(|state| {
+                let found = profile.tags.user_state.contains(&state);
+                (name(&state), found)
+            });
+            let mode = (
+                name(&scenario.mode),
+                profile.tags.answer_mode.contains(&scenario.mode),
+            );
+            let row: Row = vec![
+                ("file", file.into()),
+                ("persona", persona.name().into()),
+                ("scenario", scenario.name.into()),
+                ("seed", seed.into()),
+                (
+                    "user_state",
+                    state
+                        .as_ref()
+                        .map_or(Value::Null, |(s, _)| s.as_str().into()),
+                ),
+                ("answer_mode", mode.0.as_str().into()),
+                ("text", text.into()),
+            ];
+            Ok(Sample { row, state, mode })
+        })
+        .collect::<Result<_, String>>()?;
+
+    let rows: Vec<Row> = samples.iter().map(|sample| sample.row.clone()).collect();
+    let labels = out.join("labels.csv");
+    std::fs::write(&labels, render_rows(Format::Csv, &rows)?)
+        .map_err(|e| format!("cannot write {}: {}", labels.display(), e))?;
+    eprintln!(
+        "Generated {} event logs in {}, labeled in {}",
+        samples.len(),
+        events_dir.display(),
+        labels.display()
+    );
+
+    // How many logs of each label the current rules tag with it
+    let mut found: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for sample in &samples {
+        let labels = sample
+            .state
+            .iter()
+            .map(|(state, hit)| (format!("user_state={}", state), *hit))
+            .chain([(format!("answer_mode={}", sample.mode.0), sample.mode.1)]);
+        for (label, hit) in labels {
+            let counts = found.entry(label).or_default();
+            counts.0 += hit as usize;
+            counts.1 += 1;
+        }
+    }
+    let label_width = found.keys().map(String::len).max().unwrap_or(0);
+    eprintln!("The rules find:");
+    for (label, (hits, total)) in &found {
+        eprintln!(
+            "  {:<width$}  {}/{}",
+            label,
+            hits,
+            total,
+            width = label_width
+        );
+    }
+    Ok(())
+}
+
+/// A tag by its serialized name, as in the profile.
+fn name(tag: &impl Serialize) -> String {
+    match serde_json::to_value(tag) {
+        Ok(Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,61 @@
+//! The model behind `ifl chat` and `ifl serve`, chosen the same way for
+//! both.
+
+use clap::Args;
+use ifl_core::backend::{BackendConfig, MockBackend};
+use ifl_core::llm_client::LlmClient;
+use ifl_core::presets::{PresetLibrary, PromptPreset};
+
+/// Model name of the mock backend.
+const MOCK_MODEL: &str = "mock";
+
+#[derive(Args, Debug)]
+pub struct LlmArgs {
+    /// Backend settings (a `BackendConfig` TOML file); the `IFL_LLM_*`
+    /// environment variables if missing
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// Model to use instead of the configured one
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// Built-in persona for the system prompt: coding, writing, email or
+    /// tutor
+    #[arg(long)]
+    preset: Option<String>,
+
+    /// Answer with the mock backend, which echoes the directives and needs
+    /// no model
+    #[arg(long)]
+    mock: bool,
+}
+
+impl LlmArgs {
+    /// The client these flags ask for, with its preset.
+    pub async fn connect(&self) -> Result<LlmClient, String> {
+        let preset = match &self.preset {
+            Some(name) => Some(PromptPreset::builtin(name).ok_or_else(|| {
+                format!(
+                    "Unknown preset '{}'; expected one of {}",
+                    name,
+                    PresetLibrary::builtin().names().join(", ")
+                )
+            })?),
+            None => None,
+        };
+        let client = if self.mock {
+            LlmClient::new(None, Some(MOCK_MODEL.to_string())).with_backend(MockBackend::new())
+        } else {
+            let mut config = match &self.config {
+                Some(path) => BackendConfig::from_file(path)?,
+                None => BackendConfig::from_env()?,
+            };
+            if self.model.is_some() {
+                config.model = self.model.clone();
+            }
+            LlmClient::from_config(&config).await?
+        };
+        Ok(client.with_preset(preset))
+    }
+}
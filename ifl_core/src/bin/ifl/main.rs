@@ -0,0 +1,52 @@
+//! The `ifl` command line: one subcommand per way of feeding input to the
+//! analyzer, each in its own module.
+
+mod analyze;
+mod chat;
+mod editor;
+mod format;
+mod genset;
+mod llm;
+mod record;
+mod replay;
+mod serve;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(name = "ifl", author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Analyze text as if a user had typed or pasted it
+    Analyze(analyze::AnalyzeArgs),
+    /// Analyze recorded events, rebuilding the text they produce
+    Replay(replay::ReplayArgs),
+    /// Record keystrokes in the terminal into a replayable event log
+    Record(record::RecordArgs),
+    /// Chat with a model from the terminal, with the live profile alongside
+    Chat(chat::ChatArgs),
+    /// Serve the analyzer to other programs over HTTP
+    Serve(serve::ServeArgs),
+    /// Generate simulated event logs labeled with the tags they should get
+    Genset(genset::GensetArgs),
+}
+
+fn main() {
+    let result = match Cli::parse().command {
+        Command::Analyze(args) => analyze::run(args),
+        Command::Replay(args) => replay::run(args),
+        Command::Record(args) => record::run(args),
+        Command::Chat(args) => chat::run(args),
+        Command::Serve(args) => serve::run(args),
+        Command::Genset(args) => genset::run(args),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
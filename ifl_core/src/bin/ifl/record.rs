@@ -0,0 +1,122 @@
+use crate::editor::{Action, Editor};
+use clap::Args;
+use crossterm::cursor::MoveTo;
+use crossterm::event::{self, DisableBracketedPaste, EnableBracketedPaste};
+use crossterm::style::{Print, Stylize};
+use crossterm::terminal::{
+    self, disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen,
+    LeaveAlternateScreen,
+};
+use crossterm::{execute, queue};
+use ifl_core::InputEvent;
+use std::io::{self, IsTerminal, Write};
+
+const HELP: &str =
+    "Recording · Enter submits · Alt+Enter new line · Shift+arrows select · Ctrl+X cut · Esc cancels";
+
+#[derive(Args, Debug)]
+pub struct RecordArgs {
+    /// File to write the events to, for `ifl replay`; printed if missing
+    #[arg(short, long)]
+    out: Option<String>,
+
+    /// One event per line instead of a JSON array; the default for a
+    /// `.jsonl` file
+    #[arg(long)]
+    jsonl: bool,
+}
+
+/// Raw mode and the alternate screen for as long as it lives, so the
+/// terminal is restored however recording or chatting ends.
+pub struct TerminalGuard;
+
+impl TerminalGuard {
+    pub fn enter() -> io::Result<Self> {
+        enable_raw_mode()?;
+        let guard = Self;
+        execute!(io::stderr(), EnterAlternateScreen, EnableBracketedPaste)?;
+        Ok(guard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stderr(), DisableBracketedPaste, LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+/// Let the user write one message in the terminal and save what they did
+/// as events, timestamped as it happened.
+pub fn run(args: RecordArgs) -> Result<(), String> {
+    if !io::stderr().is_terminal() {
+        return Err("`ifl record` needs a terminal to type in".to_string());
+    }
+    let events = {
+        let _guard = TerminalGuard::enter().map_err(|e| e.to_string())?;
+        capture().map_err(|e| e.to_string())?
+    };
+    let Some(events) = events else {
+        return Err("Recording cancelled; nothing was written".to_string());
+    };
+
+    let jsonl = args.jsonl
+        || args
+            .out
+            .as_deref()
+            .is_some_and(|out| out.ends_with(".jsonl"));
+    let content = if jsonl {
+        let mut lines = String::new();
+        for event in &events {
+            lines.push_str(&serde_json::to_string(event).map_err(|e| e.to_string())?);
+            lines.push('\n');
+        }
+        lines
+    } else {
+        serde_json::to_string_pretty(&events).map_err(|e| e.to_string())? + "\n"
+    };
+    match &args.out {
+        Some(path) => {
+            std::fs::write(path, content).map_err(|e| format!("cannot write {}: {}", path, e))?;
+            eprintln!("Recorded {} events to {}", events.len(), path);
+        }
+        None => print!("{}", content),
+    }
+    if events
+        .iter()
+        .any(|event| matches!(event, InputEvent::Paste { .. }))
+    {
+        eprintln!(
+            "Pasted text is not stored in the events; replay with --text to analyze it exactly"
+        );
+    }
+    Ok(())
+}
+
+/// The events of the message, or `None` if the user cancelled.
+fn capture() -> io::Result<Option<Vec<InputEvent>>> {
+    let mut editor = Editor::new();
+    let mut out = io::stderr();
+    loop {
+        draw(&mut out, &editor)?;
+        match editor.handle(&event::read()?) {
+            Action::Continue => {}
+            Action::Submit => return Ok(Some(editor.take_events())),
+            Action::Cancel => return Ok(None),
+        }
+    }
+}
+
+fn draw(out: &mut impl Write, editor: &Editor) -> io::Result<()> {
+    let (width, height) = terminal::size()?;
+    let (rows, row, column) = editor.layout(width as usize);
+    // Keep the cursor's row on screen below the help line
+    let visible = (height as usize).saturating_sub(2).max(1);
+    let first = (row + 1).saturating_sub(visible);
+    queue!(out, Clear(ClearType::All), MoveTo(0, 0), Print(HELP.dim()))?;
+    for (i, text) in rows.iter().skip(first).take(visible).enumerate() {
+        queue!(out, MoveTo(0, (i + 2) as u16), Print(text))?;
+    }
+    queue!(out, MoveTo(column as u16, (row - first + 2) as u16))?;
+    out.flush()
+}
@@ -0,0 +1,91 @@
+use crate::format::{profile_row, render_one, Format, Row};
+use clap::Args;
+use ifl_core::event::{reconstruct_text, ReconstructedText};
+use ifl_core::profile::SessionSnapshot;
+use ifl_core::{IflCore, InputEvent, InputProfile};
+use serde::Serialize;
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// Events to replay: a JSON array of events, JSON lines from
+    /// `ifl record`, or a snapshot from `IflCore::export_snapshot`
+    file: String,
+
+    /// The text that was sent, in place of the one rebuilt from the events
+    #[arg(short, long)]
+    text: Option<String>,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Json)]
+    format: Format,
+}
+
+/// What `replay` prints: the text analyzed, how it was rebuilt from the
+/// events, and the profile.
+#[derive(Serialize)]
+pub struct Replay {
+    pub text: String,
+    pub reconstruction: ReconstructedText,
+    pub profile: InputProfile,
+}
+
+pub fn run(args: ReplayArgs) -> Result<(), String> {
+    let json = std::fs::read_to_string(&args.file)
+        .map_err(|e| format!("cannot read {}: {}", args.file, e))?;
+    let events = read_events(&json)
+        .map_err(|e| format!("{} holds neither events nor a snapshot: {}", args.file, e))?;
+
+    let explicit_text = args.text.is_some();
+    let output = replay(events, args.text)?;
+    let reconstructed = &output.reconstruction;
+    if !explicit_text && !reconstructed.is_exact() {
+        eprintln!(
+            "Warning: the events do not fully describe the text ({} characters unknown{}); pass --text for an exact profile",
+            reconstructed.unknown_chars,
+            if reconstructed.approximate {
+                ", undo/redo or suggestions not replayed"
+            } else {
+                ""
+            }
+        );
+    }
+    let mut row: Row = vec![("exact_text", reconstructed.is_exact().into())];
+    row.extend(profile_row(&output.profile, &output.text));
+    print!("{}", render_one(args.format, &output, &row)?);
+    Ok(())
+}
+
+/// Analyze `events` as one message in a session of its own, with `text`
+/// as sent or else the text the events rebuild.
+pub fn replay(events: Vec<InputEvent>, text: Option<String>) -> Result<Replay, String> {
+    let reconstruction = reconstruct_text(&events);
+    let text = text.unwrap_or_else(|| reconstruction.text.clone());
+    let core = IflCore::new();
+    let id = core.start_message()?;
+    for event in events {
+        core.push_event(&id, event)?;
+    }
+    let profile =
+        serde_json::from_str(&core.finalize_message(&id, &text)?).map_err(|e| e.to_string())?;
+    Ok(Replay {
+        text,
+        reconstruction,
+        profile,
+    })
+}
+
+/// Events from a JSON array, a snapshot's `events`, or JSON lines of one
+/// event each (as `ifl record` writes to a `.jsonl` file).
+pub fn read_events(json: &str) -> Result<Vec<InputEvent>, String> {
+    if let Ok(events) = serde_json::from_str::<Vec<InputEvent>>(json) {
+        return Ok(events);
+    }
+    if let Ok(snapshot) = serde_json::from_str::<SessionSnapshot>(json) {
+        return Ok(snapshot.events);
+    }
+    json.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e)))
+        .collect()
+}
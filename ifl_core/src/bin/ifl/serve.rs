@@ -0,0 +1,61 @@
+//! `ifl serve`: the API of `ifl_core::server` on a local port, for an app
+//! that runs it next to itself as a sidecar.
+
+use crate::llm::LlmArgs;
+use clap::Args;
+use ifl_core::rules::RuleEngine;
+use ifl_core::server::{router, Server};
+use ifl_core::IflCore;
+use std::sync::Arc;
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    bind: String,
+
+    /// Messages open at once; starting another is refused until one is
+    /// finalized or discarded
+    #[arg(long, default_value_t = 1000)]
+    max_sessions: usize,
+
+    /// Rule set to analyze with, e.g. a tuned copy of `config/rules.toml`
+    #[arg(short, long)]
+    rules: Option<String>,
+
+    #[command(flatten)]
+    llm: LlmArgs,
+}
+
+/// Serve the analyzer until interrupted.
+pub fn run(args: ServeArgs) -> Result<(), String> {
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    runtime.block_on(async {
+        let mut core = IflCore::new();
+        if let Some(path) = &args.rules {
+            let rules = RuleEngine::from_config(path).map_err(|e| format!("{}: {}", path, e))?;
+            core = core.with_rule_engine(rules);
+        }
+        let client = args.llm.connect().await?;
+        // Analysis works without a model, so a missing one only warns
+        if let Err(e) = client.health().await {
+            eprintln!(
+                "Warning: LLM unavailable ({}); /generate will fail until it is up",
+                e
+            );
+        }
+        let model = client.model().to_string();
+        let server = Arc::new(Server::new(core, client, args.max_sessions));
+
+        let listener = tokio::net::TcpListener::bind(&args.bind)
+            .await
+            .map_err(|e| format!("cannot listen on {}: {}", args.bind, e))?;
+        eprintln!("Serving on http://{} with {}", args.bind, model);
+        axum::serve(listener, router(server))
+            .with_graceful_shutdown(async {
+                let _ = tokio::signal::ctrl_c().await;
+            })
+            .await
+            .map_err(|e| e.to_string())
+    })
+}
@@ -0,0 +1,185 @@
+use crate::backend::{ChatRequest, Completion};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Answers to earlier requests, so sending the same input again — as when
+/// re-running a transcript after changing a rule — returns at once instead
+/// of waiting on the model. Entries live in memory and, with `with_dir`,
+/// also as one JSON file each, so they survive a restart. Memory holds the
+/// `with_capacity` most recently used; the rest stay on disk only.
+///
+/// A request matches when the model, the system prompt and everything
+/// after it (the user text, earlier turns, sampling and tools) are the
+/// same.
+#[derive(Debug)]
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    dir: Option<PathBuf>,
+}
+
+/// Entries in memory until `with_capacity` is passed.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// The entries in memory, each with the tick of its last use, and the keys
+/// by that tick, so the least recently used is the first.
+#[derive(Debug, Default)]
+struct Entries {
+    completions: HashMap<String, (Completion, u64)>,
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Entries {
+    fn get(&mut self, key: &str) -> Option<Completion> {
+        self.tick += 1;
+        let (completion, used) = self.completions.get_mut(key)?;
+        self.recency.remove(used);
+        *used = self.tick;
+        self.recency.insert(self.tick, key.to_string());
+        Some(completion.clone())
+    }
+
+    /// Add or replace an entry, then drop the least recently used past
+    /// `capacity`.
+    fn insert(&mut self, key: String, completion: Completion, capacity: usize) {
+        self.tick += 1;
+        if let Some((_, used)) = self
+            .completions
+            .insert(key.clone(), (completion, self.tick))
+        {
+            self.recency.remove(&used);
+        }
+        self.recency.insert(self.tick, key);
+        while self.completions.len() > capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.completions.remove(&oldest);
+        }
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+            capacity: DEFAULT_CAPACITY,
+            dir: None,
+        }
+    }
+}
+
+impl ResponseCache {
+    /// A cache kept in memory only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep at most this many entries in memory (at least one), dropping
+    /// the least recently used. With `with_dir` they are still on disk.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Also keep entries in `dir`, creating it if needed; entries already
+    /// there are used.
+    pub fn with_dir(mut self, dir: &str) -> Result<Self, String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Cannot create cache directory {}: {}", dir, e))?;
+        self.dir = Some(PathBuf::from(dir));
+        Ok(self)
+    }
+
+    /// `<model>-<system prompt>-<rest>`, each part a hex hash.
+    pub fn key(request: &ChatRequest) -> String {
+        let (system, rest) = match request.messages.split_first() {
+            Some((first, rest)) if first.role == "system" => (first.content.as_str(), rest),
+            _ => ("", request.messages.as_slice()),
+        };
+        let rest = json!({
+            "messages": rest,
+            "sampling": request.sampling,
+            "options": request.options,
+            "tools": request.tools,
+        });
+        format!(
+            "{:016x}-{:016x}-{:016x}",
+            fnv1a(&request.model),
+            fnv1a(system),
+            fnv1a(&rest.to_string())
+        )
+    }
+
+    /// The cached answer to `request`, from memory or else from disk.
+    pub fn get(&self, request: &ChatRequest) -> Option<Completion> {
+        let key = Self::key(request);
+        if let Some(hit) = self.entries.lock().ok()?.get(&key) {
+            return Some(hit);
+        }
+        let path = self.dir.as_ref()?.join(format!("{}.json", key));
+        let hit: Completion = serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+        self.entries
+            .lock()
+            .ok()?
+            .insert(key, hit.clone(), self.capacity);
+        Some(hit)
+    }
+
+    /// Remember `completion` as the answer to `request`. A failed write
+    /// only costs the disk copy; the cache never fails a request.
+    pub fn insert(&self, request: &ChatRequest, completion: &Completion) {
+        let key = Self::key(request);
+        if let Some(dir) = &self.dir {
+            if let Ok(json) = serde_json::to_string(completion) {
+                let _ = std::fs::write(dir.join(format!("{}.json", key)), json);
+            }
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(key, completion.clone(), self.capacity);
+        }
+    }
+
+    /// Entries in memory; those only on disk are loaded on first use.
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .map_or(0, |entries| entries.completions.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget every entry, on disk as well.
+    pub fn clear(&self) -> Result<(), String> {
+        *self
+            .entries
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())? = Entries::default();
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let files = std::fs::read_dir(dir).map_err(|e| e.to_string())?;
+        for file in files.flatten() {
+            let path = file.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                std::fs::remove_file(path).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// FNV-1a over the exact bytes; unlike `event::content_hash`, whitespace
+/// counts, since it can change the answer.
+fn fnv1a(text: &str) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    text.bytes().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
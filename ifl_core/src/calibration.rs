@@ -0,0 +1,207 @@
+use crate::profile::{TagConfidence, TagDimension};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Feedback needed before the first fit.
+const MIN_FEEDBACK_SAMPLES: usize = 20;
+/// Refit after this many new feedback entries.
+const RECALIBRATE_EVERY: usize = 10;
+/// Oldest outcomes are dropped beyond this.
+const MAX_FEEDBACK_SAMPLES: usize = 1000;
+/// Finalized messages that can still receive feedback.
+const MAX_PENDING: usize = 64;
+/// Regularization of the Platt fit toward the identity.
+const RIDGE: f64 = 0.01;
+
+const DIMENSIONS: [TagDimension; 4] = [
+    TagDimension::Mode,
+    TagDimension::Tone,
+    TagDimension::Depth,
+    TagDimension::UserState,
+];
+
+/// Platt scaling in logit space: `sigmoid(a * logit(p) + b)`.
+/// The identity (`a = 1, b = 0`) leaves confidences unchanged.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct PlattScaler {
+    pub a: f32,
+    pub b: f32,
+}
+
+impl Default for PlattScaler {
+    fn default() -> Self {
+        Self { a: 1.0, b: 0.0 }
+    }
+}
+
+impl PlattScaler {
+    pub fn apply(&self, p: f32) -> f32 {
+        sigmoid(self.a as f64 * logit(p) + self.b as f64) as f32
+    }
+
+    /// Fit to (raw confidence, helpful) pairs with Newton's method, using
+    /// Platt's smoothed targets so a handful of outcomes can't saturate it.
+    /// A small pull toward the identity keeps the fit defined when every
+    /// sample has the same raw confidence.
+    fn fit(samples: &[(f32, bool)]) -> Self {
+        let positives = samples.iter().filter(|(_, y)| *y).count() as f64;
+        let negatives = samples.len() as f64 - positives;
+        let hi = (positives + 1.0) / (positives + 2.0);
+        let lo = 1.0 / (negatives + 2.0);
+        let points: Vec<(f64, f64)> = samples
+            .iter()
+            .map(|&(p, y)| (logit(p), if y { hi } else { lo }))
+            .collect();
+
+        let (mut a, mut b) = (1.0f64, 0.0f64);
+        for _ in 0..50 {
+            let (mut ga, mut gb) = (RIDGE * (a - 1.0), RIDGE * b);
+            let (mut haa, mut hab, mut hbb) = (RIDGE, 0.0, RIDGE);
+            for &(x, t) in &points {
+                let q = sigmoid(a * x + b);
+                let w = q * (1.0 - q);
+                ga += (q - t) * x;
+                gb += q - t;
+                haa += w * x * x;
+                hab += w * x;
+                hbb += w;
+            }
+            let det = haa * hbb - hab * hab;
+            let da = (hbb * ga - hab * gb) / det;
+            let db = (haa * gb - hab * ga) / det;
+
+            // Backtrack: saturated starting points make full Newton steps overshoot
+            let current = Self::loss(a, b, &points);
+            let mut step = 1.0;
+            while step > 1e-8 && Self::loss(a - step * da, b - step * db, &points) > current {
+                step /= 2.0;
+            }
+            a -= step * da;
+            b -= step * db;
+            if (step * da).abs() < 1e-7 && (step * db).abs() < 1e-7 {
+                break;
+            }
+        }
+        Self {
+            a: a as f32,
+            b: b as f32,
+        }
+    }
+
+    /// Regularized cross-entropy of `sigmoid(a * x + b)` against the targets.
+    fn loss(a: f64, b: f64, points: &[(f64, f64)]) -> f64 {
+        let penalty = 0.5 * RIDGE * ((a - 1.0).powi(2) + b * b);
+        points.iter().fold(penalty, |sum, &(x, t)| {
+            let z = a * x + b;
+            // ln(1 + e^z) - t·z without overflow
+            sum + z.max(0.0) + (-z.abs()).exp().ln_1p() - t * z
+        })
+    }
+}
+
+/// One scaler per tag dimension.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct CalibrationScalers {
+    pub mode: PlattScaler,
+    pub tone: PlattScaler,
+    pub depth: PlattScaler,
+    pub user_state: PlattScaler,
+}
+
+impl CalibrationScalers {
+    fn get_mut(&mut self, dimension: TagDimension) -> &mut PlattScaler {
+        match dimension {
+            TagDimension::Mode => &mut self.mode,
+            TagDimension::Tone => &mut self.tone,
+            TagDimension::Depth => &mut self.depth,
+            TagDimension::UserState => &mut self.user_state,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedbackSample {
+    /// Uncalibrated confidence the rules reported.
+    pub confidence: TagConfidence,
+    pub helpful: bool,
+}
+
+/// Feedback log and the confidence calibration fitted from it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Calibrator {
+    pub samples: VecDeque<FeedbackSample>,
+    pub scalers: CalibrationScalers,
+    #[serde(default)]
+    since_fit: usize,
+    /// Raw confidences of recent messages awaiting feedback.
+    #[serde(skip)]
+    pending: VecDeque<(String, TagConfidence)>,
+}
+
+impl Calibrator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember a finalized message's raw confidence so feedback can refer to it.
+    pub fn track(&mut self, message_id: &str, confidence: TagConfidence) {
+        self.pending.push_back((message_id.to_string(), confidence));
+        while self.pending.len() > MAX_PENDING {
+            self.pending.pop_front();
+        }
+    }
+
+    pub fn record(&mut self, message_id: &str, helpful: bool) -> Result<(), String> {
+        let (_, confidence) = self
+            .pending
+            .iter()
+            .position(|(id, _)| id == message_id)
+            .and_then(|index| self.pending.remove(index))
+            .ok_or_else(|| format!("No feedback pending for message {}", message_id))?;
+        self.samples.push_back(FeedbackSample {
+            confidence,
+            helpful,
+        });
+        while self.samples.len() > MAX_FEEDBACK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.since_fit += 1;
+        if self.samples.len() >= MIN_FEEDBACK_SAMPLES && self.since_fit >= RECALIBRATE_EVERY {
+            self.recalibrate();
+        }
+        Ok(())
+    }
+
+    pub fn recalibrate(&mut self) {
+        for dimension in DIMENSIONS {
+            let points: Vec<(f32, bool)> = self
+                .samples
+                .iter()
+                .map(|s| (s.confidence.get(dimension), s.helpful))
+                .collect();
+            *self.scalers.get_mut(dimension) = PlattScaler::fit(&points);
+        }
+        self.since_fit = 0;
+    }
+
+    pub fn calibrate(&self, raw: TagConfidence) -> TagConfidence {
+        if self.scalers == CalibrationScalers::default() {
+            return raw;
+        }
+        TagConfidence {
+            mode: self.scalers.mode.apply(raw.mode),
+            tone: self.scalers.tone.apply(raw.tone),
+            depth: self.scalers.depth.apply(raw.depth),
+            user_state: self.scalers.user_state.apply(raw.user_state),
+        }
+    }
+}
+
+fn logit(p: f32) -> f64 {
+    let p = (p as f64).clamp(1e-4, 1.0 - 1e-4);
+    (p / (1.0 - p)).ln()
+}
+
+fn sigmoid(z: f64) -> f64 {
+    1.0 / (1.0 + (-z).exp())
+}
@@ -0,0 +1,166 @@
+use crate::backend::{ChatMessage, ChatRequest, GenerationOptions, SamplingParams};
+use crate::llm_client::{LlmClient, LlmError};
+use crate::profile::{
+    AnswerMode, AnswerTags, ClassifierVerdict, InputProfile, PragmaticIntent, TagDisagreement,
+    ToneHint,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Rule confidence below which the classifier's mode or tone replaces the
+/// rules'; the same threshold marks tags as tentative in the prompt.
+const ADOPT_BELOW: f32 = 0.6;
+/// Added to a dimension's confidence when the classifier agrees, and to the
+/// top mode score when its mode is adopted.
+const AGREEMENT_BONUS: f32 = 0.1;
+/// Room for the JSON verdict.
+const VERDICT_TOKENS: u32 = 96;
+
+const CLASSIFIER_PROMPT: &str = "\
+Classify the user's message. Reply with one JSON object and nothing else, like \
+{\"mode\": \"explore\", \"tone\": \"neutral\", \"intents\": [\"information_seeking\"]}.
+- mode: what the user mainly wants done, one of: summarize, structure, refine, explore, \
+complete, clarify_question, debug, translate, review_code, respond_to_quote, \
+extract_action_items, brainstorm, critique.
+- tone: the tone the answer should take, one of: direct, gentle, formal, neutral, calm.
+- intents: any that apply of: solution_focused, concept_exploration, debugging, \
+expertise_seeking, ambiguity_resolution, information_seeking, task_delegation, venting, \
+brainstorming, confirmation.";
+
+/// A small, fast model that reads the text and classifies mode, tone and
+/// intent, to back up the rules where wording matters more than typing
+/// behavior. Its verdict is merged into the profile before the answering
+/// model sees it; user state, depth and scope stay with the rules, which
+/// see how the message was typed.
+pub struct Classifier {
+    client: LlmClient,
+}
+
+impl Classifier {
+    /// Classify with `client`'s backend and model, e.g. a 0.5B–1B model on
+    /// the local Ollama server.
+    pub fn new(client: LlmClient) -> Self {
+        Self { client }
+    }
+
+    /// The classifier's reading of `text`, without disagreements.
+    pub async fn classify(&self, text: &str) -> Result<ClassifierVerdict, LlmError> {
+        let request = ChatRequest {
+            model: self.client.model().to_string(),
+            messages: vec![
+                ChatMessage::system(CLASSIFIER_PROMPT),
+                ChatMessage::user(&self.client.fit_to_context(CLASSIFIER_PROMPT, text)),
+            ],
+            sampling: SamplingParams {
+                temperature: Some(0.0),
+                top_p: None,
+                max_tokens: Some(VERDICT_TOKENS),
+            },
+            options: GenerationOptions::default(),
+            tools: Vec::new(),
+        };
+        let completion = self.client.complete(&request).await?;
+        let mut verdict = parse_verdict(&completion.content)?;
+        verdict.model = completion.model.unwrap_or(request.model);
+        Ok(verdict)
+    }
+
+    /// Classify `text` and merge the verdict into `profile.tags` (see
+    /// `merge`). On error the profile keeps the rules' tags.
+    pub async fn assist(&self, text: &str, profile: &mut InputProfile) -> Result<(), LlmError> {
+        let verdict = self.classify(text).await?;
+        merge(&mut profile.tags, verdict);
+        Ok(())
+    }
+}
+
+/// Merge a classifier's verdict into tags from the rules:
+///
+/// - agreeing on the primary mode or the tone raises that confidence;
+/// - disagreeing, the classifier's choice wins only where the rules'
+///   confidence is below 0.6, and is recorded either way;
+/// - intents only the classifier found are added.
+///
+/// The verdict, with its disagreements, is kept in `tags.classifier`.
+pub fn merge(tags: &mut AnswerTags, mut verdict: ClassifierVerdict) {
+    verdict.disagreements.clear();
+
+    if let Some(mode) = &verdict.answer_mode {
+        if tags.answer_mode.first() == Some(mode) {
+            tags.confidence.mode = (tags.confidence.mode + AGREEMENT_BONUS).min(1.0);
+        } else {
+            let adopted = tags.confidence.mode < ADOPT_BELOW;
+            verdict.disagreements.push(TagDisagreement::Mode {
+                rules: tags.answer_mode.first().cloned(),
+                classifier: mode.clone(),
+                adopted,
+            });
+            if adopted {
+                // Primary by score, so the order stays consistent with it
+                let top = tags.mode_scores.values().copied().fold(0.0, f32::max);
+                tags.mode_scores.insert(mode.clone(), top + AGREEMENT_BONUS);
+                tags.answer_mode.retain(|m| m != mode);
+                tags.answer_mode.insert(0, mode.clone());
+            }
+        }
+    }
+
+    if let Some(tone) = &verdict.tone_hint {
+        if tags.tone_hint == *tone {
+            tags.confidence.tone = (tags.confidence.tone + AGREEMENT_BONUS).min(1.0);
+        } else {
+            let adopted = tags.confidence.tone < ADOPT_BELOW;
+            verdict.disagreements.push(TagDisagreement::Tone {
+                rules: tags.tone_hint.clone(),
+                classifier: tone.clone(),
+                adopted,
+            });
+            if adopted {
+                tags.tone_hint = tone.clone();
+            }
+        }
+    }
+
+    for intent in &verdict.pragmatic_intent {
+        if !tags.pragmatic_intent.contains(intent) {
+            verdict.disagreements.push(TagDisagreement::Intent {
+                classifier: intent.clone(),
+            });
+            tags.pragmatic_intent.push(intent.clone());
+        }
+    }
+    tags.pragmatic_intent.sort();
+
+    tags.classifier = Some(verdict);
+}
+
+/// Read the JSON object in a classifier reply. Small models wrap it in
+/// prose or a code fence, so the outermost braces are taken; labels
+/// outside the lists in the prompt are dropped.
+fn parse_verdict(reply: &str) -> Result<ClassifierVerdict, LlmError> {
+    let json = reply
+        .find('{')
+        .zip(reply.rfind('}'))
+        .filter(|(start, end)| start < end)
+        .and_then(|(start, end)| serde_json::from_str::<Value>(&reply[start..=end]).ok())
+        .ok_or_else(|| LlmError::BadResponse(format!("No classification in: {}", reply)))?;
+
+    fn label<T: DeserializeOwned>(value: &Value) -> Option<T> {
+        let name = value.as_str()?.trim().to_lowercase();
+        serde_json::from_value(Value::String(name)).ok()
+    }
+    let mut intents: Vec<PragmaticIntent> = json["intents"]
+        .as_array()
+        .map(|values| values.iter().filter_map(label).collect())
+        .unwrap_or_default();
+    intents.sort();
+    intents.dedup();
+
+    Ok(ClassifierVerdict {
+        model: String::new(),
+        answer_mode: label::<AnswerMode>(&json["mode"]),
+        tone_hint: label::<ToneHint>(&json["tone"]),
+        pragmatic_intent: intents,
+        disagreements: Vec::new(),
+    })
+}
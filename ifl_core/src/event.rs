@@ -15,11 +15,31 @@ pub enum InputEvent {
     Paste {
         length: usize,
         ts: u64,
+        /// Hash of the pasted text (see `content_hash`), when the frontend has it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_hash: Option<u64>,
     },
     Cut {
         length: usize,
         ts: u64,
     },
+    Copy {
+        length: usize,
+        ts: u64,
+    },
+    FileDrop {
+        name: String,
+        bytes: usize,
+        ts: u64,
+    },
+    /// An image pasted into the input. Only its type and size are recorded;
+    /// the image itself goes to the model with the message (see
+    /// `backend::ImageData`).
+    ImagePaste {
+        media_type: String,
+        bytes: usize,
+        ts: u64,
+    },
     CursorMove {
         position: usize,
         ts: u64,
@@ -48,6 +68,51 @@ pub enum InputEvent {
         text: String,
         ts: u64,
     },
+    /// A word-prediction or autocomplete suggestion accepted as a whole, as
+    /// on-screen and predictive keyboards do (`insertReplacementText`).
+    SuggestionAccept {
+        length: usize,
+        ts: u64,
+    },
+    /// Text inserted by speech recognition (`insertFromDictation`).
+    Dictation {
+        length: usize,
+        ts: u64,
+    },
+}
+
+impl InputEvent {
+    /// Paste event carrying the hash of its content, so repeated pastes can be
+    /// recognized without keeping the pasted text.
+    pub fn paste(text: &str, ts: u64) -> Self {
+        InputEvent::Paste {
+            length: text.chars().count(),
+            ts,
+            content_hash: Some(content_hash(text)),
+        }
+    }
+
+    pub fn ts(&self) -> u64 {
+        match self {
+            InputEvent::KeyInsert { ts, .. } => *ts,
+            InputEvent::KeyDelete { ts, .. } => *ts,
+            InputEvent::Paste { ts, .. } => *ts,
+            InputEvent::Cut { ts, .. } => *ts,
+            InputEvent::Copy { ts, .. } => *ts,
+            InputEvent::FileDrop { ts, .. } => *ts,
+            InputEvent::ImagePaste { ts, .. } => *ts,
+            InputEvent::CursorMove { ts, .. } => *ts,
+            InputEvent::SelectionChange { ts, .. } => *ts,
+            InputEvent::CompositionStart { ts } => *ts,
+            InputEvent::CompositionEnd { ts } => *ts,
+            InputEvent::Submit { ts } => *ts,
+            InputEvent::Undo { ts } => *ts,
+            InputEvent::Redo { ts } => *ts,
+            InputEvent::GhostText { ts, .. } => *ts,
+            InputEvent::SuggestionAccept { ts, .. } => *ts,
+            InputEvent::Dictation { ts, .. } => *ts,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -55,3 +120,146 @@ pub enum DeleteKind {
     Backspace,
     Delete,
 }
+
+/// Media type of an image file by its extension, for the formats vision
+/// models read; `None` for anything else.
+pub fn image_media_type(name: &str) -> Option<&'static str> {
+    let extension = name.rsplit_once('.')?.1.to_lowercase();
+    match extension.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Stable FNV-1a hash of whitespace-normalized text, so the same block pasted
+/// with different trailing newlines or indentation still matches.
+pub fn content_hash(text: &str) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut hash = FNV_OFFSET;
+    for (i, word) in text.split_whitespace().enumerate() {
+        if i > 0 {
+            hash = (hash ^ u64::from(b' ')).wrapping_mul(FNV_PRIME);
+        }
+        for byte in word.bytes() {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+        }
+    }
+    hash
+}
+
+/// Stands in for characters whose content the events do not carry.
+pub const UNKNOWN_CHAR: char = '\u{FFFD}';
+
+/// The text a message's events produce (see `reconstruct_text`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReconstructedText {
+    pub text: String,
+    /// Pasted, dictated or suggested characters, which the events count but
+    /// do not carry; each is `UNKNOWN_CHAR` in `text`.
+    pub unknown_chars: usize,
+    /// Undo, redo or an accepted suggestion changed the text in a way the
+    /// events do not describe, so `text` may differ from what was sent.
+    pub approximate: bool,
+}
+
+impl ReconstructedText {
+    /// Whether `text` is exactly what the user wrote.
+    pub fn is_exact(&self) -> bool {
+        self.unknown_chars == 0 && !self.approximate
+    }
+}
+
+/// Replay `events` against an empty input: typed characters go in at the
+/// cursor, replacing any selection; backspace and delete remove characters
+/// around it; cursor moves and selections position the next edit (in
+/// characters, as the frontend reports them). Without cursor events, edits
+/// happen at the end.
+pub fn reconstruct_text(events: &[InputEvent]) -> ReconstructedText {
+    let mut text: Vec<char> = Vec::new();
+    let mut cursor = 0;
+    // Non-empty selection, start < end
+    let mut selection: Option<(usize, usize)> = None;
+    let mut approximate = false;
+
+    // Removes the selection, leaving the cursor where it was
+    let take_selection =
+        |text: &mut Vec<char>, cursor: &mut usize, selection: &mut Option<(usize, usize)>| {
+            match selection.take() {
+                Some((start, end)) => {
+                    text.drain(start..end);
+                    *cursor = start;
+                    true
+                }
+                None => false,
+            }
+        };
+
+    for event in events {
+        match event {
+            InputEvent::KeyInsert { ch, .. } => {
+                take_selection(&mut text, &mut cursor, &mut selection);
+                text.insert(cursor, *ch);
+                cursor += 1;
+            }
+            InputEvent::KeyDelete { kind, count, .. } => {
+                if take_selection(&mut text, &mut cursor, &mut selection) {
+                    continue;
+                }
+                let count = *count as usize;
+                match kind {
+                    DeleteKind::Backspace => {
+                        let start = cursor.saturating_sub(count);
+                        text.drain(start..cursor);
+                        cursor = start;
+                    }
+                    DeleteKind::Delete => {
+                        let end = (cursor + count).min(text.len());
+                        text.drain(cursor..end);
+                    }
+                }
+            }
+            InputEvent::Paste { length, .. }
+            | InputEvent::Dictation { length, .. }
+            | InputEvent::SuggestionAccept { length, .. } => {
+                if matches!(event, InputEvent::SuggestionAccept { .. }) {
+                    // It may replace the word being typed, which is not reported
+                    approximate = true;
+                }
+                take_selection(&mut text, &mut cursor, &mut selection);
+                text.splice(cursor..cursor, std::iter::repeat_n(UNKNOWN_CHAR, *length));
+                cursor += length;
+            }
+            InputEvent::Cut { length, .. } => {
+                let cut = take_selection(&mut text, &mut cursor, &mut selection);
+                if !cut {
+                    // No selection reported: assume the text before the cursor
+                    let start = cursor.saturating_sub(*length);
+                    text.drain(start..cursor);
+                    cursor = start;
+                }
+            }
+            InputEvent::CursorMove { position, .. } => {
+                cursor = (*position).min(text.len());
+                selection = None;
+            }
+            InputEvent::SelectionChange { start, end, .. } => {
+                let (start, end) = ((*start).min(*end), (*start).max(*end));
+                let (start, end) = (start.min(text.len()), end.min(text.len()));
+                selection = (start < end).then_some((start, end));
+                cursor = end;
+            }
+            InputEvent::Undo { .. } | InputEvent::Redo { .. } => approximate = true,
+            _ => {}
+        }
+    }
+
+    ReconstructedText {
+        unknown_chars: text.iter().filter(|&&c| c == UNKNOWN_CHAR).count(),
+        text: text.into_iter().collect(),
+        approximate,
+    }
+}
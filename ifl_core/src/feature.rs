@@ -1,10 +1,99 @@
-use crate::event::{DeleteKind, InputEvent};
+use crate::event::{image_media_type, DeleteKind, InputEvent};
+use crate::keywords::KeywordDictionary;
 use crate::profile::{
-    EditingFeatures, FirstAction, SourceFeatures, SourceType, StructureFeatures, TimingFeatures,
+    DataFormat, DraftingPhase, EditingFeatures, FirstAction, InputModality, InstructionExcerpt,
+    InstructionPosition, LanguageShare, PhaseSegment, PolitenessLevel, QuestionType, RequestKind,
+    SourceFeatures, SourceType, StructureFeatures, TimingFeatures, TranslationRequest,
 };
+use crate::tokens::TokenizerFamily;
+use std::collections::{HashMap, VecDeque};
+
+/// Backspace runs up to this many characters count as typo fixes, not rewrites.
+const TYPO_MAX_CHARS: usize = 3;
+/// Retyping must resume within this gap for the run to count as a typo fix.
+const TYPO_RETYPE_GAP_MS: u64 = 1000;
+
+/// Upper bound on ghost-text fragments carried into the profile.
+const MAX_GHOST_FRAGMENTS: usize = 5;
+/// Candidate fragments retained while the session is in progress.
+const MAX_GHOST_CANDIDATES: usize = 32;
+/// Minimum shared characters for two ghost fragments to be merged at their edges.
+const MIN_GHOST_OVERLAP: usize = 3;
+
+/// Width of the windows used to classify drafting phases.
+const PHASE_WINDOW_MS: u64 = 20_000;
+/// Sessions shorter than this are not segmented into phases.
+const MIN_PHASED_SESSION_MS: u64 = 60_000;
+/// Windows changing fewer characters than this count as light polishing.
+const POLISH_MAX_CHARS: usize = 10;
+
+/// Ghost fragments shorter than this are too generic to detect as restored.
+const MIN_RESTORE_CHARS: usize = 8;
+/// Bigram similarity above which retyped text counts as restoring a deletion.
+const RESTORE_SIMILARITY: f32 = 0.8;
+/// Typed characters kept for matching against ghost text.
+const RECENT_TYPED_CAPACITY: usize = 256;
+/// Messages shorter than this are read whole; no instruction extraction.
+const MIN_INSTRUCTION_BODY_CHARS: usize = 500;
+/// Longest leading/trailing block still treated as a typed instruction.
+const MAX_INSTRUCTION_CHARS: usize = 300;
+/// A language counts towards code-switching with at least a sentence's worth
+/// of tokens, so a stray loanword or identifier does not.
+const CODE_SWITCH_MIN_TOKENS: usize = 8;
+/// Previews of texts larger than this are analyzed from a sample.
+pub const PREVIEW_SAMPLE_BYTES: usize = 64 * 1024;
+/// Characters fed to language identification per text or segment.
+const LANGUAGE_SAMPLE_CHARS: usize = 1000;
+/// Segments identified individually before languages are carried forward.
+const MAX_LANGUAGE_SEGMENTS: usize = 64;
+/// Number of topic terms kept per message.
+const MAX_TOPIC_KEYWORDS: usize = 5;
+/// Share of the text (or of its non-empty lines) a data format must cover.
+const DOMINANT_DATA_SHARE: f32 = 0.6;
+
+/// Keystroke gaps needed before judging their regularity.
+const MIN_RHYTHM_INTERVALS: usize = 10;
+/// Most recent keystroke gaps the rhythm is judged from, so a preview costs
+/// the same however long the message has been typed.
+const RHYTHM_WINDOW: usize = 256;
+/// Switch and scanning access: slow keystrokes at a near-constant pace.
+const SWITCH_ACCESS_MIN_INTERVAL_MS: u64 = 700;
+const SWITCH_ACCESS_MAX_CV: f32 = 0.25;
+/// Share of the user's own text that came from speech recognition.
+const DICTATION_MIN_SHARE: f32 = 0.5;
+/// Share of the user's own text that came from accepted suggestions.
+const ON_SCREEN_MIN_SUGGESTION_SHARE: f32 = 0.3;
+
+struct GhostFragment {
+    text: String,
+    ts: u64,
+    restored: bool,
+}
+
+/// Tunable thresholds for feature extraction.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractorConfig {
+    /// Gaps longer than this end a typing burst and count as a long pause.
+    pub burst_gap_ms: u64,
+}
+
+impl Default for ExtractorConfig {
+    fn default() -> Self {
+        Self { burst_gap_ms: 1500 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BurstState {
+    InBurst,
+    Paused,
+}
 
 pub struct FeatureExtractor {
+    config: ExtractorConfig,
+
     // State
+    burst_state: BurstState,
     start_time: Option<u64>,
     last_event_time: Option<u64>,
 
@@ -13,46 +102,92 @@ pub struct FeatureExtractor {
     paste_events: usize,
     total_pasted_chars: usize,
     total_typed_chars: usize, // For calculating paste ratio
+    suggested_chars: usize,
+    dictated_chars: usize,
+    copy_events: usize,
+    attachment_count: usize,
+    attachment_bytes: usize, // Kept out of the paste ratio
+    image_count: usize,
 
     // Timing stats
     typing_bursts: usize,
     long_pause_count: usize,
+    last_keystroke_time: Option<u64>,
+    keystroke_intervals: VecDeque<u64>,
 
     // Editing stats
     backspace_count: usize,
     backspace_burst_count: usize,
+    delete_forward_count: usize,
+    typo_corrections: usize,
+    typo_backspace_count: usize,
     undo_count: usize,
     redo_count: usize,
     selection_edit_count: usize,
 
     // Internal tracking
     in_backspace_burst: bool,
+    backspace_run_chars: usize,
     paste_timestamps: Vec<u64>, // To check beginning/end
+    paste_hashes: Vec<u64>,
     current_selection_len: usize,
+    phase_window_index: u64,
+    phase_window_inserted: usize,
+    phase_window_deleted: usize,
+    phases: Vec<PhaseSegment>,
     final_pause_ms: u64,
+    ghost_fragments: Vec<GhostFragment>,
+    recent_typed: Vec<char>,
+    second_guessing_count: usize,
     events: Vec<InputEvent>,
 }
 
 impl FeatureExtractor {
     pub fn new() -> Self {
+        Self::with_config(ExtractorConfig::default())
+    }
+
+    pub fn with_config(config: ExtractorConfig) -> Self {
         Self {
+            config,
+            burst_state: BurstState::Paused,
             start_time: None,
             last_event_time: None,
             first_action: None,
             paste_events: 0,
             total_pasted_chars: 0,
             total_typed_chars: 0,
+            suggested_chars: 0,
+            dictated_chars: 0,
+            copy_events: 0,
+            attachment_count: 0,
+            attachment_bytes: 0,
+            image_count: 0,
             typing_bursts: 0,
             long_pause_count: 0,
+            last_keystroke_time: None,
+            keystroke_intervals: VecDeque::with_capacity(RHYTHM_WINDOW),
             backspace_count: 0,
             backspace_burst_count: 0,
+            delete_forward_count: 0,
+            typo_corrections: 0,
+            typo_backspace_count: 0,
             undo_count: 0,
             redo_count: 0,
             selection_edit_count: 0,
             in_backspace_burst: false,
+            backspace_run_chars: 0,
             paste_timestamps: Vec::new(),
+            paste_hashes: Vec::new(),
             current_selection_len: 0,
+            phase_window_index: 0,
+            phase_window_inserted: 0,
+            phase_window_deleted: 0,
+            phases: Vec::new(),
             final_pause_ms: 0,
+            ghost_fragments: Vec::new(),
+            recent_typed: Vec::new(),
+            second_guessing_count: 0,
             events: Vec::new(),
         }
     }
@@ -63,52 +198,96 @@ impl FeatureExtractor {
 
     pub fn process_event(&mut self, event: &InputEvent) {
         self.events.push(event.clone());
-        let ts = match event {
-            InputEvent::KeyInsert { ts, .. } => *ts,
-            InputEvent::KeyDelete { ts, .. } => *ts,
-            InputEvent::Paste { ts, .. } => *ts,
-            InputEvent::Cut { ts, .. } => *ts,
-            InputEvent::CursorMove { ts, .. } => *ts,
-            InputEvent::SelectionChange { ts, .. } => *ts,
-            InputEvent::CompositionStart { ts } => *ts,
-            InputEvent::CompositionEnd { ts } => *ts,
-            InputEvent::Submit { ts } => *ts,
-            InputEvent::Undo { ts } => *ts,
-            InputEvent::Redo { ts } => *ts,
-            InputEvent::GhostText { ts, .. } => *ts,
-        };
+        let ts = event.ts();
 
         if self.start_time.is_none() {
             self.start_time = Some(ts);
         }
 
+        // Close the phase window once the stream moves past it
+        let window_index = ts.saturating_sub(self.start_time.unwrap_or(ts)) / PHASE_WINDOW_MS;
+        if window_index > self.phase_window_index {
+            if let Some(segment) = self.current_phase_segment() {
+                Self::push_phase(&mut self.phases, segment);
+            }
+            self.phase_window_index = window_index;
+            self.phase_window_inserted = 0;
+            self.phase_window_deleted = 0;
+        }
+        match event {
+            InputEvent::KeyInsert { .. } => self.phase_window_inserted += 1,
+            InputEvent::Paste { length, .. }
+            | InputEvent::SuggestionAccept { length, .. }
+            | InputEvent::Dictation { length, .. } => self.phase_window_inserted += *length,
+            InputEvent::KeyDelete { count, .. } => self.phase_window_deleted += *count as usize,
+            InputEvent::Cut { length, .. } => self.phase_window_deleted += *length,
+            _ => {}
+        }
+
         // First action detection
         if self.first_action.is_none() {
             match event {
-                InputEvent::Paste { .. } => self.first_action = Some(FirstAction::Paste),
-                InputEvent::KeyInsert { .. } => self.first_action = Some(FirstAction::Typed),
+                InputEvent::Paste { .. } | InputEvent::ImagePaste { .. } => {
+                    self.first_action = Some(FirstAction::Paste)
+                }
+                InputEvent::KeyInsert { .. }
+                | InputEvent::SuggestionAccept { .. }
+                | InputEvent::Dictation { .. } => self.first_action = Some(FirstAction::Typed),
                 _ => {} // Wait for first significant action
             }
         }
 
-        // Timing analysis
-        if let Some(last_ts) = self.last_event_time {
-            let diff = ts.saturating_sub(last_ts);
-            if diff > 1500 {
+        let gap_ms = self
+            .last_event_time
+            .map(|last_ts| ts.saturating_sub(last_ts));
+
+        // Timing analysis: a long gap pauses the burst, the next keystroke starts a new one
+        let long_gap = gap_ms.is_some_and(|gap| gap > self.config.burst_gap_ms);
+        match event {
+            InputEvent::Submit { .. } => {
+                // The trailing pause is reported separately, not as a mid-message pause
+                self.final_pause_ms = gap_ms.unwrap_or(0);
+            }
+            _ if long_gap => {
                 self.long_pause_count += 1;
-                // End of a burst
-                self.typing_bursts += 1;
+                self.burst_state = BurstState::Paused;
             }
-        } else {
-            // First event starts a burst
+            _ => {}
+        }
+        if matches!(
+            event,
+            InputEvent::KeyInsert { .. } | InputEvent::KeyDelete { .. }
+        ) && self.burst_state == BurstState::Paused
+        {
             self.typing_bursts += 1;
+            self.burst_state = BurstState::InBurst;
         }
         self.last_event_time = Some(ts);
 
+        // Keystroke rhythm, for recognizing assistive input
+        if matches!(event, InputEvent::KeyInsert { .. }) {
+            if let Some(last) = self.last_keystroke_time {
+                if self.keystroke_intervals.len() == RHYTHM_WINDOW {
+                    self.keystroke_intervals.pop_front();
+                }
+                self.keystroke_intervals.push_back(ts.saturating_sub(last));
+            }
+            self.last_keystroke_time = Some(ts);
+        }
+
         // Event specific logic
         match event {
-            InputEvent::KeyInsert { .. } => {
+            InputEvent::KeyInsert { ch, .. } => {
                 self.total_typed_chars += 1;
+                self.record_typed_char(*ch);
+                // Short backspace run followed by immediate retyping: a typo fix
+                if self.in_backspace_burst
+                    && self.backspace_run_chars <= TYPO_MAX_CHARS
+                    && gap_ms.is_some_and(|gap| gap <= TYPO_RETYPE_GAP_MS)
+                {
+                    self.typo_corrections += 1;
+                    self.typo_backspace_count += self.backspace_run_chars;
+                }
                 self.in_backspace_burst = false;
                 if self.current_selection_len > 0 {
                     self.selection_edit_count += 1;
@@ -118,13 +297,18 @@ impl FeatureExtractor {
             InputEvent::KeyDelete { kind, count, .. } => {
                 if matches!(kind, DeleteKind::Backspace) {
                     self.backspace_count += *count as usize;
+                    let kept = self.recent_typed.len().saturating_sub(*count as usize);
+                    self.recent_typed.truncate(kept);
                     if self.in_backspace_burst {
                         // Continue burst
+                        self.backspace_run_chars += *count as usize;
                     } else {
                         self.backspace_burst_count += 1;
                         self.in_backspace_burst = true;
+                        self.backspace_run_chars = *count as usize;
                     }
                 } else {
+                    self.delete_forward_count += *count as usize;
                     self.in_backspace_burst = false;
                 }
                 if self.current_selection_len > 0 {
@@ -132,8 +316,15 @@ impl FeatureExtractor {
                     self.current_selection_len = 0;
                 }
             }
-            InputEvent::Paste { length, .. } => {
+            InputEvent::Paste {
+                length,
+                content_hash,
+                ..
+            } => {
                 self.paste_events += 1;
+                if let Some(hash) = content_hash {
+                    self.paste_hashes.push(*hash);
+                }
                 self.total_pasted_chars += *length;
                 self.paste_timestamps.push(ts);
                 self.in_backspace_burst = false;
@@ -142,6 +333,42 @@ impl FeatureExtractor {
                     self.current_selection_len = 0;
                 }
             }
+            // The user's own words, just not keystroke by keystroke
+            InputEvent::SuggestionAccept { length, .. } => {
+                self.total_typed_chars += *length;
+                self.suggested_chars += *length;
+                self.in_backspace_burst = false;
+            }
+            InputEvent::Dictation { length, .. } => {
+                self.total_typed_chars += *length;
+                self.dictated_chars += *length;
+                self.in_backspace_burst = false;
+            }
+            InputEvent::FileDrop { name, bytes, .. } => {
+                self.attachment_count += 1;
+                self.attachment_bytes += *bytes;
+                if image_media_type(name).is_some() {
+                    self.image_count += 1;
+                }
+                self.in_backspace_burst = false;
+            }
+            InputEvent::ImagePaste { bytes, .. } => {
+                self.attachment_count += 1;
+                self.attachment_bytes += *bytes;
+                self.image_count += 1;
+                self.in_backspace_burst = false;
+            }
+            InputEvent::Copy { length, .. } => {
+                // Copying part of the draft out, e.g. to search for it elsewhere
+                if *length > 0 {
+                    self.copy_events += 1;
+                }
+                self.in_backspace_burst = false;
+            }
+            InputEvent::GhostText { text, .. } => {
+                self.record_ghost_fragment(text, ts);
+                self.in_backspace_burst = false;
+            }
             InputEvent::Undo { .. } => {
                 self.undo_count += 1;
                 self.in_backspace_burst = false;
@@ -154,18 +381,17 @@ impl FeatureExtractor {
                 self.current_selection_len = end.saturating_sub(*start);
                 self.in_backspace_burst = false;
             }
-            InputEvent::Submit { .. } => {
-                if let Some(last) = self.last_event_time {
-                    self.final_pause_ms = ts.saturating_sub(last);
-                }
-                self.in_backspace_burst = false;
-            }
             _ => {
                 self.in_backspace_burst = false;
             }
         }
     }
 
+    /// Content hashes of this message's pastes, when the frontend supplied them.
+    pub fn paste_hashes(&self) -> &[u64] {
+        &self.paste_hashes
+    }
+
     pub fn extract_source_features(&self, _total_duration: u64) -> SourceFeatures {
         let total_chars = self.total_typed_chars + self.total_pasted_chars;
         let paste_ratio = if total_chars > 0 {
@@ -186,8 +412,57 @@ impl FeatureExtractor {
             source_type,
             paste_ratio,
             paste_events: self.paste_events,
+            copy_events: self.copy_events,
+            copied_from_draft: self.copy_events > 0,
+            attachment_count: self.attachment_count,
+            attachment_bytes: self.attachment_bytes,
+            image_count: self.image_count,
             first_action: self.first_action.clone().unwrap_or(FirstAction::Other),
+            repeated_paste: false, // Needs conversation history; set by IflCore
+            input_modality: self.input_modality(),
+        }
+    }
+
+    /// Dictation and accepted suggestions are reported by the frontend;
+    /// switch access shows only in the keystroke rhythm.
+    fn input_modality(&self) -> InputModality {
+        let own_chars = self.total_typed_chars.max(1) as f32;
+        if self.dictated_chars as f32 / own_chars >= DICTATION_MIN_SHARE {
+            return InputModality::Dictation;
+        }
+        if self.suggested_chars as f32 / own_chars >= ON_SCREEN_MIN_SUGGESTION_SHARE {
+            return InputModality::OnScreenKeyboard;
+        }
+        let (median, cv) = self.keystroke_rhythm();
+        if self.keystroke_intervals.len() >= MIN_RHYTHM_INTERVALS
+            && median >= SWITCH_ACCESS_MIN_INTERVAL_MS
+            && cv <= SWITCH_ACCESS_MAX_CV
+        {
+            return InputModality::SwitchAccess;
         }
+        InputModality::Keyboard
+    }
+
+    /// Median keystroke gap and its coefficient of variation, over the last
+    /// `RHYTHM_WINDOW` gaps.
+    fn keystroke_rhythm(&self) -> (u64, f32) {
+        let intervals = &self.keystroke_intervals;
+        if intervals.is_empty() {
+            return (0, 0.0);
+        }
+        let mut sorted: Vec<u64> = intervals.iter().copied().collect();
+        sorted.sort_unstable();
+        let median = sorted[sorted.len() / 2];
+        let mean = intervals.iter().sum::<u64>() as f32 / intervals.len() as f32;
+        if mean == 0.0 {
+            return (median, 0.0);
+        }
+        let variance = intervals
+            .iter()
+            .map(|&gap| (gap as f32 - mean).powi(2))
+            .sum::<f32>()
+            / intervals.len() as f32;
+        (median, variance.sqrt() / mean)
     }
 
     pub fn extract_timing_features(&self) -> TimingFeatures {
@@ -196,12 +471,13 @@ impl FeatureExtractor {
         let total_duration_ms = last_ts.saturating_sub(start);
 
         let avg_chars_per_sec = if total_duration_ms > 0 {
-            (self.total_typed_chars as f32 / (total_duration_ms as f32 / 1000.0))
+            self.total_typed_chars as f32 / (total_duration_ms as f32 / 1000.0)
         } else {
             0.0
         };
 
         let pre_submit_pause_ms = self.final_pause_ms;
+        let (median_keystroke_interval_ms, keystroke_interval_cv) = self.keystroke_rhythm();
 
         TimingFeatures {
             total_duration_ms,
@@ -209,6 +485,8 @@ impl FeatureExtractor {
             typing_bursts: self.typing_bursts,
             long_pause_count: self.long_pause_count,
             pre_submit_pause_ms,
+            median_keystroke_interval_ms,
+            keystroke_interval_cv,
         }
     }
 
@@ -223,9 +501,23 @@ impl FeatureExtractor {
             0.0 // Empty
         };
 
+        // Backspace corrects what was just typed; forward delete edits earlier text
+        let total_deletes = self.backspace_count + self.delete_forward_count;
+        let forward_delete_ratio = if total_deletes > 0 {
+            self.delete_forward_count as f32 / total_deletes as f32
+        } else {
+            0.0
+        };
+
         EditingFeatures {
+            phases: self.extract_phases(),
             backspace_count: self.backspace_count,
             backspace_burst_count: self.backspace_burst_count,
+            delete_forward_count: self.delete_forward_count,
+            forward_delete_ratio,
+            typo_corrections: self.typo_corrections,
+            typo_backspace_count: self.typo_backspace_count,
+            second_guessing_count: self.second_guessing_count,
             undo_count: self.undo_count,
             redo_count: self.redo_count,
             selection_edit_count: self.selection_edit_count,
@@ -233,17 +525,191 @@ impl FeatureExtractor {
         }
     }
 
+    pub fn extract_phases(&self) -> Vec<PhaseSegment> {
+        let duration = self
+            .last_event_time
+            .unwrap_or(0)
+            .saturating_sub(self.start_time.unwrap_or(0));
+        if duration < MIN_PHASED_SESSION_MS {
+            return Vec::new();
+        }
+        let mut phases = self.phases.clone();
+        if let Some(segment) = self.current_phase_segment() {
+            Self::push_phase(&mut phases, segment);
+        }
+        phases
+    }
+
+    fn current_phase_segment(&self) -> Option<PhaseSegment> {
+        let changed = self.phase_window_inserted + self.phase_window_deleted;
+        if changed == 0 {
+            return None; // Idle window
+        }
+        let delete_share = self.phase_window_deleted as f32 / changed as f32;
+        let drafted_before = self
+            .phases
+            .iter()
+            .any(|p| p.phase == DraftingPhase::Drafting);
+        let phase = if delete_share >= 0.35 {
+            DraftingPhase::Revising
+        } else if changed < POLISH_MAX_CHARS && drafted_before {
+            DraftingPhase::Polishing
+        } else {
+            DraftingPhase::Drafting
+        };
+        let start_ms = self.phase_window_index * PHASE_WINDOW_MS;
+        Some(PhaseSegment {
+            phase,
+            start_ms,
+            end_ms: start_ms + PHASE_WINDOW_MS,
+        })
+    }
+
+    fn push_phase(phases: &mut Vec<PhaseSegment>, segment: PhaseSegment) {
+        match phases.last_mut() {
+            Some(last) if last.phase == segment.phase => last.end_ms = segment.end_ms,
+            _ => phases.push(segment),
+        }
+    }
+
+    fn record_ghost_fragment(&mut self, text: &str, ts: u64) {
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        // Only text typed after this deletion can restore it
+        self.recent_typed.clear();
+
+        // Merge overlapping fragments, keeping the latest timestamp
+        if let Some(existing) = self
+            .ghost_fragments
+            .iter_mut()
+            .find(|f| Self::fragments_overlap(&f.text, text))
+        {
+            existing.text = Self::merge_fragments(&existing.text, text);
+            existing.ts = existing.ts.max(ts);
+        } else {
+            self.ghost_fragments.push(GhostFragment {
+                text: text.to_string(),
+                ts,
+                restored: false,
+            });
+        }
+
+        // Bound the candidate pool so extraction cost stays constant
+        if self.ghost_fragments.len() > MAX_GHOST_CANDIDATES {
+            let ranked = Self::rank_ghost_fragments(&self.ghost_fragments);
+            let weakest = ranked.last().map(|(_, i)| *i).unwrap_or(0);
+            self.ghost_fragments.remove(weakest);
+        }
+    }
+
+    fn record_typed_char(&mut self, ch: char) {
+        self.recent_typed.push(ch);
+        if self.recent_typed.len() > RECENT_TYPED_CAPACITY {
+            self.recent_typed.remove(0);
+        }
+
+        // Retyping essentially the same content that was deleted earlier
+        for fragment in self.ghost_fragments.iter_mut().filter(|f| !f.restored) {
+            let len = fragment.text.chars().count();
+            let typed = self.recent_typed.len();
+            if len < MIN_RESTORE_CHARS || (typed as f32) < len as f32 * RESTORE_SIMILARITY {
+                continue;
+            }
+            let tail: String = self.recent_typed[typed.saturating_sub(len)..]
+                .iter()
+                .collect();
+            if Self::bigram_similarity(&tail, &fragment.text) >= RESTORE_SIMILARITY {
+                fragment.restored = true;
+                self.second_guessing_count += 1;
+            }
+        }
+    }
+
+    /// Dice coefficient over lowercase character bigrams.
+    fn bigram_similarity(a: &str, b: &str) -> f32 {
+        let bigrams = |s: &str| -> Vec<(char, char)> {
+            let chars: Vec<char> = s.to_lowercase().chars().collect();
+            chars.windows(2).map(|w| (w[0], w[1])).collect()
+        };
+        let a = bigrams(a);
+        let mut b = bigrams(b);
+        if a.is_empty() || b.is_empty() {
+            return 0.0;
+        }
+        let total = a.len() + b.len();
+        let mut shared = 0;
+        for pair in &a {
+            if let Some(pos) = b.iter().position(|p| p == pair) {
+                b.swap_remove(pos);
+                shared += 1;
+            }
+        }
+        2.0 * shared as f32 / total as f32
+    }
+
     pub fn extract_ghost_text(&self) -> Vec<String> {
-        self.events
+        Self::rank_ghost_fragments(&self.ghost_fragments)
+            .into_iter()
+            .take(MAX_GHOST_FRAGMENTS)
+            .map(|(_, i)| self.ghost_fragments[i].text.clone())
+            .collect()
+    }
+
+    /// Indices of `fragments` ordered by length, weighted towards the most recent deletions.
+    fn rank_ghost_fragments(fragments: &[GhostFragment]) -> Vec<(f32, usize)> {
+        let newest = fragments.iter().map(|f| f.ts).max().unwrap_or(0);
+        let oldest = fragments.iter().map(|f| f.ts).min().unwrap_or(0);
+        let span = newest.saturating_sub(oldest).max(1) as f32;
+        let mut ranked: Vec<(f32, usize)> = fragments
             .iter()
-            .filter_map(|e| {
-                if let InputEvent::GhostText { text, .. } = e {
-                    Some(text.clone())
-                } else {
-                    None
-                }
+            .enumerate()
+            .map(|(i, f)| {
+                let recency = f.ts.saturating_sub(oldest) as f32 / span;
+                (f.text.chars().count() as f32 * (0.5 + 0.5 * recency), i)
             })
-            .collect()
+            .collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranked
+    }
+
+    fn fragments_overlap(a: &str, b: &str) -> bool {
+        a.contains(b)
+            || b.contains(a)
+            || Self::edge_overlap(a, b) > 0
+            || Self::edge_overlap(b, a) > 0
+    }
+
+    fn merge_fragments(a: &str, b: &str) -> String {
+        if a.contains(b) {
+            a.to_string()
+        } else if b.contains(a) {
+            b.to_string()
+        } else if Self::edge_overlap(a, b) > 0 {
+            let overlap = Self::edge_overlap(a, b);
+            format!("{}{}", a, &b[overlap..])
+        } else {
+            let overlap = Self::edge_overlap(b, a);
+            format!("{}{}", b, &a[overlap..])
+        }
+    }
+
+    /// Byte length of the longest suffix of `a` that is also a prefix of `b`
+    /// (ignoring overlaps shorter than `MIN_GHOST_OVERLAP` characters).
+    fn edge_overlap(a: &str, b: &str) -> usize {
+        b.char_indices()
+            .map(|(i, c)| i + c.len_utf8())
+            .filter(|&end| b[..end].chars().count() >= MIN_GHOST_OVERLAP)
+            .filter(|&end| a.ends_with(&b[..end]))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Default for FeatureExtractor {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -251,9 +717,66 @@ pub struct StructureAnalyzer;
 
 impl StructureAnalyzer {
     pub fn analyze(text: &str) -> StructureFeatures {
-        let char_count = text.chars().count();
-        let lines: Vec<&str> = text.lines().collect();
-        let line_count = lines.len();
+        Self::analyze_with(
+            text,
+            KeywordDictionary::builtin(),
+            TokenizerFamily::default(),
+        )
+    }
+
+    /// Cheaper analysis for per-keystroke previews: texts over `max_bytes`
+    /// are analyzed from a head and tail sample, while size counts
+    /// (characters, lines, tokens) still cover the whole text.
+    pub fn analyze_sampled(
+        text: &str,
+        keywords: &KeywordDictionary,
+        tokenizer: TokenizerFamily,
+        max_bytes: usize,
+    ) -> StructureFeatures {
+        if text.len() <= max_bytes {
+            return Self::analyze_with(text, keywords, tokenizer);
+        }
+        // Cut on line boundaries so the sample has no half lines
+        let mut head_end = max_bytes / 2;
+        while !text.is_char_boundary(head_end) {
+            head_end -= 1;
+        }
+        if let Some(nl) = text[..head_end].rfind('\n') {
+            head_end = nl + 1;
+        }
+        let mut tail_start = text.len() - max_bytes / 2;
+        while !text.is_char_boundary(tail_start) {
+            tail_start += 1;
+        }
+        if let Some(nl) = text[tail_start..].find('\n') {
+            tail_start += nl + 1;
+        }
+        let sample = format!("{}\n{}", &text[..head_end], &text[tail_start..]);
+
+        let mut structure = Self::analyze_with(&sample, keywords, tokenizer);
+        let scan = TextScan::new(text);
+        structure.char_count = scan.char_count;
+        structure.line_count = scan.line_count();
+        structure.avg_line_length = scan.char_count as f32 / structure.line_count.max(1) as f32;
+        structure.estimated_tokens = scan.estimated_tokens(tokenizer);
+        structure.sampled = true;
+        structure
+    }
+
+    /// Full analysis. The text is read once, line by line, and each line
+    /// feeds the character counts and every line-based detector (see
+    /// `LineScan`); only the Markdown parser, the PII patterns and the
+    /// keyword lookups over the lowercased copy read it again.
+    pub fn analyze_with(
+        text: &str,
+        keywords: &KeywordDictionary,
+        tokenizer: TokenizerFamily,
+    ) -> StructureFeatures {
+        let lines = LineScan::new(text);
+        let scan = &lines.scan;
+        let char_count = scan.char_count;
+        let estimated_tokens = scan.estimated_tokens(tokenizer);
+        let line_count = scan.line_count();
 
         let avg_line_length = if line_count > 0 {
             char_count as f32 / line_count as f32
@@ -261,68 +784,1549 @@ impl StructureAnalyzer {
             0.0
         };
 
-        let bullet_lines = lines
-            .iter()
-            .filter(|l| {
-                let trimmed = l.trim_start();
-                trimmed.starts_with("- ")
-                    || trimmed.starts_with("* ")
-                    || (trimmed.chars().next().map_or(false, |c| c.is_digit(10))
-                        && trimmed.contains(". "))
-            })
-            .count();
+        let has_code_block = lines.fence || lines.indented_code;
 
-        let has_code_block = text.contains("```")
-            || lines
-                .iter()
-                .any(|l| l.starts_with("    ") || l.starts_with("\t"));
-
-        let question_like =
-            text.trim().ends_with('?') || text.contains('?') || text.trim().ends_with('？');
-
-        let command_like = {
-            let lower = text.to_lowercase();
-            lower.starts_with("please")
-                || lower.starts_with("write")
-                || lower.starts_with("create")
-                || text.contains("して")
-                || text.contains("ください")
-        };
+        let question_like = scan.question_marks > 0;
+        let japanese_detected = scan.japanese_detected;
 
-        let japanese_detected = text.chars().any(|c| {
-            let u = c as u32;
-            (0x3040..=0x309F).contains(&u) || // Hiragana
-            (0x30A0..=0x30FF).contains(&u) || // Katakana
-            (0x4E00..=0x9FFF).contains(&u) // Kanji
-        });
+        let lower_text = lines.lower.as_str();
+
+        let command_like = keywords.is_command_like(lower_text);
+        let requested_actions = keywords.requested_actions(lower_text);
+        let question_type =
+            Self::classify_question(lower_text, scan.question_marks, &requested_actions);
+        let request_summary = requested_actions.contains(&RequestKind::Summarize);
+        let request_implementation = requested_actions.contains(&RequestKind::Implement);
 
-        let lower_text = text.to_lowercase();
+        let language = Self::detect_language(text, japanese_detected);
+        let translation = Self::detect_translation(text, lower_text, &language, &requested_actions);
+        let markdown = Self::analyze_markdown(text);
+        let data_format = Self::detect_json(text).or_else(|| lines.data_format());
+        let instruction_excerpt = lines.instruction(keywords);
+        let (language_shares, code_switching) = lines.language_shares();
+        let response_language = Self::response_language(
+            lines.first_line,
+            &language,
+            instruction_excerpt.as_ref(),
+            code_switching,
+        );
+        let quote_ratio = if lines.non_empty_lines > 0 {
+            lines.quoted_lines as f32 / lines.non_empty_lines as f32
+        } else {
+            0.0
+        };
+        let meeting_signals = [
+            lines.date_header,
+            lines.attendees,
+            lines.decisions,
+            lines.action_items > 0,
+        ]
+        .iter()
+        .filter(|&&s| s)
+        .count();
 
-        let request_summary =
-            lower_text.contains("summarize") || text.contains("要約") || text.contains("まとめて");
+        let pii_categories = crate::pii::categories(text);
+        let sentiment = lines.sentiment();
+        let readability = lines.readability(&language);
 
-        let request_implementation =
-            lower_text.contains("implement") || text.contains("実装") || text.contains("作って");
+        let mut url_domains: Vec<String> = Vec::new();
+        for url in &lines.urls {
+            let domain = Self::url_domain(url);
+            if !url_domains.contains(&domain) {
+                url_domains.push(domain);
+            }
+        }
+        let url_ratio = if scan.visible_chars > 0 {
+            lines.urls.iter().map(|u| u.chars().count()).sum::<usize>() as f32
+                / scan.visible_chars as f32
+        } else {
+            0.0
+        };
 
-        let is_polite = text.contains("です") || text.contains("ます") || text.contains("ください");
-        let is_direct = text.contains("だ")
-            || text.contains("である")
-            || text.contains("しろ")
-            || text.contains("せよ");
+        let (code_language, code_ratio) = if has_code_block {
+            let code = if markdown.code_text.is_empty() {
+                text // Unclosed fence or loose indentation: judge the whole message
+            } else {
+                markdown.code_text.as_str()
+            };
+            let ratio = if char_count > 0 {
+                (markdown.code_text.chars().count() as f32 / char_count as f32).min(1.0)
+            } else {
+                0.0
+            };
+            (
+                markdown
+                    .fence_language
+                    .clone()
+                    .or_else(|| Self::detect_code_language(code)),
+                ratio,
+            )
+        } else {
+            (None, 0.0)
+        };
+
+        // Register keywords depend on the language of the message
+        let politeness = Self::politeness_level(text, lower_text, &language);
 
         StructureFeatures {
             char_count,
+            estimated_tokens,
+            sampled: false,
             line_count,
             avg_line_length,
-            bullet_lines,
+            bullet_lines: lines.bullet_lines,
             has_code_block,
+            code_language,
+            code_ratio,
+            stack_trace_detected: lines.trace_header || lines.stack_frames >= 2,
+            log_line_count: lines.log_lines,
+            data_format,
+            diff_detected: lines.diff_headers >= 2
+                || (lines.diff_headers == 1 && lines.diff_changes >= 2),
+            quoted_lines: lines.quoted_lines,
+            quote_ratio,
+            math_detected: lines.math_detected(has_code_block),
+            meeting_notes_detected: meeting_signals >= 2,
+            action_item_count: lines.action_items,
+            url_count: lines.urls.len(),
+            url_domains,
+            url_ratio,
+            sentence_count: readability.sentence_count,
+            avg_sentence_length: readability.avg_sentence_length,
+            readability_score: readability.score,
+            exclamation_density: sentiment.exclamation_density,
+            caps_word_ratio: sentiment.caps_word_ratio,
+            negative_word_count: sentiment.negative_word_count,
+            frustration_score: sentiment.frustration_score,
+            contains_pii: !pii_categories.is_empty(),
+            pii_categories,
+            header_count: markdown.header_count,
+            header_levels: markdown.header_levels,
+            has_table: markdown.has_table,
+            link_count: markdown.link_count,
+            blockquote_lines: markdown.blockquote_lines,
             question_like,
+            question_count: scan.question_marks,
+            question_type,
             command_like,
             japanese_detected,
+            language,
+            language_shares,
+            code_switching,
+            response_language,
             request_summary,
             request_implementation,
-            is_polite,
-            is_direct,
+            requested_actions,
+            translation,
+            instruction_excerpt,
+            topic_keywords: lines.topic_keywords(),
+            politeness,
+        }
+    }
+
+    fn detect_translation(
+        text: &str,
+        lower_text: &str,
+        language: &str,
+        requested_actions: &[RequestKind],
+    ) -> Option<TranslationRequest> {
+        // (code, English name, Japanese name)
+        const LANGUAGES: &[(&str, &str, &str)] = &[
+            ("en", "english", "英語"),
+            ("ja", "japanese", "日本語"),
+            ("zh", "chinese", "中国語"),
+            ("ko", "korean", "韓国語"),
+            ("de", "german", "ドイツ語"),
+            ("fr", "french", "フランス語"),
+            ("es", "spanish", "スペイン語"),
+        ];
+
+        let mut source = None;
+        let mut target = None;
+
+        // Explicit code pairs such as "en->ja" or "ja→en"
+        for (from, _, _) in LANGUAGES {
+            for (to, _, _) in LANGUAGES {
+                if from != to
+                    && (lower_text.contains(&format!("{}->{}", from, to))
+                        || lower_text.contains(&format!("{}→{}", from, to)))
+                {
+                    source = Some(from.to_string());
+                    target = Some(to.to_string());
+                }
+            }
+        }
+
+        if text.contains("英訳") {
+            target = Some("en".to_string());
+        } else if text.contains("和訳") {
+            target = Some("ja".to_string());
+        }
+        if target.is_none() && !requested_actions.contains(&RequestKind::Translate) {
+            return None;
+        }
+
+        if source.is_none() {
+            for (code, en, ja) in LANGUAGES {
+                if ["into ", "to ", "in "]
+                    .iter()
+                    .any(|p| lower_text.contains(&format!("{}{}", p, en)))
+                    || [format!("{}に", ja), format!("{}で", ja)]
+                        .iter()
+                        .any(|p| text.contains(p.as_str()))
+                {
+                    target.get_or_insert_with(|| code.to_string());
+                }
+                if lower_text.contains(&format!("from {}", en))
+                    || text.contains(&format!("{}から", ja))
+                {
+                    source = Some(code.to_string());
+                }
+            }
+        }
+
+        // Mixed Japanese framing around Latin-script content: the content is the source
+        if source.is_none() {
+            let latin = text.chars().filter(|c| c.is_ascii_alphabetic()).count();
+            let mixed = language == "ja" && latin * 3 >= text.chars().count();
+            source = if mixed {
+                Some("en".to_string())
+            } else if target.as_deref() != Some(language) && language != "und" {
+                Some(language.to_string())
+            } else {
+                None
+            };
+        }
+        if target.is_none() && source.is_some() {
+            // Translating without a stated target usually means between en and ja
+            target = Some(
+                if source.as_deref() == Some("ja") {
+                    "en"
+                } else {
+                    "ja"
+                }
+                .to_string(),
+            );
+        }
+
+        Some(TranslationRequest { source, target })
+    }
+
+    /// ISO 639-1 code of the dominant language, or "und" when undetermined.
+    fn detect_language(text: &str, japanese_detected: bool) -> String {
+        let has_kana = text.chars().any(|c| ('\u{3040}'..='\u{30FF}').contains(&c));
+        if has_kana {
+            // Kanji-heavy Japanese is easily mistaken for Chinese
+            return "ja".to_string();
+        }
+        // Identification settles long before the end of a large paste
+        let sample = match text.char_indices().nth(LANGUAGE_SAMPLE_CHARS) {
+            Some((end, _)) => &text[..end],
+            None => text,
+        };
+        let Some(info) = whatlang::detect(sample) else {
+            return if japanese_detected { "ja" } else { "und" }.to_string();
+        };
+        if !info.is_reliable() && info.script() == whatlang::Script::Latin {
+            // Short Latin-script snippets are mostly English in practice
+            return "en".to_string();
+        }
+        match info.lang() {
+            whatlang::Lang::Eng => "en",
+            whatlang::Lang::Jpn => "ja",
+            whatlang::Lang::Cmn => "zh",
+            whatlang::Lang::Kor => "ko",
+            whatlang::Lang::Deu => "de",
+            whatlang::Lang::Fra => "fr",
+            whatlang::Lang::Spa => "es",
+            whatlang::Lang::Por => "pt",
+            whatlang::Lang::Ita => "it",
+            whatlang::Lang::Rus => "ru",
+            other => other.code(),
+        }
+        .to_string()
+    }
+
+    /// Answer in the language of the framing the user typed, not of a pasted
+    /// body: the extracted instruction or, in mixed messages, the first line.
+    fn response_language(
+        first_line: Option<&str>,
+        language: &str,
+        instruction: Option<&InstructionExcerpt>,
+        code_switching: bool,
+    ) -> String {
+        let framing = match instruction {
+            Some(instruction) => Some(instruction.text.as_str()),
+            None if code_switching => first_line,
+            None => None,
+        };
+        framing
+            .map(|f| Self::detect_language(f, Self::has_cjk(f)))
+            .filter(|l| l != "und")
+            .unwrap_or_else(|| language.to_string())
+    }
+
+    fn has_cjk(text: &str) -> bool {
+        text.chars().any(|c| {
+            let u = c as u32;
+            (0x3040..=0x30FF).contains(&u) || (0x4E00..=0x9FFF).contains(&u)
+        })
+    }
+
+    fn is_log_line(line: &str) -> bool {
+        let bytes = line.as_bytes();
+        let digit = |i: usize| bytes.get(i).is_some_and(|b| b.is_ascii_digit());
+        // 2024-01-31... or [12:34:56...
+        let iso_date = (0..4).all(digit) && bytes.get(4) == Some(&b'-') && digit(5);
+        let bracket_time =
+            bytes.first() == Some(&b'[') && digit(1) && digit(2) && bytes.get(3) == Some(&b':');
+        const LEVELS: &[&str] = &[
+            "ERROR", "WARN", "INFO", "DEBUG", "TRACE", "FATAL", "Error:", "error:",
+        ];
+        let level = LEVELS.iter().any(|l| {
+            line.starts_with(l)
+                || line.match_indices(l).any(|(i, _)| {
+                    let before = line[..i].chars().next_back();
+                    let after = line[i + l.len()..].chars().next();
+                    (before == Some(' ') && after == Some(' '))
+                        || (before == Some('[') && after == Some(']'))
+                })
+        });
+        iso_date || bracket_time || level
+    }
+
+    fn estimate_syllables(word: &str) -> usize {
+        let letters: Vec<char> = word
+            .chars()
+            .filter(|c| c.is_alphabetic())
+            .flat_map(|c| c.to_lowercase())
+            .collect();
+        if letters.iter().any(|c| !c.is_ascii()) {
+            return letters.len().max(1); // Syllabic scripts such as Hangul
+        }
+        let is_vowel = |c: &char| "aeiouy".contains(*c);
+        let mut count = 0usize;
+        let mut previous_vowel = false;
+        for c in &letters {
+            let vowel = is_vowel(c);
+            if vowel && !previous_vowel {
+                count += 1;
+            }
+            previous_vowel = vowel;
+        }
+        // Silent trailing "e"
+        if letters.len() > 2 && letters.ends_with(&['e']) && !is_vowel(&letters[letters.len() - 2])
+        {
+            count = count.saturating_sub(1);
+        }
+        count.max(1)
+    }
+
+    fn extract_urls(text: &str) -> Vec<&str> {
+        text.split(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '(' | ')' | '"' | '\''))
+            .filter(|token| {
+                token.starts_with("http://")
+                    || token.starts_with("https://")
+                    || token.starts_with("www.")
+            })
+            .map(|token| token.trim_end_matches(['.', ',', ';', ':', '!', '?', ']']))
+            .filter(|url| url.len() > "https://".len())
+            .collect()
+    }
+
+    fn url_domain(url: &str) -> String {
+        let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+        rest.split(['/', ':', '?', '#'])
+            .next()
+            .unwrap_or(rest)
+            .trim_start_matches("www.")
+            .to_lowercase()
+    }
+
+    /// Grade the register of the message. Japanese distinguishes plain form,
+    /// です・ます and honorific/humble keigo; other languages only separate
+    /// polite from plain/imperative wording.
+    fn politeness_level(text: &str, lower_text: &str, language: &str) -> PolitenessLevel {
+        let grade = |polite: bool, direct: bool| {
+            if polite {
+                PolitenessLevel::Polite
+            } else if direct {
+                PolitenessLevel::Plain
+            } else {
+                PolitenessLevel::Neutral
+            }
+        };
+        match language {
+            "en" => grade(
+                [
+                    "could you",
+                    "would you",
+                    "thank you",
+                    "thanks",
+                    "i'd appreciate",
+                ]
+                .iter()
+                .any(|k| lower_text.contains(k)),
+                false,
+            ),
+            "de" => grade(
+                ["bitte", "könnten sie", "würden sie", "danke"]
+                    .iter()
+                    .any(|k| lower_text.contains(k)),
+                false,
+            ),
+            "ko" => {
+                // Honorific (-요/-습니다) vs. plain imperative (-해라/-줘) sentence endings
+                let endings: Vec<&str> = text
+                    .split(['.', '?', '!', '\n'])
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                grade(
+                    endings
+                        .iter()
+                        .any(|s| s.ends_with('요') || s.ends_with("니다"))
+                        || text.contains("부탁"),
+                    endings
+                        .iter()
+                        .any(|s| s.ends_with("해라") || s.ends_with("하라") || s.ends_with('줘')),
+                )
+            }
+            "zh" => grade(
+                ["请", "麻烦", "劳驾", "您", "谢谢", "能否"]
+                    .iter()
+                    .any(|k| text.contains(k)),
+                ["给我", "必须", "马上", "赶紧"]
+                    .iter()
+                    .any(|k| text.contains(k)),
+            ),
+            _ => {
+                // Sonkeigo/kenjougo markers outrank plain です・ます
+                let honorific = [
+                    "いただけ",
+                    "いただき",
+                    "くださいませ",
+                    "存じ",
+                    "申し上げ",
+                    "拝見",
+                    "拝読",
+                    "伺い",
+                    "おっしゃ",
+                    "いらっしゃ",
+                    "ご覧",
+                    "恐れ入り",
+                    "恐縮",
+                    "差し上げ",
+                ];
+                if honorific.iter().any(|k| text.contains(k)) {
+                    PolitenessLevel::Honorific
+                } else {
+                    // Sentence-final forms only, so まだ/ただ/だけ mid-sentence don't read as plain
+                    const MARKS: [char; 5] = ['。', '！', '？', '!', '?'];
+                    let endings: Vec<(&str, Option<char>)> = text
+                        .split_inclusive(['。', '！', '？', '!', '?', '\n'])
+                        .map(|s| s.trim())
+                        .filter(|s| !s.is_empty())
+                        .map(|s| {
+                            (
+                                s.trim_end_matches(MARKS),
+                                s.chars().last().filter(|c| MARKS.contains(c)),
+                            )
+                        })
+                        .collect();
+                    grade(
+                        endings.iter().any(|(s, _)| {
+                            // Particles like か/ね/よ may follow the polite ending
+                            let s = s.trim_end_matches(['か', 'ね', 'よ']);
+                            ["です", "ます", "ました", "ません", "でした", "ください"]
+                                .iter()
+                                .any(|e| s.ends_with(e))
+                        }),
+                        endings.iter().any(|(s, mark)| {
+                            (s.ends_with('だ') && matches!(mark, None | Some('。')))
+                                || ["である", "しろ", "せよ"].iter().any(|e| s.ends_with(e))
+                        }),
+                    )
+                }
+            }
+        }
+    }
+
+    /// Dates like `2024-05-01`, `2024/5/1` or `2024年5月1日`.
+    fn has_date(text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        chars.windows(6).any(|w| {
+            w[..4].iter().all(|c| c.is_ascii_digit())
+                && matches!(w[4], '-' | '/' | '年' | '.')
+                && w[5].is_ascii_digit()
+        })
+    }
+
+    /// What kind of answer the question asks for: several questions, an
+    /// open why/how question, a lookup, or a yes/no check.
+    fn classify_question(
+        lower_text: &str,
+        question_marks: usize,
+        requested_actions: &[RequestKind],
+    ) -> Option<QuestionType> {
+        const OPEN_MARKERS: &[&str] = &[
+            "why ",
+            "how ",
+            "what if",
+            "what would",
+            "explain",
+            "walk me through",
+            "なぜ",
+            "どうして",
+            "どうやって",
+            "どのように",
+            "説明",
+            "解説",
+        ];
+        const YES_NO_STARTS: &[&str] = &[
+            "is ", "are ", "was ", "were ", "do ", "does ", "did ", "can ", "could ", "should ",
+            "will ", "would ", "has ", "have ", "am ",
+        ];
+
+        // "What is X?" also counts as an explain request, so only the
+        // wording decides between a lookup and an open question
+        let open = requested_actions.contains(&RequestKind::Compare)
+            || OPEN_MARKERS.iter().any(|m| lower_text.contains(m));
+        if question_marks == 0 && !open {
+            return None;
+        }
+        if question_marks >= 2 {
+            return Some(QuestionType::MultiPart);
+        }
+        if open {
+            return Some(QuestionType::Open);
+        }
+        // The question sentence itself, without any lead-in
+        let question = lower_text
+            .split(['\n', '.', '。'])
+            .find(|sentence| sentence.contains(['?', '？']))
+            .unwrap_or(lower_text)
+            .trim_start();
+        if requested_actions.contains(&RequestKind::Confirm)
+            || YES_NO_STARTS
+                .iter()
+                .any(|start| question.starts_with(start))
+        {
+            return Some(QuestionType::YesNo);
+        }
+        Some(QuestionType::Factual)
+    }
+
+    /// Fields of a delimited row; delimiters inside double quotes do not
+    /// split it.
+    fn csv_field_count(line: &str, delimiter: char) -> usize {
+        let mut quoted = false;
+        let mut fields = 1;
+        for ch in line.chars() {
+            if ch == '"' {
+                quoted = !quoted;
+            } else if ch == delimiter && !quoted {
+                fields += 1;
+            }
+        }
+        fields
+    }
+
+    /// A body that is predominantly JSON: the largest `{...}` or `[...]`
+    /// span parses and covers most of the text. CSV and YAML are judged
+    /// line by line in `LineScan`.
+    fn detect_json(text: &str) -> Option<DataFormat> {
+        let trimmed = text.trim();
+        let (start, end) = (trimmed.find(['{', '['])?, trimmed.rfind(['}', ']'])?);
+        if start >= end {
+            return None;
+        }
+        let candidate = &trimmed[start..=end];
+        (candidate.len() as f32 >= trimmed.len() as f32 * DOMINANT_DATA_SHARE
+            && serde_json::from_str::<serde_json::Value>(candidate).is_ok())
+        .then_some(DataFormat::Json)
+    }
+
+    fn normalize_code_language(tag: &str) -> String {
+        match tag.to_lowercase().as_str() {
+            "rs" => "rust",
+            "py" | "python3" => "python",
+            "js" | "jsx" | "node" => "javascript",
+            "ts" | "tsx" => "typescript",
+            "sh" | "bash" | "zsh" | "shell" | "console" => "shell",
+            "c++" | "cc" | "hpp" => "cpp",
+            "golang" => "go",
+            "yml" => "yaml",
+            other => return other.to_string(),
+        }
+        .to_string()
+    }
+
+    /// Guess the language of unlabeled code by counting characteristic tokens.
+    fn detect_code_language(code: &str) -> Option<String> {
+        const SIGNATURES: &[(&str, &[&str])] = &[
+            (
+                "rust",
+                &[
+                    "fn ", "let mut ", "impl ", "pub fn", "-> ", "&str", "println!", "::new(",
+                ],
+            ),
+            (
+                "python",
+                &[
+                    "def ", "import ", "self.", "elif ", "print(", "None", "__init__",
+                ],
+            ),
+            (
+                "typescript",
+                &["interface ", ": string", ": number", "export type "],
+            ),
+            (
+                "javascript",
+                &[
+                    "const ",
+                    "function ",
+                    "=> ",
+                    "console.log",
+                    "===",
+                    "require(",
+                ],
+            ),
+            ("go", &["func ", "package ", ":= ", "fmt.", "err != nil"]),
+            (
+                "java",
+                &[
+                    "public class",
+                    "System.out",
+                    "private ",
+                    "void ",
+                    "@Override",
+                ],
+            ),
+            ("cpp", &["#include", "std::", "int main", "printf(", "->"]),
+            (
+                "sql",
+                &["SELECT ", "FROM ", "WHERE ", "INSERT INTO", "JOIN "],
+            ),
+            (
+                "shell",
+                &["#!/bin", "$ ", "echo ", "sudo ", "export ", "| grep"],
+            ),
+        ];
+
+        SIGNATURES
+            .iter()
+            .map(|(lang, tokens)| {
+                let hits: usize = tokens.iter().map(|t| code.matches(t).count()).sum();
+                (hits, *lang)
+            })
+            .filter(|(hits, _)| *hits >= 2)
+            .max_by_key(|(hits, _)| *hits)
+            .map(|(_, lang)| lang.to_string())
+    }
+
+    fn analyze_markdown(text: &str) -> MarkdownStats {
+        use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+
+        let mut stats = MarkdownStats::default();
+        let mut quote_depth = 0usize;
+        let mut in_code = false;
+        let parser = Parser::new_ext(text, Options::ENABLE_TABLES).into_offset_iter();
+        for (event, range) in parser {
+            match event {
+                Event::Start(Tag::Heading { level, .. }) => {
+                    stats.header_count += 1;
+                    let level = level as u8;
+                    if !stats.header_levels.contains(&level) {
+                        stats.header_levels.push(level);
+                    }
+                }
+                Event::Start(Tag::Table(_)) => stats.has_table = true,
+                Event::Start(Tag::Link { .. }) => stats.link_count += 1,
+                Event::Start(Tag::CodeBlock(kind)) => {
+                    in_code = true;
+                    if let CodeBlockKind::Fenced(info) = kind {
+                        let tag = info.split_whitespace().next().unwrap_or("");
+                        if stats.fence_language.is_none() && !tag.is_empty() {
+                            stats.fence_language = Some(Self::normalize_code_language(tag));
+                        }
+                    }
+                }
+                Event::End(pulldown_cmark::TagEnd::CodeBlock) => in_code = false,
+                Event::Text(code) if in_code => stats.code_text.push_str(&code),
+                Event::Start(Tag::BlockQuote(_)) => {
+                    // Count each quoted line once, even inside nested quotes
+                    if quote_depth == 0 {
+                        stats.blockquote_lines += text[range].lines().count();
+                    }
+                    quote_depth += 1;
+                }
+                Event::End(pulldown_cmark::TagEnd::BlockQuote(_)) => {
+                    quote_depth = quote_depth.saturating_sub(1);
+                }
+                _ => {}
+            }
+        }
+        stats.header_levels.sort_unstable();
+        stats
+    }
+}
+
+/// Character-level statistics, gathered as the text is read.
+#[derive(Default)]
+struct TextScan {
+    char_count: usize,
+    line_breaks: usize,
+    cjk_chars: usize,
+    visible_chars: usize,
+    japanese_detected: bool,
+    /// Question marks, counting a run like `??` once.
+    question_marks: usize,
+    previous: Option<char>,
+}
+
+impl TextScan {
+    fn new(text: &str) -> Self {
+        let mut scan = Self::default();
+        scan.add(text);
+        scan
+    }
+
+    /// Count the characters of the next piece of the text.
+    fn add(&mut self, text: &str) {
+        let is_mark = |c: char| c == '?' || c == '？';
+        for c in text.chars() {
+            self.char_count += 1;
+            if c == '\n' {
+                self.line_breaks += 1;
+            }
+            if crate::tokens::is_cjk(c) {
+                self.cjk_chars += 1;
+            }
+            if !c.is_whitespace() {
+                self.visible_chars += 1;
+            }
+            let u = c as u32;
+            self.japanese_detected |= (0x3040..=0x309F).contains(&u) // Hiragana
+                || (0x30A0..=0x30FF).contains(&u) // Katakana
+                || (0x4E00..=0x9FFF).contains(&u); // Kanji
+            if is_mark(c) && !self.previous.is_some_and(is_mark) {
+                self.question_marks += 1;
+            }
+            self.previous = Some(c);
+        }
+    }
+
+    /// Lines as `str::lines` counts them: the last needs no line break.
+    fn line_count(&self) -> usize {
+        self.line_breaks + usize::from(self.previous.is_some_and(|c| c != '\n'))
+    }
+
+    fn estimated_tokens(&self, tokenizer: TokenizerFamily) -> usize {
+        tokenizer.estimate_counts(self.char_count - self.cjk_chars, self.cjk_chars)
+    }
+}
+
+/// Everything the structure analysis reads off the text, gathered in one
+/// pass: each line, as `str::lines` splits them, updates the character
+/// counts, a lowercase copy for keyword lookups and the state of every
+/// line-based detector. Patterns that may run across a line break
+/// (arithmetic, inline `$...$`, topic terms) carry their state over.
+struct LineScan<'a> {
+    text: &'a str,
+    scan: TextScan,
+    lower: String,
+    lines: usize,
+    /// First and last non-empty lines, trimmed.
+    first_line: Option<&'a str>,
+    last_line: Option<&'a str>,
+    /// Byte ranges of the first and last paragraphs of non-blank lines.
+    first_paragraph: Option<(usize, usize)>,
+    last_paragraph: Option<(usize, usize)>,
+    in_paragraph: bool,
+
+    bullet_lines: usize,
+    indented_code: bool,
+    fence: bool,
+    // Pasted panics and tracebacks, and log-looking lines
+    trace_header: bool,
+    stack_frames: usize,
+    log_lines: usize,
+    // Unified diffs / `git diff` output
+    diff_headers: usize,
+    diff_changes: usize,
+    // `>`-quoted lines and email reply quoting: attribution lines and
+    // everything below an "Original Message" separator
+    quoted_lines: usize,
+    non_empty_lines: usize,
+    in_original: bool,
+    // Meeting-minutes layout in English or Japanese
+    date_header: bool,
+    attendees: bool,
+    decisions: bool,
+    action_items: usize,
+    // CSV and YAML, over the non-empty lines: the first row's field count
+    // per delimiter and the rows that match it
+    body_lines: usize,
+    csv_header: [usize; 3],
+    csv_rows: [usize; 3],
+    yaml_lines: usize,
+    mapping_lines: usize,
+    // LaTeX fragments, equation-like lines, inline `$...$` spans and
+    // "number operator number" expressions
+    latex: bool,
+    equation_lines: usize,
+    dollars: usize,
+    math_span: Option<MathSpan>,
+    inline_math: bool,
+    arithmetic: usize,
+    operator_after_digit: bool,
+    last_visible: Option<char>,
+    previous: Option<char>,
+    // Sentiment
+    exclamations: usize,
+    long_words: usize,
+    caps_words: usize,
+    negative_words: usize,
+    // Readability: sentences with their characters and kanji (for CJK) and
+    // their words and syllables (for Flesch)
+    sentences: usize,
+    sentence_chars: usize,
+    sentence_kanji: usize,
+    words: usize,
+    syllables: usize,
+    urls: Vec<&'a str>,
+    languages: LanguageTally,
+    topics: TopicTerms,
+}
+
+/// What decides whether an inline `$...$` span is math.
+#[derive(Default)]
+struct MathSpan {
+    bytes: usize,
+    first: Option<char>,
+    operators: bool,
+}
+
+impl MathSpan {
+    /// Math inside, not currency like "$5 and $10".
+    fn is_math(&self) -> bool {
+        self.bytes > 0 && self.bytes < 80 && self.operators && self.first != Some(' ')
+    }
+}
+
+impl<'a> LineScan<'a> {
+    fn new(text: &'a str) -> Self {
+        let mut scan = Self {
+            text,
+            scan: TextScan::default(),
+            lower: String::with_capacity(text.len()),
+            lines: 0,
+            first_line: None,
+            last_line: None,
+            first_paragraph: None,
+            last_paragraph: None,
+            in_paragraph: false,
+            bullet_lines: 0,
+            indented_code: false,
+            fence: false,
+            trace_header: false,
+            stack_frames: 0,
+            log_lines: 0,
+            diff_headers: 0,
+            diff_changes: 0,
+            quoted_lines: 0,
+            non_empty_lines: 0,
+            in_original: false,
+            date_header: false,
+            attendees: false,
+            decisions: false,
+            action_items: 0,
+            body_lines: 0,
+            csv_header: [0; 3],
+            csv_rows: [0; 3],
+            yaml_lines: 0,
+            mapping_lines: 0,
+            latex: false,
+            equation_lines: 0,
+            dollars: 0,
+            math_span: None,
+            inline_math: false,
+            arithmetic: 0,
+            operator_after_digit: false,
+            last_visible: None,
+            previous: None,
+            exclamations: 0,
+            long_words: 0,
+            caps_words: 0,
+            negative_words: 0,
+            sentences: 0,
+            sentence_chars: 0,
+            sentence_kanji: 0,
+            words: 0,
+            syllables: 0,
+            urls: Vec::new(),
+            languages: LanguageTally::default(),
+            topics: TopicTerms::default(),
+        };
+        let mut offset = 0;
+        for chunk in text.split_inclusive('\n') {
+            scan.push(offset, chunk);
+            offset += chunk.len();
+        }
+        scan.languages.add_segment();
+        scan.topics.flush();
+        // After an odd `$`, the rest of the text counts as a span
+        if let Some(span) = scan.math_span.take() {
+            scan.inline_math |= span.is_math();
+        }
+        scan
+    }
+
+    /// One line with its line break, `offset` bytes into the text.
+    fn push(&mut self, offset: usize, chunk: &'a str) {
+        let line = match chunk.strip_suffix('\n') {
+            Some(line) => line.strip_suffix('\r').unwrap_or(line),
+            None => chunk,
+        };
+        let trimmed = line.trim();
+        let lower_chunk = chunk.to_lowercase();
+        let index = self.lines;
+        self.lines += 1;
+        self.scan.add(chunk);
+
+        if trimmed.is_empty() {
+            self.in_paragraph = false;
+        } else {
+            self.first_line.get_or_insert(trimmed);
+            self.last_line = Some(trimmed);
+            let end = offset + line.len();
+            match &mut self.last_paragraph {
+                Some(paragraph) if self.in_paragraph => paragraph.1 = end,
+                _ => self.last_paragraph = Some((offset, end)),
+            }
+            self.in_paragraph = true;
+            if self
+                .first_paragraph
+                .is_none_or(|first| Some(first.0) == self.last_paragraph.map(|last| last.0))
+            {
+                self.first_paragraph = self.last_paragraph;
+            }
+        }
+
+        self.push_layout(line, trimmed, lower_chunk.trim(), index);
+        self.push_data(line, trimmed);
+        self.push_math(chunk, trimmed);
+        self.push_words(chunk, &lower_chunk);
+        self.urls.extend(StructureAnalyzer::extract_urls(chunk));
+        self.languages.push(trimmed);
+        self.topics.push(&lower_chunk);
+        self.lower.push_str(&lower_chunk);
+    }
+
+    fn push_layout(&mut self, line: &str, trimmed: &str, lower: &str, index: usize) {
+        let indented = line.trim_start();
+        if indented.starts_with("- ")
+            || indented.starts_with("* ")
+            || (indented.chars().next().is_some_and(|c| c.is_ascii_digit())
+                && indented.contains(". "))
+        {
+            self.bullet_lines += 1;
+        }
+        self.indented_code |= line.starts_with("    ") || line.starts_with('\t');
+        self.fence |= line.contains("```");
+
+        if indented.contains("panicked at")
+            || indented.starts_with("Traceback (most recent call last)")
+            || indented.starts_with("error[E")
+            || indented.starts_with("Exception in thread")
+            || indented.starts_with("Caused by:")
+        {
+            self.trace_header = true;
+        }
+        // Stack frames: JS/Java "at ...", Python 'File "..."', Rust backtrace "  3: ..."
+        let numbered_frame = indented
+            .split_once(": ")
+            .is_some_and(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+        if (indented.starts_with("at ") && line.starts_with(char::is_whitespace))
+            || indented.starts_with("File \"")
+            || (numbered_frame && line.starts_with(char::is_whitespace))
+        {
+            self.stack_frames += 1;
+        }
+        if StructureAnalyzer::is_log_line(indented) {
+            self.log_lines += 1;
+        }
+
+        if line.starts_with("diff --git ")
+            || line.starts_with("--- a/")
+            || line.starts_with("+++ b/")
+            || (line.starts_with("@@ -") && line[4..].contains(" @@"))
+        {
+            self.diff_headers += 1;
+        }
+        if (line.starts_with('+') && !line.starts_with("+++"))
+            || (line.starts_with('-') && !line.starts_with("---") && !line.starts_with("- "))
+        {
+            self.diff_changes += 1;
+        }
+
+        if !trimmed.is_empty() {
+            self.non_empty_lines += 1;
+            if lower.contains("original message") && trimmed.starts_with("---") {
+                self.in_original = true;
+            }
+            let attribution = (lower.starts_with("on ") && lower.ends_with("wrote:"))
+                || trimmed.ends_with("書きました:")
+                || trimmed.ends_with("書きました：");
+            if self.in_original || attribution || trimmed.starts_with('>') {
+                self.quoted_lines += 1;
+            }
+        }
+
+        const ATTENDEE_MARKERS: &[&str] =
+            &["attendees", "participants", "present:", "出席者", "参加者"];
+        const DECISION_MARKERS: &[&str] = &["decision", "agreed", "決定", "合意"];
+        const ACTION_MARKERS: &[&str] = &[
+            "todo",
+            "action item",
+            "next step",
+            "宿題",
+            "アクションアイテム",
+            "担当",
+            "[ ]",
+        ];
+        if index < 5
+            && (lower.starts_with("date")
+                || lower.starts_with("日時")
+                || StructureAnalyzer::has_date(lower))
+        {
+            self.date_header = true;
+        }
+        self.attendees |= ATTENDEE_MARKERS.iter().any(|m| lower.starts_with(m));
+        self.decisions |= DECISION_MARKERS.iter().any(|m| lower.contains(m));
+        if ACTION_MARKERS.iter().any(|m| lower.contains(m)) {
+            self.action_items += 1;
         }
     }
+
+    fn push_data(&mut self, line: &str, trimmed: &str) {
+        if trimmed.is_empty() {
+            return;
+        }
+        // CSV: rows of the first row's three or more fields. Prose has
+        // commas too, but its lines end as sentences do
+        let sentence = line.trim_end().ends_with(['.', '!', '?', '。', '！', '？']);
+        for (i, delimiter) in [',', '\t', ';'].into_iter().enumerate() {
+            let fields = if sentence {
+                0
+            } else {
+                StructureAnalyzer::csv_field_count(line, delimiter)
+            };
+            if self.body_lines == 0 {
+                self.csv_header[i] = fields;
+            }
+            if fields == self.csv_header[i] {
+                self.csv_rows[i] += 1;
+            }
+        }
+        self.body_lines += 1;
+
+        // YAML: "key: value" mappings with identifier-like keys, or "- item" sequences
+        let t = line.trim_start();
+        let yaml = t.starts_with("- ")
+            || t == "---"
+            || t.starts_with('#')
+            || t.split_once(':').is_some_and(|(key, rest)| {
+                !key.is_empty()
+                    && key
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+                    && (rest.is_empty() || rest.starts_with(' '))
+            });
+        if yaml {
+            self.yaml_lines += 1;
+        }
+        if line.contains(": ") || line.ends_with(':') {
+            self.mapping_lines += 1;
+        }
+    }
+
+    fn push_math(&mut self, chunk: &str, trimmed: &str) {
+        const LATEX_MARKERS: &[&str] = &[
+            "$$",
+            "\\frac",
+            "\\sum",
+            "\\int",
+            "\\sqrt",
+            "\\begin{equation",
+            "\\begin{align",
+            "\\(",
+            "\\[",
+            "\\cdot",
+            "\\times",
+        ];
+        self.latex |= LATEX_MARKERS.iter().any(|m| chunk.contains(m));
+
+        // Equations: variables are single letters, so no word of 3+ letters.
+        // `=` as a comparison or an assignment does not count
+        let is_math_char = |c: char| {
+            c.is_ascii_digit() || "+-*/^=()[].,<>≤≥×÷√π²³".contains(c) || c.is_ascii_lowercase()
+        };
+        if trimmed.contains('=')
+            && !["==", "=>", "!=", ":=", "+="]
+                .iter()
+                .any(|op| trimmed.contains(op))
+            && !trimmed
+                .split(|c: char| !c.is_alphabetic())
+                .any(|w| w.chars().count() >= 3)
+        {
+            let mut compact = trimmed.chars().filter(|c| !c.is_whitespace());
+            let mut digit = false;
+            if compact.all(|c| {
+                digit |= c.is_ascii_digit();
+                is_math_char(c)
+            }) && digit
+            {
+                self.equation_lines += 1;
+            }
+        }
+
+        for c in chunk.chars() {
+            if c == '$' {
+                if let Some(span) = self.math_span.take() {
+                    self.inline_math |= span.is_math();
+                }
+                self.dollars += 1;
+                if self.dollars % 2 == 1 {
+                    self.math_span = Some(MathSpan::default());
+                }
+            } else if let Some(span) = &mut self.math_span {
+                span.bytes += c.len_utf8();
+                span.first.get_or_insert(c);
+                span.operators |= matches!(c, '^' | '_' | '=' | '\\');
+            }
+
+            if !c.is_whitespace() {
+                if self.operator_after_digit && c.is_ascii_digit() {
+                    self.arithmetic += 1;
+                }
+                // A minus only counts when spaced, so dates and ranges are skipped
+                let operator = "+*/×÷^".contains(c) || (c == '-' && self.previous == Some(' '));
+                self.operator_after_digit =
+                    operator && self.last_visible.is_some_and(|c| c.is_ascii_digit());
+                self.last_visible = Some(c);
+            }
+            self.previous = Some(c);
+        }
+    }
+
+    fn push_words(&mut self, chunk: &str, lower_chunk: &str) {
+        const NEGATIVE_WORDS: &[&str] = &[
+            "doesn't work",
+            "does not work",
+            "not working",
+            "broken",
+            "stupid",
+            "useless",
+            "hate",
+            "annoying",
+            "terrible",
+            "frustrat",
+            "wtf",
+            "damn",
+            "shit",
+            "fuck",
+            "ugh",
+            "くそ",
+            "クソ",
+            "最悪",
+            "むかつく",
+            "ムカつく",
+            "イライラ",
+            "ふざけ",
+            "動かない",
+            "使えない",
+            "ダメ",
+            "だめ",
+        ];
+        self.exclamations += chunk.chars().filter(|c| matches!(c, '!' | '！')).count();
+        // Shouting: words of 3+ letters written entirely in capitals
+        for word in chunk
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .filter(|w| w.len() >= 3)
+        {
+            self.long_words += 1;
+            if word.chars().all(|c| c.is_ascii_uppercase()) {
+                self.caps_words += 1;
+            }
+        }
+        self.negative_words += NEGATIVE_WORDS
+            .iter()
+            .map(|w| lower_chunk.matches(w).count())
+            .sum::<usize>();
+
+        for sentence in chunk
+            .split(['.', '!', '?', '。', '！', '？', '\n'])
+            .map(|s| s.trim())
+            .filter(|s| s.chars().any(|c| c.is_alphanumeric()))
+        {
+            self.sentences += 1;
+            for c in sentence.chars() {
+                self.sentence_chars += 1;
+                if ('\u{4E00}'..='\u{9FFF}').contains(&c) {
+                    self.sentence_kanji += 1;
+                }
+            }
+            for word in sentence
+                .split_whitespace()
+                .filter(|w| w.chars().any(|c| c.is_alphabetic()))
+            {
+                self.words += 1;
+                self.syllables += StructureAnalyzer::estimate_syllables(word);
+            }
+        }
+    }
+
+    /// CSV or YAML making up most of the non-empty lines.
+    fn data_format(&self) -> Option<DataFormat> {
+        if self.body_lines < 3 {
+            return None;
+        }
+        let dominant = |count: usize| count as f32 >= self.body_lines as f32 * DOMINANT_DATA_SHARE;
+        if self
+            .csv_header
+            .iter()
+            .zip(&self.csv_rows)
+            .any(|(&header, &rows)| header >= 3 && dominant(rows))
+        {
+            return Some(DataFormat::Csv);
+        }
+        (dominant(self.yaml_lines) && self.mapping_lines > 0).then_some(DataFormat::Yaml)
+    }
+
+    /// A short instruction typed before or after a large pasted body.
+    fn instruction(&self, keywords: &KeywordDictionary) -> Option<InstructionExcerpt> {
+        let char_count = self.scan.char_count;
+        if char_count < MIN_INSTRUCTION_BODY_CHARS {
+            return None;
+        }
+        let (first_line, last_line) = (self.first_line?, self.last_line?);
+        let paragraph = |range: Option<(usize, usize)>| {
+            range.map(|(start, end)| {
+                let lines: Vec<&str> = self.text[start..end].lines().collect();
+                lines.join("\n").trim().to_string()
+            })
+        };
+
+        // Prefer the whole leading/trailing paragraph, falling back to a single line
+        let pick = |paragraph: Option<String>, line: &str| -> Option<String> {
+            let block = match paragraph {
+                Some(p) if p.chars().count() <= MAX_INSTRUCTION_CHARS => p,
+                _ => line.to_string(),
+            };
+            let len = block.chars().count();
+            (len <= MAX_INSTRUCTION_CHARS && len * 2 < char_count).then_some(block)
+        };
+        // Explicit request keywords outweigh a trailing '?' or ':'
+        let score = |block: &str| -> u8 {
+            let lower = block.to_lowercase();
+            if keywords.is_command_like(&lower) || !keywords.requested_actions(&lower).is_empty() {
+                2
+            } else if block.ends_with(['?', '？', ':', '：']) {
+                1
+            } else {
+                0
+            }
+        };
+
+        let before = pick(paragraph(self.first_paragraph), first_line).map(|b| (score(&b), b));
+        let after = pick(paragraph(self.last_paragraph), last_line).map(|b| (score(&b), b));
+        let (position, (_, text)) = match (before, after) {
+            (Some(b), Some(a)) if a.0 > b.0 => (InstructionPosition::After, a),
+            (Some(b), _) if b.0 > 0 => (InstructionPosition::Before, b),
+            (_, Some(a)) if a.0 > 0 => (InstructionPosition::After, a),
+            _ => return None,
+        };
+        Some(InstructionExcerpt { text, position })
+    }
+
+    /// LaTeX anywhere, or, outside code (where `=` is assignment), an
+    /// equation line or arithmetic-heavy prose.
+    fn math_detected(&self, has_code_block: bool) -> bool {
+        self.latex
+            || (self.inline_math && self.dollars >= 2)
+            || (!has_code_block && (self.equation_lines > 0 || self.arithmetic >= 3))
+    }
+
+    fn sentiment(&self) -> SentimentStats {
+        let char_count = self.scan.char_count;
+        let exclamation_density = if char_count > 0 {
+            self.exclamations as f32 * 100.0 / char_count as f32
+        } else {
+            0.0
+        };
+        let caps_word_ratio = if self.long_words == 0 {
+            0.0
+        } else {
+            self.caps_words as f32 / self.long_words as f32
+        };
+        let frustration_score = 0.3 * (exclamation_density / 3.0).min(1.0)
+            + 0.3 * (caps_word_ratio * 2.0).min(1.0)
+            + 0.4 * (self.negative_words as f32 / 2.0).min(1.0);
+        SentimentStats {
+            exclamation_density,
+            caps_word_ratio,
+            negative_word_count: self.negative_words,
+            frustration_score,
+        }
+    }
+
+    /// Sentence statistics plus a 0-100 ease score (higher is easier to read).
+    fn readability(&self, language: &str) -> ReadabilityStats {
+        let sentence_count = self.sentences;
+        let easy = |avg_sentence_length: f32| ReadabilityStats {
+            sentence_count,
+            avg_sentence_length,
+            score: 100.0,
+        };
+        if sentence_count == 0 {
+            return easy(0.0);
+        }
+
+        let (avg_sentence_length, score) = if matches!(language, "ja" | "zh") {
+            // Character-type mix: kanji-dense, long sentences read harder
+            let kanji_ratio = self.sentence_kanji as f32 / self.sentence_chars.max(1) as f32;
+            let avg_sentence_length = self.sentence_chars as f32 / sentence_count as f32;
+            let score = 100.0
+                - (kanji_ratio - 0.2).max(0.0) * 150.0
+                - (avg_sentence_length - 40.0).max(0.0) * 0.8;
+            (avg_sentence_length, score)
+        } else {
+            // Flesch reading ease over whitespace-separated words
+            if self.words == 0 {
+                return easy(0.0);
+            }
+            let avg_sentence_length = self.words as f32 / sentence_count as f32;
+            let score = 206.835
+                - 1.015 * avg_sentence_length
+                - 84.6 * (self.syllables as f32 / self.words as f32);
+            (avg_sentence_length, score)
+        };
+        ReadabilityStats {
+            sentence_count,
+            avg_sentence_length,
+            score: score.clamp(0.0, 100.0),
+        }
+    }
+
+    fn language_shares(&self) -> (Vec<LanguageShare>, bool) {
+        self.languages.shares()
+    }
+
+    fn topic_keywords(&self) -> Vec<String> {
+        self.topics.most_frequent()
+    }
+}
+
+/// Share of prose per language, weighted by estimated tokens so dense CJK
+/// text is not undercounted against Latin text. Language is identified
+/// once per segment (a paragraph of one script), not per line; past
+/// `MAX_LANGUAGE_SEGMENTS` a segment reuses the last language seen for its
+/// script. Fenced code is skipped since identifiers read as English.
+#[derive(Default)]
+struct LanguageTally {
+    counts: Vec<(String, usize)>,
+    detections: usize,
+    /// The last language seen per script: [latin, cjk].
+    last_language: [Option<String>; 2],
+    segment: String,
+    segment_cjk: bool,
+    in_fence: bool,
+}
+
+impl LanguageTally {
+    fn push(&mut self, trimmed: &str) {
+        if trimmed.starts_with("```") {
+            self.in_fence = !self.in_fence;
+            self.add_segment();
+            return;
+        }
+        if self.in_fence || trimmed.is_empty() {
+            self.add_segment();
+            return;
+        }
+        let cjk = StructureAnalyzer::has_cjk(trimmed);
+        if cjk != self.segment_cjk {
+            self.add_segment();
+            self.segment_cjk = cjk;
+        }
+        self.segment.push_str(trimmed);
+        self.segment.push('\n');
+    }
+
+    fn add_segment(&mut self) {
+        if self.segment.chars().any(|c| c.is_alphabetic()) {
+            let weight = TokenizerFamily::Generic.estimate(&self.segment);
+            let cjk = StructureAnalyzer::has_cjk(&self.segment);
+            let language = match &self.last_language[cjk as usize] {
+                Some(language) if self.detections >= MAX_LANGUAGE_SEGMENTS => language.clone(),
+                _ => {
+                    self.detections += 1;
+                    StructureAnalyzer::detect_language(&self.segment, cjk)
+                }
+            };
+            self.last_language[cjk as usize] = Some(language.clone());
+            match self.counts.iter_mut().find(|(l, _)| *l == language) {
+                Some(entry) => entry.1 += weight,
+                None => self.counts.push((language, weight)),
+            }
+        }
+        self.segment.clear();
+    }
+
+    /// The shares, largest first, and whether two languages each have a
+    /// sentence's worth of tokens.
+    fn shares(&self) -> (Vec<LanguageShare>, bool) {
+        let total: usize = self.counts.iter().map(|(_, n)| n).sum();
+        let code_switching = self
+            .counts
+            .iter()
+            .filter(|(l, n)| *n >= CODE_SWITCH_MIN_TOKENS && l != "und")
+            .count()
+            >= 2;
+        let mut shares: Vec<LanguageShare> = self
+            .counts
+            .iter()
+            .map(|(language, n)| LanguageShare {
+                language: language.clone(),
+                share: *n as f32 / total as f32,
+            })
+            .collect();
+        shares.sort_by(|a, b| b.share.total_cmp(&a.share));
+        (shares, code_switching)
+    }
+}
+
+/// Content terms, in order of appearance. Latin text is split into words
+/// minus stop words; Japanese uses katakana runs and kanji runs, with long
+/// kanji runs broken into character bigrams in place of a morphological
+/// tokenizer.
+#[derive(Default)]
+struct TopicTerms {
+    terms: Vec<String>,
+    word: String,
+    run: String,
+}
+
+impl TopicTerms {
+    fn is_kanji(c: char) -> bool {
+        ('\u{4E00}'..='\u{9FFF}').contains(&c)
+    }
+
+    fn is_katakana(c: char) -> bool {
+        ('\u{30A0}'..='\u{30FF}').contains(&c) && c != '・'
+    }
+
+    /// The next piece of the lowercased text.
+    fn push(&mut self, lower: &str) {
+        for c in lower.chars() {
+            if c.is_ascii_alphanumeric() || matches!(c, '\'' | '-' | '_') {
+                self.word.push(c);
+                continue;
+            }
+            self.flush_word();
+            let same_script = self.run.chars().next().is_some_and(|first| {
+                (Self::is_kanji(first) && Self::is_kanji(c))
+                    || (Self::is_katakana(first) && Self::is_katakana(c))
+            });
+            if !same_script {
+                self.flush_run();
+            }
+            if Self::is_kanji(c) || Self::is_katakana(c) {
+                self.run.push(c);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.flush_word();
+        self.flush_run();
+    }
+
+    fn flush_word(&mut self) {
+        const STOP_WORDS: &[&str] = &[
+            "the", "and", "for", "are", "but", "not", "you", "your", "all", "can", "had", "her",
+            "was", "one", "our", "out", "has", "have", "this", "that", "with", "from", "they",
+            "will", "would", "there", "their", "what", "about", "which", "when", "make", "like",
+            "just", "into", "than", "then", "them", "some", "could", "other", "also", "how", "why",
+            "please", "thanks", "does", "did", "been", "being", "were", "here", "where", "these",
+            "those", "only", "very", "more", "most", "any", "its", "it's", "i'm", "don't", "can't",
+            "doesn't", "should", "need", "want", "get", "use", "using",
+        ];
+        let w = self.word.trim_matches(['\'', '-', '_']);
+        if w.chars().count() >= 3
+            && !w.chars().all(|c| c.is_ascii_digit())
+            && !STOP_WORDS.contains(&w)
+        {
+            self.terms.push(w.to_string());
+        }
+        self.word.clear();
+    }
+
+    fn flush_run(&mut self) {
+        let chars: Vec<char> = self.run.chars().collect();
+        if chars.len() > 4 && chars.iter().all(|&c| Self::is_kanji(c)) {
+            self.terms
+                .extend(chars.windows(2).map(|w| w.iter().collect::<String>()));
+        } else if chars.len() >= 2 {
+            self.terms.push(self.run.clone());
+        }
+        self.run.clear();
+    }
+
+    /// The most frequent terms, ties broken by first appearance.
+    fn most_frequent(&self) -> Vec<String> {
+        let mut index: HashMap<&str, usize> = HashMap::new();
+        let mut counts: Vec<(&str, usize, usize)> = Vec::new();
+        for (i, term) in self.terms.iter().enumerate() {
+            match index.get(term.as_str()) {
+                Some(&slot) => counts[slot].1 += 1,
+                None => {
+                    index.insert(term, counts.len());
+                    counts.push((term, 1, i));
+                }
+            }
+        }
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)));
+        counts
+            .into_iter()
+            .take(MAX_TOPIC_KEYWORDS)
+            .map(|(term, _, _)| term.to_string())
+            .collect()
+    }
+}
+
+struct ReadabilityStats {
+    sentence_count: usize,
+    avg_sentence_length: f32,
+    score: f32,
+}
+
+struct SentimentStats {
+    exclamation_density: f32,
+    caps_word_ratio: f32,
+    negative_word_count: usize,
+    frustration_score: f32,
+}
+
+#[derive(Default)]
+struct MarkdownStats {
+    header_count: usize,
+    header_levels: Vec<u8>,
+    has_table: bool,
+    link_count: usize,
+    blockquote_lines: usize,
+    code_text: String,
+    fence_language: Option<String>,
 }
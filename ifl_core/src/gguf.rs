@@ -0,0 +1,427 @@
+use crate::backend::{ChatRequest, LlmBackend, TokenSender};
+use async_trait::async_trait;
+use candle_core::quantized::gguf_file;
+use candle_core::{Device, Tensor};
+use candle_transformers::generation::{LogitsProcessor, Sampling as Strategy};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use candle_transformers::utils::apply_repeat_penalty;
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokenizers::Tokenizer;
+
+/// Tokens looked back on when penalizing repetition.
+const REPEAT_LAST_N: usize = 64;
+
+/// How chat turns are laid out for the model, with the token ending a turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptFormat {
+    /// `<|start_header_id|>role<|end_header_id|> ... <|eot_id|>`
+    Llama3,
+    /// `<|im_start|>role ... <|im_end|>` (many fine-tunes)
+    ChatMl,
+    /// `[INST] ... [/INST]` (Mistral, Llama 2)
+    Inst,
+}
+
+impl PromptFormat {
+    /// The format whose turn markers the tokenizer knows.
+    pub fn detect(tokenizer: &Tokenizer) -> Self {
+        if tokenizer.token_to_id("<|eot_id|>").is_some() {
+            PromptFormat::Llama3
+        } else if tokenizer.token_to_id("<|im_end|>").is_some() {
+            PromptFormat::ChatMl
+        } else {
+            PromptFormat::Inst
+        }
+    }
+
+    fn end_of_turn(self) -> &'static str {
+        match self {
+            PromptFormat::Llama3 => "<|eot_id|>",
+            PromptFormat::ChatMl => "<|im_end|>",
+            PromptFormat::Inst => "</s>",
+        }
+    }
+
+    /// Tokens that end the reply: the end of turn and the end of text.
+    fn stop_tokens(self, tokenizer: &Tokenizer) -> Vec<u32> {
+        [self.end_of_turn(), "<|end_of_text|>", "</s>"]
+            .iter()
+            .filter_map(|token| tokenizer.token_to_id(token))
+            .collect()
+    }
+
+    fn render(self, request: &ChatRequest) -> String {
+        let mut prompt = String::new();
+        match self {
+            PromptFormat::Llama3 => {
+                prompt.push_str("<|begin_of_text|>");
+                for message in &request.messages {
+                    prompt.push_str(&format!(
+                        "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                        message.role, message.content
+                    ));
+                }
+                prompt.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+            }
+            PromptFormat::ChatMl => {
+                for message in &request.messages {
+                    prompt.push_str(&format!(
+                        "<|im_start|>{}\n{}<|im_end|>\n",
+                        message.role, message.content
+                    ));
+                }
+                prompt.push_str("<|im_start|>assistant\n");
+            }
+            PromptFormat::Inst => {
+                // No system role: the system prompt leads the first instruction
+                let mut pending_system = String::new();
+                prompt.push_str("<s>");
+                for message in &request.messages {
+                    match message.role.as_str() {
+                        "system" => {
+                            pending_system.push_str(&message.content);
+                            pending_system.push_str("\n\n");
+                        }
+                        "assistant" => prompt.push_str(&format!("{}</s>", message.content)),
+                        _ => {
+                            prompt.push_str(&format!(
+                                "[INST] {}{} [/INST]",
+                                std::mem::take(&mut pending_system),
+                                message.content
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        prompt
+    }
+}
+
+/// Sampling settings of one generation.
+#[derive(Debug, Clone, Copy)]
+struct Sampling {
+    max_tokens: usize,
+    temperature: f64,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    repeat_penalty: f32,
+    seed: u64,
+}
+
+/// A model loaded in this process; one generation at a time.
+struct LoadedModel {
+    weights: Mutex<ModelWeights>,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl LoadedModel {
+    /// Generate a reply to `prompt`, sending each piece of text to `tokens`.
+    /// Ends before the first of the `stop` strings, and early once
+    /// `abandoned` is set.
+    fn generate(
+        &self,
+        prompt: &str,
+        sampling: Sampling,
+        stop_tokens: &[u32],
+        stop: &[String],
+        tokens: Option<&TokenSender>,
+        abandoned: &AtomicBool,
+    ) -> Result<String, String> {
+        let mut weights = self
+            .weights
+            .lock()
+            .map_err(|_| "Mutex poisoned".to_string())?;
+        let prompt_ids = self
+            .tokenizer
+            .encode(prompt, false)
+            .map_err(|e| e.to_string())?
+            .get_ids()
+            .to_vec();
+        let temperature = sampling.temperature;
+        let strategy = match (sampling.top_k, sampling.top_p) {
+            _ if temperature < 1e-7 => Strategy::ArgMax,
+            (None, None) => Strategy::All { temperature },
+            (Some(k), None) => Strategy::TopK { k, temperature },
+            (None, Some(p)) => Strategy::TopP { p, temperature },
+            (Some(k), Some(p)) => Strategy::TopKThenTopP { k, p, temperature },
+        };
+        let mut logits_processor = LogitsProcessor::from_sampling(sampling.seed, strategy);
+
+        let mut generated: Vec<u32> = Vec::new();
+        let mut text = String::new();
+        let mut input = prompt_ids;
+        // Position 0 replaces the cache left by the previous request
+        let mut position = 0;
+        for _ in 0..sampling.max_tokens {
+            if abandoned.load(Ordering::Relaxed) {
+                return Err("Generation cancelled".to_string());
+            }
+            let logits = Tensor::new(input.as_slice(), &self.device)
+                .and_then(|t| t.unsqueeze(0))
+                .and_then(|t| weights.forward(&t, position))
+                .and_then(|t| t.squeeze(0))
+                .map_err(|e| e.to_string())?;
+            position += input.len();
+            let logits = if sampling.repeat_penalty == 1.0 {
+                logits
+            } else {
+                let recent = &generated[generated.len().saturating_sub(REPEAT_LAST_N)..];
+                apply_repeat_penalty(&logits, sampling.repeat_penalty, recent)
+                    .map_err(|e| e.to_string())?
+            };
+            let next = logits_processor
+                .sample(&logits)
+                .map_err(|e| e.to_string())?;
+            if stop_tokens.contains(&next) {
+                break;
+            }
+            generated.push(next);
+            input = vec![next];
+
+            // Decode everything so far; multi-byte characters span tokens
+            let mut decoded = self
+                .tokenizer
+                .decode(&generated, true)
+                .map_err(|e| e.to_string())?;
+            if let Some(end) = stop_position(&decoded, stop) {
+                decoded.truncate(end);
+                if let (Some(tokens), Some(piece)) = (tokens, decoded.get(text.len()..)) {
+                    if !piece.is_empty() {
+                        let _ = tokens.send(piece.to_string());
+                    }
+                }
+                return Ok(decoded);
+            }
+            // Hold back what may be the start of a stop string
+            let ready = decoded.len() - partial_stop(&decoded, stop);
+            if ready > text.len() && !decoded[..ready].ends_with('\u{FFFD}') {
+                if let (Some(tokens), Some(piece)) = (tokens, decoded.get(text.len()..ready)) {
+                    let _ = tokens.send(piece.to_string());
+                }
+                text = decoded[..ready].to_string();
+            }
+        }
+        let decoded = self
+            .tokenizer
+            .decode(&generated, true)
+            .map_err(|e| e.to_string())?;
+        if let (Some(tokens), Some(piece)) = (tokens, decoded.get(text.len()..)) {
+            if !piece.is_empty() {
+                let _ = tokens.send(piece.to_string());
+            }
+        }
+        Ok(decoded)
+    }
+}
+
+/// Runs a quantized GGUF model in-process with candle, so no LLM server is
+/// needed. Supports the `llama` architecture (Llama 2/3, Mistral and their
+/// fine-tunes); the tokenizer comes from the model's `tokenizer.json`.
+///
+/// Generation is CPU-bound and runs on tokio's blocking pool.
+pub struct GgufBackend {
+    name: String,
+    model: Arc<LoadedModel>,
+    format: PromptFormat,
+    stop_tokens: Vec<u32>,
+    sampling: Sampling,
+}
+
+impl GgufBackend {
+    pub fn load(model_path: &str, tokenizer_path: &str) -> Result<Self, String> {
+        let mut file = std::fs::File::open(model_path)
+            .map_err(|e| format!("Cannot open model {}: {}", model_path, e))?;
+        let content = gguf_file::Content::read(&mut file)
+            .map_err(|e| format!("{} is not a GGUF model: {}", model_path, e))?;
+        let architecture = content
+            .metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .cloned()
+            .unwrap_or_default();
+        if architecture != "llama" {
+            return Err(format!(
+                "Unsupported model architecture '{}'; only llama GGUF models load in-process",
+                architecture
+            ));
+        }
+        let name = content
+            .metadata
+            .get("general.name")
+            .and_then(|v| v.to_string().ok())
+            .cloned()
+            .or_else(|| {
+                Path::new(model_path)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+            })
+            .unwrap_or_else(|| "gguf".to_string());
+
+        let device = Device::Cpu;
+        let weights =
+            ModelWeights::from_gguf(content, &mut file, &device).map_err(|e| e.to_string())?;
+        let tokenizer = Tokenizer::from_file(tokenizer_path)
+            .map_err(|e| format!("Cannot load tokenizer {}: {}", tokenizer_path, e))?;
+        let format = PromptFormat::detect(&tokenizer);
+        let stop_tokens = format.stop_tokens(&tokenizer);
+
+        Ok(Self {
+            name,
+            model: Arc::new(LoadedModel {
+                weights: Mutex::new(weights),
+                tokenizer,
+                device,
+            }),
+            format,
+            stop_tokens,
+            sampling: Sampling {
+                max_tokens: 1024,
+                temperature: 0.7,
+                top_k: None,
+                top_p: Some(0.9),
+                repeat_penalty: 1.1,
+                seed: 42,
+            },
+        })
+    }
+
+    /// Override the chat layout detected from the tokenizer.
+    pub fn with_prompt_format(mut self, format: PromptFormat) -> Self {
+        self.format = format;
+        self.stop_tokens = format.stop_tokens(&self.model.tokenizer);
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.sampling.max_tokens = max_tokens;
+        self
+    }
+
+    /// 0 always picks the most likely token.
+    pub fn with_temperature(mut self, temperature: f64) -> Self {
+        self.sampling.temperature = temperature;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.sampling.seed = seed;
+        self
+    }
+
+    pub fn prompt_format(&self) -> PromptFormat {
+        self.format
+    }
+
+    async fn run(
+        &self,
+        request: &ChatRequest,
+        tokens: Option<TokenSender>,
+    ) -> Result<String, Box<dyn Error>> {
+        let model = Arc::clone(&self.model);
+        let prompt = self.format.render(request);
+        let mut sampling = self.sampling;
+        if let Some(temperature) = request.sampling.temperature {
+            sampling.temperature = temperature as f64;
+        }
+        if let Some(top_p) = request.options.top_p.or(request.sampling.top_p) {
+            sampling.top_p = Some(top_p as f64);
+        }
+        if let Some(max_tokens) = request.sampling.max_tokens {
+            sampling.max_tokens = max_tokens as usize;
+        }
+        if let Some(top_k) = request.options.top_k {
+            sampling.top_k = Some(top_k as usize);
+        }
+        if let Some(seed) = request.options.seed {
+            sampling.seed = seed;
+        }
+        let stop_tokens = self.stop_tokens.clone();
+        let stop = request.options.stop.clone();
+        // The blocking task outlives this future; if the caller drops it,
+        // the guard tells the task to stop
+        let guard = Abandon::default();
+        let abandoned = Arc::clone(&guard.0);
+        let result = tokio::task::spawn_blocking(move || {
+            model.generate(
+                &prompt,
+                sampling,
+                &stop_tokens,
+                &stop,
+                tokens.as_ref(),
+                &abandoned,
+            )
+        })
+        .await?;
+        Ok(result?)
+    }
+}
+
+/// Where the first of the `stop` strings begins in `text`.
+fn stop_position(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| text.find(s.as_str()))
+        .min()
+}
+
+/// Length of the longest end of `text` that begins one of the `stop`
+/// strings.
+fn partial_stop(text: &str, stop: &[String]) -> usize {
+    stop.iter()
+        .flat_map(|s| {
+            s.char_indices()
+                .skip(1)
+                .map(|(i, _)| &s[..i])
+                .filter(|prefix| text.ends_with(prefix))
+                .map(str::len)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Sets its flag when dropped.
+#[derive(Default)]
+struct Abandon(Arc<AtomicBool>);
+
+impl Drop for Abandon {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl LlmBackend for GgufBackend {
+    async fn chat(&self, request: &ChatRequest) -> Result<String, Box<dyn Error>> {
+        self.run(request, None).await
+    }
+
+    async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        tokens: TokenSender,
+    ) -> Result<String, Box<dyn Error>> {
+        self.run(request, Some(tokens)).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(vec![self.name.clone()])
+    }
+
+    /// Loading already validated the model.
+    async fn health(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        "gguf"
+    }
+}
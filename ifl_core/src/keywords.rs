@@ -0,0 +1,113 @@
+use crate::profile::RequestKind;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+const DEFAULT_KEYWORDS: &str = include_str!("../config/keywords.toml");
+
+/// Request keywords for one language.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LanguageKeywords {
+    /// Phrases that make a message command-like when it starts with them.
+    #[serde(default)]
+    pub command_prefixes: Vec<String>,
+    /// Phrases that make a message command-like anywhere in the text.
+    #[serde(default)]
+    pub command_markers: Vec<String>,
+    #[serde(default)]
+    pub summarize: Vec<String>,
+    #[serde(default)]
+    pub implement: Vec<String>,
+    #[serde(default)]
+    pub translate: Vec<String>,
+    #[serde(default)]
+    pub explain: Vec<String>,
+    #[serde(default)]
+    pub fix: Vec<String>,
+    #[serde(default)]
+    pub review: Vec<String>,
+    #[serde(default)]
+    pub compare: Vec<String>,
+    #[serde(default)]
+    pub brainstorm: Vec<String>,
+    #[serde(default)]
+    pub confirm: Vec<String>,
+}
+
+impl LanguageKeywords {
+    fn for_kind(&self, kind: RequestKind) -> &[String] {
+        match kind {
+            RequestKind::Summarize => &self.summarize,
+            RequestKind::Implement => &self.implement,
+            RequestKind::Translate => &self.translate,
+            RequestKind::Explain => &self.explain,
+            RequestKind::Fix => &self.fix,
+            RequestKind::Review => &self.review,
+            RequestKind::Compare => &self.compare,
+            RequestKind::Brainstorm => &self.brainstorm,
+            RequestKind::Confirm => &self.confirm,
+        }
+    }
+}
+
+/// Per-language keyword dictionaries keyed by ISO 639-1 code.
+///
+/// Every language is checked regardless of the detected language, so mixed
+/// messages (Japanese framing around an English request) still match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct KeywordDictionary {
+    pub languages: BTreeMap<String, LanguageKeywords>,
+}
+
+impl Default for KeywordDictionary {
+    fn default() -> Self {
+        Self::builtin().clone()
+    }
+}
+
+impl KeywordDictionary {
+    /// The dictionary shipped in `config/keywords.toml`.
+    pub fn builtin() -> &'static KeywordDictionary {
+        static BUILTIN: OnceLock<KeywordDictionary> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            KeywordDictionary::from_toml_str(DEFAULT_KEYWORDS)
+                .expect("built-in keyword dictionary must parse")
+        })
+    }
+
+    pub fn from_toml_str(toml_str: &str) -> Result<Self, String> {
+        toml::from_str(toml_str).map_err(|e| e.to_string())
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_toml_str(&content)
+    }
+
+    pub fn is_command_like(&self, lower_text: &str) -> bool {
+        let trimmed = lower_text.trim_start();
+        self.languages.values().any(|lang| {
+            lang.command_prefixes
+                .iter()
+                .any(|p| trimmed.starts_with(&p.to_lowercase()))
+                || lang
+                    .command_markers
+                    .iter()
+                    .any(|m| lower_text.contains(&m.to_lowercase()))
+        })
+    }
+
+    pub fn requested_actions(&self, lower_text: &str) -> Vec<RequestKind> {
+        RequestKind::ALL
+            .into_iter()
+            .filter(|&kind| {
+                self.languages.values().any(|lang| {
+                    lang.for_kind(kind)
+                        .iter()
+                        .any(|k| lower_text.contains(&k.to_lowercase()))
+                })
+            })
+            .collect()
+    }
+}
@@ -1,11 +1,34 @@
 pub mod api;
+pub mod backend;
+pub mod baseline;
+pub mod cache;
+pub mod calibration;
+pub mod classifier;
 pub mod event;
 pub mod feature;
+#[cfg(feature = "gguf")]
+pub mod gguf;
+pub mod keywords;
 pub mod llm_client;
+#[cfg(feature = "ml")]
+pub mod ml;
+pub mod pii;
+pub mod postprocess;
+pub mod presets;
 pub mod profile;
+pub mod prompt_log;
+pub mod rephrase;
+pub mod retrieval;
 pub mod rules;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod server;
+pub mod simulate;
+pub mod tokens;
+pub mod tools;
 
 pub use api::IflCore;
+pub use baseline::UserBaseline;
 pub use event::DeleteKind;
 pub use event::InputEvent;
 pub use profile::InputProfile;
@@ -0,0 +1,305 @@
+use crate::profile::{
+    AnswerMode, DepthHint, PragmaticIntent, ScopeHint, TagConfidence, TagDimension, TagEffect,
+    ToneHint, UserState,
+};
+use crate::rules::{feature_number, Features, Rule, NAMESPACES};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tract_onnx::prelude::{
+    tvec, DatumExt, Framework, InferenceModelExt, IntoTValue, Tensor, TypedModel,
+    TypedRunnableModel,
+};
+
+/// The tag a model head predicts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "dimension", content = "value", rename_all = "snake_case")]
+pub enum MlTarget {
+    Mode(AnswerMode),
+    Tone(ToneHint),
+    Depth(DepthHint),
+    Scope(ScopeHint),
+    State(UserState),
+    Intent(PragmaticIntent),
+}
+
+/// One logistic-regression head: `sigmoid(weights · x + bias)`. The heads
+/// of an ONNX model have no weights; the graph gives their probabilities.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MlHead {
+    pub target: MlTarget,
+    #[serde(default)]
+    pub weights: Vec<f32>,
+    #[serde(default)]
+    pub bias: f32,
+}
+
+/// A linear model over the extracted features, as exported from a
+/// logistic-regression trainer (linfa-logistic, scikit-learn, ...), or the
+/// description of an ONNX model's inputs and outputs.
+///
+/// `features` are dotted paths as in rule conditions; inputs are standardized
+/// with `means`/`scales` when given.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MlModel {
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub means: Vec<f32>,
+    #[serde(default)]
+    pub scales: Vec<f32>,
+    /// Heads below this probability are ignored.
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    pub heads: Vec<MlHead>,
+}
+
+fn default_threshold() -> f32 {
+    0.5
+}
+
+/// Tags predicted by a trained model instead of handcrafted thresholds.
+///
+/// Runs as a [`Rule`]: alone (`Engine::Ml`) or after the rule set
+/// (`Engine::Hybrid`). Modes get the head probability as their score; tone,
+/// depth and scope take the most probable head; the margin over the threshold
+/// is added to the dimension's confidence.
+#[derive(Debug, Clone)]
+pub struct MlRuleEngine {
+    model: MlModel,
+    graph: Option<Arc<OnnxGraph>>,
+}
+
+/// An ONNX graph taking the standardized feature vector as a `[1, n]` f32
+/// tensor and giving one probability per head, in head order.
+struct OnnxGraph(TypedRunnableModel<TypedModel>);
+
+impl std::fmt::Debug for OnnxGraph {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OnnxGraph")
+    }
+}
+
+impl OnnxGraph {
+    fn load(bytes: &[u8], inputs: usize) -> Result<Self, String> {
+        let plan = tract_onnx::onnx()
+            .model_for_read(&mut &bytes[..])
+            .and_then(|model| model.with_input_fact(0, f32::fact([1, inputs]).into()))
+            .and_then(|model| model.into_optimized())
+            .and_then(|model| model.into_runnable())
+            .map_err(|e| format!("Cannot load ONNX model: {}", e))?;
+        Ok(Self(plan))
+    }
+
+    fn run(&self, x: &[f32]) -> Result<Vec<f32>, String> {
+        let input = Tensor::from_shape(&[1, x.len()], x).map_err(|e| e.to_string())?;
+        let outputs = self
+            .0
+            .run(tvec!(input.into_tvalue()))
+            .map_err(|e| e.to_string())?;
+        let output = outputs.first().ok_or("ONNX model has no output")?;
+        let probabilities = output.as_slice::<f32>().map_err(|e| e.to_string())?;
+        Ok(probabilities.to_vec())
+    }
+}
+
+impl MlRuleEngine {
+    pub fn new(model: MlModel) -> Result<Self, String> {
+        let n = model.features.len();
+        Self::check_inputs(&model)?;
+        if let Some(head) = model.heads.iter().find(|h| h.weights.len() != n) {
+            return Err(format!(
+                "Head {:?} has {} weights, expected {}",
+                head.target,
+                head.weights.len(),
+                n
+            ));
+        }
+        Ok(Self { model, graph: None })
+    }
+
+    /// An ONNX model (`onnx` holds the file's bytes) described by `model`,
+    /// whose heads carry no weights. One inference is run to check that
+    /// the graph gives a probability for every head.
+    pub fn from_onnx(onnx: &[u8], model: MlModel) -> Result<Self, String> {
+        Self::check_inputs(&model)?;
+        if let Some(head) = model.heads.iter().find(|h| !h.weights.is_empty()) {
+            return Err(format!(
+                "Head {:?} has weights; an ONNX model computes its own",
+                head.target
+            ));
+        }
+        let graph = OnnxGraph::load(onnx, model.features.len())?;
+        let outputs = graph.run(&vec![0.0; model.features.len()])?.len();
+        if outputs != model.heads.len() {
+            return Err(format!(
+                "ONNX model gives {} outputs, expected one per head ({})",
+                outputs,
+                model.heads.len()
+            ));
+        }
+        Ok(Self {
+            model,
+            graph: Some(Arc::new(graph)),
+        })
+    }
+
+    /// `from_onnx` with the model at `onnx_path` and its description, as
+    /// JSON, at `model_path`.
+    pub fn from_onnx_file(onnx_path: &str, model_path: &str) -> Result<Self, String> {
+        let onnx = std::fs::read(onnx_path).map_err(|e| e.to_string())?;
+        let content = std::fs::read_to_string(model_path).map_err(|e| e.to_string())?;
+        let model: MlModel = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+        Self::from_onnx(&onnx, model)
+    }
+
+    /// Known feature paths, and standardization for each of them.
+    fn check_inputs(model: &MlModel) -> Result<(), String> {
+        let n = model.features.len();
+        if let Some(feature) = model
+            .features
+            .iter()
+            .find(|f| !NAMESPACES.contains(&f.split('.').next().unwrap_or_default()))
+        {
+            return Err(format!("Unknown model feature '{}'", feature));
+        }
+        if (!model.means.is_empty() && model.means.len() != n)
+            || (!model.scales.is_empty() && model.scales.len() != n)
+        {
+            return Err(format!("means/scales must have {} entries", n));
+        }
+        Ok(())
+    }
+
+    pub fn from_json_str(json: &str) -> Result<Self, String> {
+        let model: MlModel = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Self::new(model)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_json_str(&content)
+    }
+
+    pub fn model(&self) -> &MlModel {
+        &self.model
+    }
+
+    /// Standardized feature vector; missing features read as the mean.
+    fn inputs(&self, features: &Features) -> Vec<f32> {
+        let context = features.to_value();
+        self.model
+            .features
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let mean = self.model.means.get(i).copied().unwrap_or(0.0);
+                let scale = self.model.scales.get(i).copied().unwrap_or(1.0);
+                let value = feature_number(&context, path).map_or(mean, |v| v as f32);
+                if scale == 0.0 {
+                    0.0
+                } else {
+                    (value - mean) / scale
+                }
+            })
+            .collect()
+    }
+
+    /// Probability of every head, in model order. An ONNX inference that
+    /// fails predicts nothing.
+    pub fn predict(&self, features: &Features) -> Vec<(MlTarget, f32)> {
+        let x = self.inputs(features);
+        let probabilities: Vec<f32> = match &self.graph {
+            Some(graph) => graph.run(&x).unwrap_or_default(),
+            None => self
+                .model
+                .heads
+                .iter()
+                .map(|head| {
+                    let z: f32 = head.weights.iter().zip(&x).map(|(w, v)| w * v).sum();
+                    1.0 / (1.0 + (-(z + head.bias)).exp())
+                })
+                .collect(),
+        };
+        self.model
+            .heads
+            .iter()
+            .zip(probabilities)
+            .map(|(head, p)| (head.target.clone(), p))
+            .collect()
+    }
+}
+
+impl Rule for MlRuleEngine {
+    fn id(&self) -> &str {
+        "ml_model"
+    }
+
+    fn description(&self) -> &str {
+        "Tags predicted by the trained model"
+    }
+
+    fn evaluate(&self, features: &Features) -> Vec<TagEffect> {
+        let threshold = self.model.threshold;
+        let mut effects = Vec::new();
+        // Tone, depth and scope are single-valued: keep the most probable head
+        let mut tone: Option<(ToneHint, f32)> = None;
+        let mut depth: Option<(DepthHint, f32)> = None;
+        let mut scope: Option<(ScopeHint, f32)> = None;
+        let mut margins = TagConfidence::uniform(0.0);
+
+        for (target, p) in self.predict(features) {
+            if p < threshold {
+                continue;
+            }
+            let dimension = match target {
+                MlTarget::Mode(mode) => {
+                    effects.push(TagEffect::AddMode(mode, p));
+                    TagDimension::Mode
+                }
+                MlTarget::Intent(intent) => {
+                    effects.push(TagEffect::AddIntent(intent));
+                    TagDimension::Mode
+                }
+                MlTarget::State(state) => {
+                    effects.push(TagEffect::AddState(state));
+                    TagDimension::UserState
+                }
+                MlTarget::Tone(hint) => {
+                    keep_best(&mut tone, hint, p);
+                    TagDimension::Tone
+                }
+                MlTarget::Depth(hint) => {
+                    keep_best(&mut depth, hint, p);
+                    TagDimension::Depth
+                }
+                MlTarget::Scope(hint) => {
+                    keep_best(&mut scope, hint, p);
+                    TagDimension::Mode
+                }
+            };
+            let margin = margins.get_mut(dimension);
+            *margin = margin.max(p - threshold);
+        }
+        effects.extend(tone.map(|(hint, _)| TagEffect::Tone(hint)));
+        effects.extend(depth.map(|(hint, _)| TagEffect::Depth(hint)));
+        effects.extend(scope.map(|(hint, _)| TagEffect::Scope(hint)));
+
+        for dimension in [
+            TagDimension::Mode,
+            TagDimension::Tone,
+            TagDimension::Depth,
+            TagDimension::UserState,
+        ] {
+            let margin = margins.get(dimension);
+            if margin > 0.0 {
+                effects.push(TagEffect::Confidence(dimension, margin));
+            }
+        }
+        effects
+    }
+}
+
+fn keep_best<T>(best: &mut Option<(T, f32)>, candidate: T, p: f32) {
+    if best.as_ref().is_none_or(|(_, best_p)| p > *best_p) {
+        *best = Some((candidate, p));
+    }
+}
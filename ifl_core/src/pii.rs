@@ -0,0 +1,191 @@
+use crate::profile::PiiCategory;
+use std::ops::Range;
+
+/// Well-known credential prefixes (OpenAI, GitHub, Slack, AWS, Google, GitLab).
+const KEY_PREFIXES: &[&str] = &[
+    "sk-", "ghp_", "gho_", "ghs_", "xoxb-", "xoxp-", "AKIA", "AIza", "glpat-",
+];
+
+/// Locate personally identifiable or secret data as byte ranges into `text`.
+pub fn find_pii(text: &str) -> Vec<(PiiCategory, Range<usize>)> {
+    let mut found = Vec::new();
+
+    for (range, token) in tokens(text) {
+        if is_email(token) {
+            found.push((PiiCategory::Email, range));
+        } else if is_api_key(token) {
+            found.push((PiiCategory::ApiKey, range));
+        }
+    }
+
+    for range in digit_runs(text) {
+        let run = &text[range.clone()];
+        let digits: Vec<u32> = run.chars().filter_map(|c| c.to_digit(10)).collect();
+        if (13..=19).contains(&digits.len()) && luhn_valid(&digits) {
+            found.push((PiiCategory::CreditCard, range));
+        } else if (10..=15).contains(&digits.len()) && looks_like_phone(run) {
+            found.push((PiiCategory::Phone, range));
+        }
+    }
+
+    found.sort_by_key(|(_, range)| range.start);
+    found
+}
+
+/// Distinct categories present in `text`, in a stable order.
+pub fn categories(text: &str) -> Vec<PiiCategory> {
+    let mut categories: Vec<PiiCategory> = find_pii(text).into_iter().map(|(c, _)| c).collect();
+    categories.sort();
+    categories.dedup();
+    categories
+}
+
+/// Replace every detected item with a `[REDACTED_<CATEGORY>]` placeholder.
+pub fn redact(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut cursor = 0;
+    for (category, range) in find_pii(text) {
+        if range.start < cursor {
+            continue; // Overlaps an earlier match
+        }
+        out.push_str(&text[cursor..range.start]);
+        out.push_str(match category {
+            PiiCategory::Email => "[REDACTED_EMAIL]",
+            PiiCategory::Phone => "[REDACTED_PHONE]",
+            PiiCategory::ApiKey => "[REDACTED_API_KEY]",
+            PiiCategory::CreditCard => "[REDACTED_CREDIT_CARD]",
+        });
+        cursor = range.end;
+    }
+    out.push_str(&text[cursor..]);
+    out
+}
+
+/// Whitespace-separated tokens with surrounding punctuation trimmed.
+fn tokens(text: &str) -> impl Iterator<Item = (Range<usize>, &str)> {
+    let mut start = None;
+    let mut spans = Vec::new();
+    for (i, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                spans.push(s..i);
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        spans.push(s..text.len());
+    }
+    spans.into_iter().filter_map(move |span| {
+        let raw = &text[span.clone()];
+        let trimmed_start = raw.trim_start_matches(['(', '<', '"', '\'', '[']);
+        let trimmed =
+            trimmed_start.trim_end_matches([')', '>', '"', '\'', ']', '.', ',', ';', ':']);
+        if trimmed.is_empty() {
+            return None;
+        }
+        let offset = span.start + (raw.len() - trimmed_start.len());
+        Some((offset..offset + trimmed.len(), trimmed))
+    })
+}
+
+fn is_email(token: &str) -> bool {
+    let Some((local, domain)) = token.split_once('@') else {
+        return false;
+    };
+    let local_ok = !local.is_empty()
+        && local
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._%+-".contains(c));
+    let tld_ok = domain.rsplit_once('.').is_some_and(|(host, tld)| {
+        !host.is_empty() && tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic())
+    });
+    local_ok && tld_ok
+}
+
+fn is_api_key(token: &str) -> bool {
+    let charset_ok = token
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !charset_ok || token.len() < 20 {
+        return false;
+    }
+    if KEY_PREFIXES.iter().any(|p| token.starts_with(p)) {
+        return true;
+    }
+    // Generic high-entropy secret: long and mixing upper, lower and digits
+    token.len() >= 32
+        && token.chars().any(|c| c.is_ascii_uppercase())
+        && token.chars().any(|c| c.is_ascii_lowercase())
+        && token.chars().any(|c| c.is_ascii_digit())
+}
+
+/// Maximal runs of digits joined by common phone/card separators.
+fn digit_runs(text: &str) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut last_digit_end = 0;
+    for (i, c) in text.char_indices() {
+        let starts_run = c.is_ascii_digit() || c == '+' || c == '(';
+        let continues_run = c.is_ascii_digit() || " -().+".contains(c);
+        match start {
+            None if starts_run => {
+                start = Some(i);
+                if c.is_ascii_digit() {
+                    last_digit_end = i + 1;
+                }
+            }
+            Some(_) if c.is_ascii_digit() => last_digit_end = i + 1,
+            Some(_) if continues_run => {}
+            Some(s) => {
+                if last_digit_end > s {
+                    runs.push(s..last_digit_end);
+                }
+                start = None;
+            }
+            None => {}
+        }
+    }
+    if let Some(s) = start {
+        if last_digit_end > s {
+            runs.push(s..last_digit_end);
+        }
+    }
+    runs
+}
+
+fn looks_like_phone(run: &str) -> bool {
+    if run.starts_with('+') || run.starts_with('(') || run.starts_with('0') {
+        return true;
+    }
+    // North American 3-3-4 grouping
+    let groups: Vec<usize> = run
+        .split([' ', '-', '.'])
+        .filter(|g| !g.is_empty())
+        .map(|g| g.len())
+        .collect();
+    groups == [3, 3, 4]
+}
+
+fn luhn_valid(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| {
+            if i % 2 == 1 {
+                let doubled = d * 2;
+                if doubled > 9 {
+                    doubled - 9
+                } else {
+                    doubled
+                }
+            } else {
+                d
+            }
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
@@ -0,0 +1,238 @@
+use crate::profile::{AnswerMode, AnswerTags, ToneHint, UserState};
+use serde::{Deserialize, Serialize};
+
+/// Openers that only delay the answer; dropped for users who want it direct.
+const PLEASANTRIES: &[&str] = &[
+    "sure",
+    "of course",
+    "certainly",
+    "absolutely",
+    "great question",
+    "good question",
+    "what a great question",
+    "happy to help",
+    "i'd be happy to help",
+    "i would be happy to help",
+    "i'd be glad to help",
+    "thanks for asking",
+    "thank you for your question",
+    "もちろんです",
+    "いい質問ですね",
+    "良い質問ですね",
+    "喜んでお手伝いします",
+    "承知しました",
+    "かしこまりました",
+];
+
+/// Enforces the selected tags on the model's answer where the prompt alone
+/// is not enough: small models often ignore "use bullet points" or "skip
+/// pleasantries". Each step can be turned off.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PostProcessor {
+    /// Turn prose into a bulleted list when `Structure` was requested.
+    pub structure_as_bullets: bool,
+    /// Answers for flowing users are cut at a sentence boundary past this
+    /// many characters; 0 keeps them whole.
+    pub flowing_max_chars: usize,
+    /// Drop "Sure!"-style openers when the tone is `Direct`.
+    pub strip_pleasantries: bool,
+}
+
+impl Default for PostProcessor {
+    fn default() -> Self {
+        Self {
+            structure_as_bullets: true,
+            flowing_max_chars: 600,
+            strip_pleasantries: true,
+        }
+    }
+}
+
+impl PostProcessor {
+    pub fn apply(&self, text: &str, tags: &AnswerTags) -> String {
+        let mut text = text.trim().to_string();
+        if self.strip_pleasantries && tags.tone_hint == ToneHint::Direct {
+            text = strip_pleasantries(&text);
+        }
+        if self.structure_as_bullets && tags.answer_mode.contains(&AnswerMode::Structure) {
+            text = to_bullets(&text);
+        }
+        if self.flowing_max_chars > 0 && tags.user_state.contains(&UserState::Flowing) {
+            text = truncate_at_sentence(&text, self.flowing_max_chars);
+        }
+        text
+    }
+}
+
+/// Remove pleasantry sentences from the start of `text`, unless nothing
+/// else is left.
+pub fn strip_pleasantries(text: &str) -> String {
+    let is_stop = |c: char| matches!(c, '.' | '!' | ',' | '。' | '！' | '、');
+    let mut rest = text.trim_start();
+    while let Some(opener) = PLEASANTRIES.iter().find(|p| {
+        rest.get(..p.len())
+            .is_some_and(|head| head.eq_ignore_ascii_case(p))
+    }) {
+        // Only a whole sentence: "Sure!" goes, "Sure enough, ..." stays
+        let after = rest[opener.len()..].trim_start_matches(' ');
+        if !after.starts_with(is_stop) {
+            break;
+        }
+        rest = after.trim_start_matches(|c: char| is_stop(c) || c.is_whitespace());
+    }
+    if rest.is_empty() {
+        return text.to_string();
+    }
+    // "Sure, here is..." leaves a lowercase start
+    let mut chars = rest.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_lowercase() => {
+            format!("{}{}", first.to_ascii_uppercase(), chars.as_str())
+        }
+        _ => rest.to_string(),
+    }
+}
+
+/// Rewrite prose as a bulleted list: one bullet per paragraph, or per
+/// sentence when it is all one paragraph. Text that already has a list,
+/// headings or code is left alone.
+pub fn to_bullets(text: &str) -> String {
+    let structured = text.lines().any(|line| {
+        let line = line.trim_start();
+        line.starts_with("- ")
+            || line.starts_with("* ")
+            || line.starts_with('#')
+            || line.starts_with("```")
+            || line
+                .split_once(". ")
+                .is_some_and(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    });
+    if structured {
+        return text.to_string();
+    }
+    let paragraphs: Vec<String> = text
+        .split("\n\n")
+        .map(|p| p.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|p| !p.is_empty())
+        .collect();
+    let items = if paragraphs.len() > 1 {
+        paragraphs
+    } else {
+        sentences(text)
+    };
+    if items.len() < 2 {
+        return text.to_string();
+    }
+    items
+        .iter()
+        .map(|item| format!("- {}", item))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Cut `text` at the last sentence end within `max_chars` characters; if
+/// the first sentence is already longer, at the last space. Fenced code is
+/// never cut: a block that does not fit is dropped whole.
+pub fn truncate_at_sentence(text: &str, max_chars: usize) -> String {
+    let Some((limit, _)) = text.char_indices().nth(max_chars) else {
+        return text.to_string();
+    };
+
+    // Byte ranges of fenced code blocks; an unclosed one runs to the end
+    let mut fences = Vec::new();
+    let mut open = None;
+    let mut position = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            match open.take() {
+                Some(start) => fences.push(start..position + line.len()),
+                None => open = Some(position),
+            }
+        }
+        position += line.len();
+    }
+    if let Some(start) = open {
+        fences.push(start..text.len());
+    }
+    let in_code = |i: usize| fences.iter().any(|fence| fence.start < i && i < fence.end);
+
+    let head = &text[..limit];
+    let sentence_end = head
+        .char_indices()
+        .filter(|&(i, c)| match c {
+            '。' | '！' | '？' => true,
+            '.' | '!' | '?' => head[i + c.len_utf8()..]
+                .chars()
+                .next()
+                .is_none_or(char::is_whitespace),
+            _ => false,
+        })
+        .map(|(i, c)| i + c.len_utf8())
+        .chain(fences.iter().map(|fence| fence.end))
+        .filter(|&cut| cut <= limit && !in_code(cut))
+        .max();
+    let cut = sentence_end.or_else(|| {
+        head.char_indices()
+            .rev()
+            .find(|&(i, c)| c.is_whitespace() && i > 0 && !in_code(i))
+            .map(|(i, _)| i)
+    });
+    match cut {
+        Some(cut) => text[..cut].trim_end().to_string(),
+        None => {
+            let end = fences
+                .iter()
+                .find(|fence| fence.start < limit && limit < fence.end)
+                .map_or(limit, |fence| fence.start);
+            format!("{}…", text[..end].trim_end())
+        }
+    }
+}
+
+/// Cut `text` like `truncate_at_sentence` so that at most `max_words`
+/// words are left.
+pub fn truncate_at_words(text: &str, max_words: usize) -> String {
+    let mut words = 0;
+    let mut in_word = false;
+    for (chars, c) in text.chars().enumerate() {
+        if c.is_whitespace() {
+            in_word = false;
+        } else if !in_word {
+            in_word = true;
+            words += 1;
+            if words > max_words {
+                return truncate_at_sentence(text, chars);
+            }
+        }
+    }
+    text.to_string()
+}
+
+/// Sentences of a paragraph, split after `.`, `!` and `?` followed by a
+/// space, and after Japanese full stops.
+fn sentences(text: &str) -> Vec<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        current.push(c);
+        let end = match c {
+            '。' | '！' | '？' => true,
+            '.' | '!' | '?' => chars.peek().is_none_or(|next| *next == ' '),
+            _ => false,
+        };
+        if end {
+            let sentence = current.trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_string());
+            }
+            current.clear();
+        }
+    }
+    if !current.trim().is_empty() {
+        sentences.push(current.trim().to_string());
+    }
+    sentences
+}
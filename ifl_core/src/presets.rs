@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+const DEFAULT_PRESETS: &str = include_str!("../config/presets.toml");
+
+/// A persona for the system prompt, such as a coding assistant or a study
+/// tutor. It takes the place of the generic opening line; the directives
+/// from the input profile follow it unchanged, so the answer still adapts
+/// to how the user wrote.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptPreset {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub persona: String,
+    /// The persona for prompts written in Japanese; `persona` if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona_ja: Option<String>,
+}
+
+impl PromptPreset {
+    pub fn new(name: &str, persona: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            description: String::new(),
+            persona: persona.to_string(),
+            persona_ja: None,
+        }
+    }
+
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = description.to_string();
+        self
+    }
+
+    pub fn with_persona_ja(mut self, persona: Option<&str>) -> Self {
+        self.persona_ja = persona.map(str::to_string);
+        self
+    }
+
+    /// The built-in preset called `name`: `coding`, `writing`, `email` or
+    /// `tutor`.
+    pub fn builtin(name: &str) -> Option<Self> {
+        PresetLibrary::builtin().get(name).cloned()
+    }
+
+    /// The persona in the language the prompt is written in.
+    pub fn persona_for(&self, japanese: bool) -> &str {
+        match &self.persona_ja {
+            Some(persona) if japanese => persona,
+            _ => &self.persona,
+        }
+    }
+}
+
+/// A set of presets to offer the user, e.g. in a persona picker.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PresetLibrary {
+    #[serde(default, rename = "preset")]
+    pub presets: Vec<PromptPreset>,
+}
+
+impl PresetLibrary {
+    /// The presets shipped in `config/presets.toml`.
+    pub fn builtin() -> &'static PresetLibrary {
+        static BUILTIN: OnceLock<PresetLibrary> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            PresetLibrary::from_toml_str(DEFAULT_PRESETS).expect("built-in presets must parse")
+        })
+    }
+
+    pub fn from_toml_str(toml_str: &str) -> Result<Self, String> {
+        let library: Self = toml::from_str(toml_str).map_err(|e| e.to_string())?;
+        for (i, preset) in library.presets.iter().enumerate() {
+            if preset.name.trim().is_empty() {
+                return Err(format!("Preset {} has no name", i + 1));
+            }
+            if library.presets[..i].iter().any(|p| p.name == preset.name) {
+                return Err(format!("Duplicate preset: {}", preset.name));
+            }
+        }
+        Ok(library)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_toml_str(&content)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PromptPreset> {
+        self.presets.iter().find(|preset| preset.name == name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.presets
+            .iter()
+            .map(|preset| preset.name.as_str())
+            .collect()
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InputProfile {
@@ -9,6 +10,17 @@ pub struct InputProfile {
     pub structure: StructureFeatures,
     pub tags: AnswerTags,
     pub ghost_text: Vec<String>,
+    pub normalized: Option<NormalizedFeatures>,
+    /// `experiment:variant` of the rule set that produced the tags, when the
+    /// session is part of a rule experiment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule_variant: Option<String>,
+    /// Set with `IflCore::set_clock_context`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<ClockContext>,
+    /// Set with `IflCore::set_rephrase_signal`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rephrase: Option<RephraseSignal>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,7 +29,31 @@ pub struct SourceFeatures {
     pub source_type: SourceType,
     pub paste_ratio: f32,
     pub paste_events: usize,
+    pub copy_events: usize,
+    pub copied_from_draft: bool,
+    pub attachment_count: usize,
+    pub attachment_bytes: usize,
+    /// Attachments that are images, pasted or dropped; they go to the model
+    /// with the message.
+    #[serde(default)]
+    pub image_count: usize,
     pub first_action: FirstAction,
+    /// The same content was already pasted earlier in the conversation.
+    pub repeated_paste: bool,
+    /// How the text was entered; speed and pause rules only apply to `Keyboard`.
+    pub input_modality: InputModality,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputModality {
+    Keyboard,
+    /// Mostly speech recognition.
+    Dictation,
+    /// Very regular, slow keystrokes, as from switch or scanning access.
+    SwitchAccess,
+    /// Many accepted word suggestions, as on on-screen keyboards.
+    OnScreenKeyboard,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -43,47 +79,393 @@ pub struct TimingFeatures {
     pub typing_bursts: usize,
     pub long_pause_count: usize,
     pub pre_submit_pause_ms: u64,
+    /// Median gap between consecutive keystrokes, over the last 256.
+    pub median_keystroke_interval_ms: u64,
+    /// Standard deviation over mean of the same keystroke gaps; near 0 for
+    /// machine-regular input.
+    pub keystroke_interval_cv: f32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EditingFeatures {
+    pub phases: Vec<PhaseSegment>,
     pub backspace_count: usize,
     pub backspace_burst_count: usize,
+    pub delete_forward_count: usize,
+    pub forward_delete_ratio: f32,
+    pub typo_corrections: usize,
+    pub typo_backspace_count: usize,
+    pub second_guessing_count: usize,
     pub undo_count: usize,
     pub redo_count: usize,
     pub selection_edit_count: usize,
     pub efficiency_score: f32,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DraftingPhase {
+    Drafting,
+    Revising,
+    Polishing,
+}
+
+/// A stretch of the session (offsets from the first event) spent in one phase.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PhaseSegment {
+    pub phase: DraftingPhase,
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+/// Graded register of the message, from imperative/plain to honorific.
+/// For Japanese this follows keigo: plain form, です・ます (teineigo), and
+/// 尊敬語・謙譲語 (sonkeigo/kenjougo).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PolitenessLevel {
+    Plain,
+    #[default]
+    Neutral,
+    Polite,
+    Honorific,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DataFormat {
+    Json,
+    Yaml,
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PiiCategory {
+    Email,
+    Phone,
+    ApiKey,
+    CreditCard,
+}
+
+/// The kind of answer a question asks for, roughly from shallow to deep.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuestionType {
+    /// "Is X supported?" or a request to confirm.
+    YesNo,
+    /// A lookup: what, which, when, where.
+    Factual,
+    /// Why/how, or a request to explain or compare.
+    Open,
+    /// Several questions in one message.
+    MultiPart,
+}
+
+/// Explicit requests recognized from the keyword dictionaries.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestKind {
+    Summarize,
+    Implement,
+    Translate,
+    Explain,
+    Fix,
+    Review,
+    Compare,
+    Brainstorm,
+    Confirm,
+}
+
+impl RequestKind {
+    pub const ALL: [RequestKind; 9] = [
+        RequestKind::Summarize,
+        RequestKind::Implement,
+        RequestKind::Translate,
+        RequestKind::Explain,
+        RequestKind::Fix,
+        RequestKind::Review,
+        RequestKind::Compare,
+        RequestKind::Brainstorm,
+        RequestKind::Confirm,
+    ];
+}
+
+/// Source/target languages (ISO 639-1) of an explicit translation request.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TranslationRequest {
+    pub source: Option<String>,
+    pub target: Option<String>,
+}
+
+/// Fraction of the message's prose (by estimated tokens) in one language.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LanguageShare {
+    pub language: String,
+    pub share: f32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstructionPosition {
+    Before,
+    After,
+}
+
+/// Short instruction found at the start or end of a long pasted body.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InstructionExcerpt {
+    pub text: String,
+    pub position: InstructionPosition,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StructureFeatures {
     pub char_count: usize,
+    pub estimated_tokens: usize,
+    /// Only a head/tail sample was analyzed (large text in a preview).
+    pub sampled: bool,
     pub line_count: usize,
     pub avg_line_length: f32,
     pub bullet_lines: usize,
     pub has_code_block: bool,
+    pub code_language: Option<String>,
+    pub code_ratio: f32,
+    pub stack_trace_detected: bool,
+    pub log_line_count: usize,
+    pub data_format: Option<DataFormat>,
+    pub diff_detected: bool,
+    pub quoted_lines: usize,
+    pub quote_ratio: f32,
+    pub math_detected: bool,
+    pub meeting_notes_detected: bool,
+    pub action_item_count: usize,
+    pub url_count: usize,
+    pub url_domains: Vec<String>,
+    pub url_ratio: f32,
+    pub sentence_count: usize,
+    /// Words per sentence, or characters per sentence for Japanese/Chinese.
+    pub avg_sentence_length: f32,
+    /// 0-100 reading ease (Flesch for alphabetic text, character-type mix for Japanese/Chinese).
+    pub readability_score: f32,
+    /// Exclamation marks per 100 characters.
+    pub exclamation_density: f32,
+    pub caps_word_ratio: f32,
+    pub negative_word_count: usize,
+    /// 0.0 (calm) to 1.0 (clearly frustrated).
+    pub frustration_score: f32,
+    pub contains_pii: bool,
+    pub pii_categories: Vec<PiiCategory>,
+    pub header_count: usize,
+    pub header_levels: Vec<u8>,
+    pub has_table: bool,
+    pub link_count: usize,
+    pub blockquote_lines: usize,
     pub question_like: bool,
+    pub question_count: usize,
+    pub question_type: Option<QuestionType>,
     pub command_like: bool,
     pub japanese_detected: bool,
+    pub language: String,
+    pub language_shares: Vec<LanguageShare>,
+    pub code_switching: bool,
+    /// Language the answer should be written in (the user's framing text).
+    pub response_language: String,
     pub request_summary: bool,
     pub request_implementation: bool,
-    pub is_polite: bool,
-    pub is_direct: bool,
+    pub requested_actions: Vec<RequestKind>,
+    pub translation: Option<TranslationRequest>,
+    pub instruction_excerpt: Option<InstructionExcerpt>,
+    pub topic_keywords: Vec<String>,
+    pub politeness: PolitenessLevel,
+}
+
+/// The message asks again what an earlier one asked, in other words; the
+/// answer to that one likely missed. Found by comparing embeddings (see
+/// `rephrase::RephraseDetector`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RephraseSignal {
+    /// Cosine similarity of the two messages' embeddings.
+    pub similarity: f32,
+    /// 1 for the previous message.
+    pub turns_ago: usize,
+}
+
+/// Wall-clock context supplied by the caller; the library never reads the
+/// system clock or time zone itself.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ClockContext {
+    /// Hour in the user's local time zone, 0-23.
+    pub local_hour: u8,
+    pub weekday: Weekday,
+    /// Since the user's previous message, by the caller's clock.
+    #[serde(default)]
+    pub since_last_message_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+/// Z-scores of raw metrics relative to the user's own baseline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NormalizedFeatures {
+    pub speed_z: f32,
+    pub backspace_rate_z: f32,
+    pub long_pause_z: f32,
+    pub baseline_samples: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct AnswerTags {
+    /// Modes ordered by score, ties in declaration order; the first is the
+    /// primary intent.
     pub answer_mode: Vec<AnswerMode>,
+    /// Score each mode accumulated from the rules that added it.
+    #[serde(default)]
+    pub mode_scores: BTreeMap<AnswerMode, f32>,
     pub scope_hint: ScopeHint,
     pub tone_hint: ToneHint,
 
     pub depth_hint: DepthHint,
+    /// Sorted, so the serialized profile is stable across runs.
     pub user_state: Vec<UserState>,
+    /// Sorted like `user_state`.
     pub pragmatic_intent: Vec<PragmaticIntent>,
-    pub confidence: f32,
+    pub confidence: TagConfidence,
+    /// Confidence is too low to commit: ask one clarifying question first.
+    #[serde(default)]
+    pub clarify_before_answering: bool,
+    /// Rules that fired, in order. Empty unless enabled with `IflCore::with_rule_trace`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rule_trace: Vec<RuleFire>,
+    /// Set when a classifier model assisted the rules (`classifier::Classifier`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub classifier: Option<ClassifierVerdict>,
+}
+
+/// What a classifier model made of the message, and where it saw the tags
+/// differently from the rules.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ClassifierVerdict {
+    pub model: String,
+    pub answer_mode: Option<AnswerMode>,
+    pub tone_hint: Option<ToneHint>,
+    #[serde(default)]
+    pub pragmatic_intent: Vec<PragmaticIntent>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disagreements: Vec<TagDisagreement>,
+}
+
+/// A tag the classifier chose differently from the rules. `adopted` when
+/// its choice replaced the rules' because they had little confidence.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "dimension", rename_all = "snake_case")]
+pub enum TagDisagreement {
+    Mode {
+        rules: Option<AnswerMode>,
+        classifier: AnswerMode,
+        adopted: bool,
+    },
+    Tone {
+        rules: ToneHint,
+        classifier: ToneHint,
+        adopted: bool,
+    },
+    /// An intent only the classifier found; intents are always added.
+    Intent { classifier: PragmaticIntent },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RuleFire {
+    pub rule_id: String,
+    pub description: String,
+    pub contribution: Vec<TagEffect>,
+    /// Rules whose tone/scope/depth hint this rule replaced.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<String>,
+    /// Effects not applied because a higher-priority rule set that hint.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suppressed: Vec<TagEffect>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+/// One change a rule makes to the answer tags.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "effect", content = "value", rename_all = "snake_case")]
+pub enum TagEffect {
+    /// Adds the score to the mode, adding the mode if needed.
+    AddMode(AnswerMode, f32),
+    RemoveMode(AnswerMode),
+    Scope(ScopeHint),
+    Tone(ToneHint),
+    Depth(DepthHint),
+    AddState(UserState),
+    AddIntent(PragmaticIntent),
+    /// Added to the confidence of one tag dimension.
+    Confidence(TagDimension, f32),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TagDimension {
+    Mode,
+    Tone,
+    Depth,
+    UserState,
+}
+
+/// How much the rules back each tag dimension, in 0.0..=1.0.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct TagConfidence {
+    pub mode: f32,
+    pub tone: f32,
+    pub depth: f32,
+    pub user_state: f32,
+}
+
+impl TagConfidence {
+    pub fn uniform(value: f32) -> Self {
+        Self {
+            mode: value,
+            tone: value,
+            depth: value,
+            user_state: value,
+        }
+    }
+
+    /// Mean over the dimensions.
+    pub fn overall(&self) -> f32 {
+        (self.mode + self.tone + self.depth + self.user_state) / 4.0
+    }
+
+    pub fn get(&self, dimension: TagDimension) -> f32 {
+        match dimension {
+            TagDimension::Mode => self.mode,
+            TagDimension::Tone => self.tone,
+            TagDimension::Depth => self.depth,
+            TagDimension::UserState => self.user_state,
+        }
+    }
+
+    pub fn get_mut(&mut self, dimension: TagDimension) -> &mut f32 {
+        match dimension {
+            TagDimension::Mode => &mut self.mode,
+            TagDimension::Tone => &mut self.tone,
+            TagDimension::Depth => &mut self.depth,
+            TagDimension::UserState => &mut self.user_state,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(rename_all = "snake_case")]
 pub enum PragmaticIntent {
     SolutionFocused,     // Just the code
@@ -91,9 +473,16 @@ pub enum PragmaticIntent {
     Debugging,           // Fix this
     ExpertiseSeeking,    // Deep dive
     AmbiguityResolution, // Clarify options
+    InformationSeeking,  // Wants to know something
+    TaskDelegation,      // Wants something done
+    Venting,             // Mostly expressing frustration
+    Brainstorming,       // Wants options, not one answer
+    Confirmation,        // Wants a yes/no check of their understanding
+    /// Application-defined intent set by a registered rule
+    Custom(String),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(rename_all = "snake_case")]
 pub enum UserState {
     Hesitant,
@@ -102,9 +491,14 @@ pub enum UserState {
     Pasting,
     Scattered,
     Focused,
+    Frustrated,
+    /// Many short bursts of questions without committing to one request
+    Exploring,
+    /// Slow but steady typing with few edits
+    Deliberate,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(rename_all = "snake_case")]
 pub enum AnswerMode {
     Summarize,
@@ -113,6 +507,13 @@ pub enum AnswerMode {
     Explore,
     Complete,
     ClarifyQuestion,
+    Debug,
+    Translate,
+    ReviewCode,
+    RespondToQuote,
+    ExtractActionItems,
+    Brainstorm,
+    Critique,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -128,7 +529,9 @@ pub enum ScopeHint {
 pub enum ToneHint {
     Direct,
     Gentle,
+    Formal,
     Neutral,
+    Calm,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -139,6 +542,20 @@ pub enum DepthHint {
     Deep,
 }
 
+/// What conversation-context rules remember about an earlier message.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TurnSummary {
+    /// Timestamp of the message's first event.
+    pub started_at: u64,
+    /// Timestamp of the message's last event.
+    pub ended_at: u64,
+    pub char_count: usize,
+    pub question_like: bool,
+    pub repeated_paste: bool,
+    pub answer_mode: Vec<AnswerMode>,
+    pub depth_hint: DepthHint,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionSnapshot {
     pub profile: InputProfile,
@@ -0,0 +1,224 @@
+use crate::event::content_hash;
+use crate::llm_client::LlmResponse;
+use crate::profile::{AnswerTags, InputProfile};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One answered message: what the model was told and what it said, with
+/// the tags that shaped the prompt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptLogEntry {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub message_id: String,
+    /// `experiment:variant` of the rule set, to compare variants.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule_variant: Option<String>,
+    pub model: String,
+    pub backend: String,
+    pub system_prompt: String,
+    /// The user's text; left out in privacy mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_text: Option<String>,
+    /// `event::content_hash` of the user's text, in hex, so repeated inputs
+    /// can be matched up without keeping them.
+    pub user_hash: String,
+    pub tags: AnswerTags,
+    pub response: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub latency_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+}
+
+/// Entries in the file, and the time of the oldest, so retention is only
+/// enforced when a limit is passed.
+#[derive(Debug, Default)]
+struct LogState {
+    count: usize,
+    oldest_ms: Option<u64>,
+}
+
+/// An opt-in record of prompts and responses, one JSON object per line, for
+/// judging whether the adapted prompts give better answers (see
+/// `LlmClient::with_prompt_log`). Nothing is written unless a client is
+/// given one.
+///
+/// In privacy mode the user's text is kept only as a hash, and the system
+/// prompt (which can quote deleted drafts) and the response go through PII
+/// redaction. Retention limits drop the oldest entries; the file may run a
+/// tenth past them between prunes.
+#[derive(Debug)]
+pub struct PromptLog {
+    path: PathBuf,
+    privacy: bool,
+    max_entries: Option<usize>,
+    max_age: Option<Duration>,
+    state: Mutex<LogState>,
+}
+
+impl PromptLog {
+    /// Log to `path`, creating it and its directory if needed. Entries
+    /// already there are kept; the retention limits apply from the next
+    /// write.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Cannot create log directory {}: {}", dir.display(), e))?;
+        }
+        let log = Self {
+            path,
+            privacy: false,
+            max_entries: None,
+            max_age: None,
+            state: Mutex::new(LogState::default()),
+        };
+        let entries = log.entries()?;
+        *log.state.lock().map_err(|e| e.to_string())? = LogState {
+            count: entries.len(),
+            oldest_ms: entries.first().map(|entry| entry.timestamp_ms),
+        };
+        Ok(log)
+    }
+
+    /// Keep only a hash of the user's text and redact PII from the rest.
+    pub fn with_privacy(mut self, privacy: bool) -> Self {
+        self.privacy = privacy;
+        self
+    }
+
+    /// Keep at most this many entries, dropping the oldest once a tenth
+    /// more (at least one) have been written.
+    pub fn with_max_entries(mut self, max_entries: Option<usize>) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Drop entries older than this, once the oldest is a tenth past it.
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.privacy
+    }
+
+    /// The entries in the file, oldest first. Lines that do not parse are
+    /// skipped.
+    pub fn entries(&self) -> Result<Vec<PromptLogEntry>, String> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Cannot read {}: {}", self.path.display(), e)),
+        };
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// Log the answer to `text`. A failed write loses the entry but never
+    /// fails the request.
+    pub fn record(
+        &self,
+        system_prompt: &str,
+        text: &str,
+        profile: &InputProfile,
+        response: &LlmResponse,
+    ) {
+        let redact = |text: &str| {
+            if self.privacy {
+                crate::pii::redact(text)
+            } else {
+                text.to_string()
+            }
+        };
+        let entry = PromptLogEntry {
+            timestamp_ms: now_ms(),
+            message_id: profile.message_id.clone(),
+            rule_variant: profile.rule_variant.clone(),
+            model: response.model.clone(),
+            backend: response.backend.clone(),
+            system_prompt: redact(system_prompt),
+            user_text: (!self.privacy).then(|| text.to_string()),
+            user_hash: format!("{:016x}", content_hash(text)),
+            tags: profile.tags.clone(),
+            response: redact(&response.content),
+            prompt_tokens: response.prompt_tokens,
+            completion_tokens: response.completion_tokens,
+            latency_ms: response.latency_ms,
+            finish_reason: response.finish_reason.clone(),
+        };
+        let _ = self.append(&entry);
+    }
+
+    fn append(&self, entry: &PromptLogEntry) -> Result<(), String> {
+        let mut state = self.state.lock().map_err(|e| e.to_string())?;
+        let line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Cannot open {}: {}", self.path.display(), e))?;
+        writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+        state.count += 1;
+        state.oldest_ms.get_or_insert(entry.timestamp_ms);
+
+        // Limits are overshot by a tenth before the file is rewritten, so a
+        // full log is pruned once per tenth of its entries, not every write
+        let too_many = self
+            .max_entries
+            .is_some_and(|max| state.count > max + (max / 10).max(1));
+        let too_old = match (self.max_age, state.oldest_ms) {
+            (Some(age), Some(oldest)) => oldest < cutoff_ms(age + age / 10),
+            _ => false,
+        };
+        if too_many || too_old {
+            *state = self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the file without the entries past the retention limits.
+    fn prune(&self) -> Result<LogState, String> {
+        let mut entries = self.entries()?;
+        if let Some(age) = self.max_age {
+            let cutoff = cutoff_ms(age);
+            entries.retain(|entry| entry.timestamp_ms >= cutoff);
+        }
+        if let Some(max) = self.max_entries {
+            let excess = entries.len().saturating_sub(max);
+            entries.drain(..excess);
+        }
+        let mut content = String::new();
+        for entry in &entries {
+            content.push_str(&serde_json::to_string(entry).map_err(|e| e.to_string())?);
+            content.push('\n');
+        }
+        let temp = self.path.with_extension("tmp");
+        std::fs::write(&temp, content)
+            .and_then(|_| std::fs::rename(&temp, &self.path))
+            .map_err(|e| format!("Cannot write {}: {}", self.path.display(), e))?;
+        Ok(LogState {
+            count: entries.len(),
+            oldest_ms: entries.first().map(|entry| entry.timestamp_ms),
+        })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Entries logged before this are older than `age`.
+fn cutoff_ms(age: Duration) -> u64 {
+    now_ms().saturating_sub(age.as_millis() as u64)
+}
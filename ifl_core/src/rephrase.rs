@@ -0,0 +1,88 @@
+use crate::llm_client::{LlmClient, LlmError};
+use crate::profile::RephraseSignal;
+use std::collections::VecDeque;
+
+/// Embedding similarity from which a message counts as the same question;
+/// with nomic-embed-text, rewordings of one question land around 0.9 and
+/// follow-ups on the same topic around 0.7.
+const DEFAULT_THRESHOLD: f32 = 0.85;
+/// Earlier messages compared against.
+const HISTORY_CAPACITY: usize = 8;
+
+/// Notices when a message asks again, in other words, what an earlier one
+/// in the conversation asked — usually because the answer missed. Compares
+/// the embeddings of the messages; the rules then see
+/// `history.rephrase.*` and the prompt asks for a different approach.
+pub struct RephraseDetector {
+    client: LlmClient,
+    threshold: f32,
+    /// Embeddings of the latest messages, oldest first.
+    history: VecDeque<Vec<f32>>,
+}
+
+impl RephraseDetector {
+    /// Embed with `client`'s backend and embedding model.
+    pub fn new(client: LlmClient) -> Self {
+        Self {
+            client,
+            threshold: DEFAULT_THRESHOLD,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Similarity needed to count as a rephrase. Tune it to the embedding
+    /// model; their scales differ.
+    pub fn with_threshold(mut self, threshold: f32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Compare `text` with the earlier messages, then remember it. Returns
+    /// the closest one at or above the threshold, the latest on a tie. Call
+    /// before `IflCore::finalize_message` and pass a signal on with
+    /// `IflCore::set_rephrase_signal`. On error the message is not
+    /// remembered.
+    pub async fn check(&mut self, text: &str) -> Result<Option<RephraseSignal>, LlmError> {
+        let embedding = self.client.embed(text).await?;
+        let signal = self
+            .history
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, earlier)| RephraseSignal {
+                similarity: cosine_similarity(&embedding, earlier),
+                turns_ago: i + 1,
+            })
+            .filter(|signal| signal.similarity >= self.threshold)
+            .max_by(|a, b| {
+                a.similarity
+                    .total_cmp(&b.similarity)
+                    .then(b.turns_ago.cmp(&a.turns_ago))
+            });
+        self.history.push_back(embedding);
+        while self.history.len() > HISTORY_CAPACITY {
+            self.history.pop_front();
+        }
+        Ok(signal)
+    }
+
+    /// Forget the earlier messages, e.g. for a new conversation.
+    pub fn clear(&mut self) {
+        self.history.clear();
+    }
+}
+
+/// Cosine of the angle between `a` and `b`: 1 for the same direction, 0
+/// when unrelated, or either is zero or they differ in length.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        return 0.0;
+    }
+    (dot / norms).clamp(-1.0, 1.0)
+}
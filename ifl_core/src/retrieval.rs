@@ -0,0 +1,201 @@
+use crate::event::content_hash;
+use crate::llm_client::LlmClient;
+use crate::profile::{AnswerMode, InputProfile, PragmaticIntent, SourceType};
+use crate::rephrase::cosine_similarity;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Target length of a snippet; paragraphs are joined up to it and longer
+/// ones split.
+const SNIPPET_CHARS: usize = 800;
+/// File types indexed.
+const EXTENSIONS: &[&str] = &["md", "markdown", "txt"];
+
+/// One passage of a document, with its embedding.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snippet {
+    /// Path of the file relative to the indexed directory, `/`-separated.
+    pub source: String,
+    pub text: String,
+    /// `event::content_hash` of the text, to reuse the embedding when the
+    /// passage has not changed.
+    pub hash: u64,
+    pub embedding: Vec<f32>,
+}
+
+/// The user's own notes and documents, split into snippets and embedded
+/// with the embeddings API, so the most relevant ones can go into the
+/// prompt (see `LlmClient::with_document_store`). Kept as one JSON file
+/// with `save` and `load`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DocumentStore {
+    /// The model the snippets were embedded with; queries must use the same.
+    pub embedding_model: String,
+    pub snippets: Vec<Snippet>,
+}
+
+impl DocumentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read document index {}: {}", path, e))?;
+        serde_json::from_str(&content).map_err(|e| format!("Invalid document index: {}", e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| format!("Cannot write {}: {}", path, e))
+    }
+
+    /// Index the Markdown and text files under `dir`, replacing what the
+    /// store held. Passages already embedded with the client's embedding
+    /// model are reused, so re-indexing after an edit only embeds what
+    /// changed. Returns how many snippets were embedded.
+    pub async fn index_dir(&mut self, client: &LlmClient, dir: &str) -> Result<usize, String> {
+        let mut files = Vec::new();
+        collect_files(Path::new(dir), &mut files)
+            .map_err(|e| format!("Cannot read {}: {}", dir, e))?;
+        files.sort();
+
+        let mut known: HashMap<u64, Vec<f32>> = HashMap::new();
+        if self.embedding_model == client.embedding_model() {
+            for snippet in self.snippets.drain(..) {
+                known.insert(snippet.hash, snippet.embedding);
+            }
+        }
+        let mut snippets = Vec::new();
+        let mut embedded = 0;
+        for file in files {
+            let content = std::fs::read_to_string(&file)
+                .map_err(|e| format!("Cannot read {}: {}", file.display(), e))?;
+            let source = file
+                .strip_prefix(dir)
+                .unwrap_or(&file)
+                .components()
+                .map(|part| part.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            for text in split_snippets(&content) {
+                let hash = content_hash(&text);
+                let embedding = match known.get(&hash) {
+                    Some(embedding) => embedding.clone(),
+                    None => {
+                        embedded += 1;
+                        client.embed(&text).await.map_err(|e| e.to_string())?
+                    }
+                };
+                snippets.push(Snippet {
+                    source: source.clone(),
+                    text,
+                    hash,
+                    embedding,
+                });
+            }
+        }
+        self.embedding_model = client.embedding_model().to_string();
+        self.snippets = snippets;
+        Ok(embedded)
+    }
+
+    /// The `k` snippets closest to `query`, most similar first, leaving out
+    /// those below `min_similarity`.
+    pub fn search(&self, query: &[f32], k: usize, min_similarity: f32) -> Vec<(&Snippet, f32)> {
+        let mut hits: Vec<(&Snippet, f32)> = self
+            .snippets
+            .iter()
+            .map(|snippet| (snippet, cosine_similarity(query, &snippet.embedding)))
+            .filter(|(_, similarity)| *similarity >= min_similarity)
+            .collect();
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+        hits.truncate(k);
+        hits
+    }
+
+    pub fn len(&self) -> usize {
+        self.snippets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snippets.is_empty()
+    }
+}
+
+/// Whether `profile` asks for knowledge the user's documents may hold: a
+/// question to answer or explain, rather than a task on text the user
+/// pasted themselves.
+pub fn wants_references(profile: &InputProfile) -> bool {
+    let tags = &profile.tags;
+    let asks = tags.pragmatic_intent.iter().any(|intent| {
+        matches!(
+            intent,
+            PragmaticIntent::InformationSeeking
+                | PragmaticIntent::ConceptExploration
+                | PragmaticIntent::ExpertiseSeeking
+        )
+    }) || matches!(
+        tags.answer_mode.first(),
+        Some(AnswerMode::ClarifyQuestion | AnswerMode::Explore)
+    );
+    asks && profile.source.source_type != SourceType::PasteOnly
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Paragraphs joined up to `SNIPPET_CHARS`; a Markdown heading starts a new
+/// snippet, and longer paragraphs are cut at sentence or word breaks.
+fn split_snippets(content: &str) -> Vec<String> {
+    let mut snippets = Vec::new();
+    let mut current = String::new();
+    for paragraph in content.split("\n\n").map(str::trim) {
+        if paragraph.is_empty() {
+            continue;
+        }
+        let heading = paragraph.starts_with('#');
+        let fits = current.chars().count() + paragraph.chars().count() + 2 <= SNIPPET_CHARS;
+        if !current.is_empty() && (heading || !fits) {
+            snippets.push(std::mem::take(&mut current));
+        }
+        let mut rest = paragraph;
+        while rest.chars().count() > SNIPPET_CHARS {
+            let limit = rest
+                .char_indices()
+                .nth(SNIPPET_CHARS)
+                .map_or(rest.len(), |(i, _)| i);
+            let head = &rest[..limit];
+            let cut = head
+                .rfind(". ")
+                .map(|i| i + 1)
+                .or_else(|| head.rfind(char::is_whitespace))
+                .filter(|&i| i > 0)
+                .unwrap_or(limit);
+            snippets.push(rest[..cut].trim().to_string());
+            rest = rest[cut..].trim_start();
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(rest);
+    }
+    if !current.is_empty() {
+        snippets.push(current);
+    }
+    snippets
+}
@@ -1,184 +1,664 @@
+use crate::baseline::UserBaseline;
+use crate::event::content_hash;
 use crate::profile::{
-    AnswerMode, AnswerTags, DepthHint, EditingFeatures, PragmaticIntent, ScopeHint, SourceFeatures,
-    SourceType, StructureFeatures, TimingFeatures, ToneHint, UserState,
+    AnswerMode, AnswerTags, ClockContext, DepthHint, EditingFeatures, NormalizedFeatures,
+    PragmaticIntent, QuestionType, RephraseSignal, RuleFire, ScopeHint, SourceFeatures,
+    StructureFeatures, TagConfidence, TimingFeatures, ToneHint, TurnSummary, UserState, Weekday,
 };
-use std::collections::HashSet;
+pub use crate::profile::{TagDimension, TagEffect};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, OnceLock};
 
-pub struct RuleEngine;
+const DEFAULT_RULES: &str = include_str!("../config/rules.toml");
 
-impl RuleEngine {
-    pub fn apply(
-        source: &SourceFeatures,
-        timing: &TimingFeatures,
-        editing: &EditingFeatures,
-        structure: &StructureFeatures,
-    ) -> AnswerTags {
-        let mut modes = HashSet::new();
-        let mut scope = ScopeHint::Narrow; // Default (was Specific)
-        let mut tone = ToneHint::Neutral; // Default
-        let mut depth = DepthHint::Normal; // Default (was Standard)
-        let mut confidence = 0.5f32; // Base confidence
-
-        // Rule 1: High paste ratio + multiple lines -> Summarize/Structure
-        if source.paste_ratio > 0.8 && structure.line_count >= 3 {
-            modes.insert(AnswerMode::Summarize);
-            modes.insert(AnswerMode::Structure);
-            scope = ScopeHint::Broad;
-            confidence += 0.2;
-        }
+/// Questions shorter than this count toward `history.short_question_streak`.
+const SHORT_QUESTION_CHARS: usize = 80;
 
-        // Rule 2: Long typed session with edits -> Refine/Clarify
-        if matches!(source.source_type, SourceType::TypedOnly)
-            && timing.total_duration_ms > 30_000
-            && editing.backspace_count > 20
-        {
-            modes.insert(AnswerMode::Refine);
-            modes.insert(AnswerMode::ClarifyQuestion);
-            depth = DepthHint::Deep; // Was Detailed
-            confidence += 0.2;
-        }
+/// Feature namespaces a condition may address.
+pub(crate) const NAMESPACES: &[&str] = &[
+    "source",
+    "timing",
+    "editing",
+    "structure",
+    "derived",
+    "baseline",
+    "history",
+    "clock",
+    "tags",
+];
 
-        // Rule 3: Short query -> Explore/Clarify
-        if structure.line_count <= 2 && structure.char_count < 40 {
-            modes.insert(AnswerMode::Explore);
-            modes.insert(AnswerMode::ClarifyQuestion);
-            scope = ScopeHint::Broad;
-            confidence += 0.1;
-        }
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Op {
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = "in")]
+    In,
+    #[serde(rename = "contains")]
+    Contains,
+    #[serde(rename = "not_contains")]
+    NotContains,
+    #[serde(rename = "exists")]
+    Exists,
+    #[serde(rename = "missing")]
+    Missing,
+}
 
-        // Rule 4: Mixed source with selection edits -> Complete
-        if matches!(source.source_type, SourceType::Mixed) && editing.selection_edit_count > 2 {
-            modes.insert(AnswerMode::Complete);
-            confidence += 0.2;
-        }
+/// A test on one feature addressed by a dotted path, e.g. `structure.line_count`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Condition {
+    pub feature: String,
+    pub op: Op,
+    #[serde(default)]
+    pub value: Value,
+}
 
-        // Rule 5: Bullet points -> Structure
-        if structure.bullet_lines > 2 {
-            modes.insert(AnswerMode::Structure);
-            scope = ScopeHint::Narrow; // Was Specific
-            confidence += 0.1;
+impl Condition {
+    fn holds(&self, actual: &Value) -> bool {
+        let expected = &self.value;
+        match self.op {
+            Op::Gt => compare(actual, expected, |a, e| a > e),
+            Op::Ge => compare(actual, expected, |a, e| a >= e),
+            Op::Lt => compare(actual, expected, |a, e| a < e),
+            Op::Le => compare(actual, expected, |a, e| a <= e),
+            Op::Eq => equals(actual, expected),
+            Op::Ne => !equals(actual, expected),
+            Op::In => expected
+                .as_array()
+                .is_some_and(|options| options.contains(actual)),
+            Op::Contains => contains(actual, expected),
+            Op::NotContains => !contains(actual, expected),
+            Op::Exists => !actual.is_null(),
+            Op::Missing => actual.is_null(),
         }
+    }
+}
 
-        // Rule 6: Question like -> Clarify/Explore
-        if structure.question_like {
-            modes.insert(AnswerMode::ClarifyQuestion);
-            confidence += 0.1;
-        }
+/// A declarative rule: conditions over features and the tag effects applied
+/// when they hold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleDef {
+    pub id: String,
+    #[serde(default)]
+    pub description: String,
+    /// Every condition must hold.
+    #[serde(default)]
+    pub when: Vec<Condition>,
+    /// At least one condition must hold (ignored when empty).
+    #[serde(default)]
+    pub any: Vec<Condition>,
+    #[serde(default)]
+    pub add_modes: Vec<AnswerMode>,
+    /// Score added to each of `add_modes`.
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+    #[serde(default)]
+    pub remove_modes: Vec<AnswerMode>,
+    #[serde(default)]
+    pub scope: Option<ScopeHint>,
+    #[serde(default)]
+    pub tone: Option<ToneHint>,
+    #[serde(default)]
+    pub depth: Option<DepthHint>,
+    #[serde(default)]
+    pub add_states: Vec<UserState>,
+    #[serde(default)]
+    pub add_intents: Vec<PragmaticIntent>,
+    /// Resolves conflicting tone/scope/depth hints: a rule replaces a hint
+    /// only if it was set by a rule of equal (earlier) or lower priority.
+    #[serde(default)]
+    pub priority: i32,
+    /// Added, when the rule fires, to the confidence of every tag dimension
+    /// the rule sets (mode if it sets none).
+    #[serde(default)]
+    pub confidence: f32,
+}
 
-        // Rule 7: Command like -> Direct tone
-        if structure.command_like {
-            tone = ToneHint::Direct; // Was Casual (Direct fits command)
-            confidence += 0.1;
-        }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleConfig {
+    #[serde(default = "default_base_confidence")]
+    pub base_confidence: f32,
+    #[serde(rename = "rule", default)]
+    pub rules: Vec<RuleDef>,
+}
+
+fn default_base_confidence() -> f32 {
+    0.5
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+/// The features of one message, as seen by a [`Rule`].
+#[derive(Debug, Clone, Copy)]
+pub struct Features<'a> {
+    pub source: &'a SourceFeatures,
+    pub timing: &'a TimingFeatures,
+    pub editing: &'a EditingFeatures,
+    pub structure: &'a StructureFeatures,
+    /// The message relative to the user's own history; `None` until the
+    /// baseline has enough samples.
+    pub normalized: Option<&'a NormalizedFeatures>,
+    /// Earlier messages of the conversation, oldest first.
+    pub history: &'a [TurnSummary],
+    /// Timestamp of this message's first event.
+    pub started_at: Option<u64>,
+    /// Local time, when the caller opted in to time-of-day rules.
+    pub clock: Option<&'a ClockContext>,
+    /// Set when the caller found the message repeats an earlier question.
+    pub rephrase: Option<&'a RephraseSignal>,
+}
+
+impl Features<'_> {
+    /// The features as JSON, addressed by the dotted paths used in rule
+    /// conditions (`source.*`, ..., `derived.*`).
+    pub fn to_value(&self) -> Value {
+        let Features {
+            source,
+            timing,
+            editing,
+            structure,
+            normalized,
+            history,
+            started_at,
+            clock,
+            rephrase,
+        } = *self;
+        let previous = history.last();
+        // Consecutive short questions immediately before this message
+        let short_question_streak = history
+            .iter()
+            .rev()
+            .take_while(|turn| turn.question_like && turn.char_count < SHORT_QUESTION_CHARS)
+            .count();
+        let pasted_chars = ((structure.char_count as f32 * source.paste_ratio).round() as usize)
+            .min(structure.char_count);
+        json!({
+            "source": source,
+            "timing": timing,
+            "editing": editing,
+            "structure": structure,
+            "derived": {
+                // Typo fixes are noise; only substantive deletions signal editing
+                "substantive_backspaces": editing
+                    .backspace_count
+                    .saturating_sub(editing.typo_backspace_count),
+                "prose": !structure.has_code_block && structure.data_format.is_none(),
+                "pasted_chars": pasted_chars,
+                "typed_chars": structure.char_count - pasted_chars,
+                "pasted_tokens": pasted_tokens(source, structure),
+                "depth_score": depth_score(source, timing, structure),
+            },
+            // Null without a baseline, so relative conditions simply don't hold
+            "baseline": normalized.map(|n| json!({
+                "samples": n.baseline_samples,
+                "speed_z": n.speed_z,
+                "speed_percentile": UserBaseline::percentile(n.speed_z),
+                "backspace_rate_z": n.backspace_rate_z,
+                "long_pause_z": n.long_pause_z,
+            })),
+            "history": {
+                "turns": history.len(),
+                "short_question_streak": short_question_streak,
+                // Null when either timestamp is unknown or the clocks disagree
+                "gap_ms": previous
+                    .zip(started_at)
+                    .and_then(|(turn, start)| start.checked_sub(turn.ended_at)),
+                "previous": previous,
+                // Null unless the caller ran rephrase detection
+                "rephrase": rephrase,
+            },
+            // Null unless the caller supplied it, so clock rules stay opt-in
+            "clock": clock.map(|c| json!({
+                "local_hour": c.local_hour,
+                "weekday": c.weekday,
+                "weekend": matches!(c.weekday, Weekday::Saturday | Weekday::Sunday),
+                "since_last_message_ms": c.since_last_message_ms,
+            })),
+        })
+    }
+}
+
+/// Estimated tokens that arrived by pasting rather than typing.
+fn pasted_tokens(source: &SourceFeatures, structure: &StructureFeatures) -> usize {
+    (structure.estimated_tokens as f32 * source.paste_ratio).round() as usize
+}
 
-        // Rule 8: Japanese specific rules
-        if structure.japanese_detected {
-            // Japanese text tends to be denser, so "Short" threshold might be lower
-            if structure.char_count > 500 {
-                depth = DepthHint::Deep; // Was Detailed
+/// How thorough an answer the message calls for, roughly -0.5 (a quick
+/// check) to 1.0 (a multi-part ask over a long document). Sums the question
+/// type, the amount of pasted material, how hard the prose reads, and how
+/// long the user reread before sending; `depth_*` rules threshold it.
+fn depth_score(
+    source: &SourceFeatures,
+    timing: &TimingFeatures,
+    structure: &StructureFeatures,
+) -> f32 {
+    let question = match structure.question_type {
+        Some(QuestionType::MultiPart) => 0.4,
+        Some(QuestionType::Open) => 0.25,
+        Some(QuestionType::Factual) | None => 0.0,
+        Some(QuestionType::YesNo) => -0.3,
+    };
+    let material = 0.3 * (pasted_tokens(source, structure) as f32 / 1000.0).min(1.0);
+    // Reading ease is noise on a single short sentence
+    let readability = if structure.sentence_count >= 2 {
+        0.2 * ((50.0 - structure.readability_score) / 50.0).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let pause = 0.2 * (timing.pre_submit_pause_ms as f32 / 15_000.0).min(1.0);
+    let brevity = if structure.char_count < 40 {
+        -0.25
+    } else {
+        0.0
+    };
+    question + material + readability + pause + brevity
+}
+
+/// A rule implemented in code, e.g. a company-specific intent. Registered
+/// rules run after the declarative rule set, in registration order.
+pub trait Rule: Send + Sync {
+    /// Unique name; registering a second rule with the same id fails.
+    fn id(&self) -> &str;
+    /// Shown next to the id in `rule_trace`.
+    fn description(&self) -> &str {
+        ""
+    }
+    /// Priority of the tone/scope/depth hints this rule sets (see `RuleDef::priority`).
+    fn priority(&self) -> i32 {
+        0
+    }
+    fn evaluate(&self, features: &Features) -> Vec<TagEffect>;
+}
+
+impl RuleDef {
+    /// The effects this rule applies when its conditions hold.
+    pub fn effects(&self) -> Vec<TagEffect> {
+        let mut effects: Vec<TagEffect> = self
+            .add_modes
+            .iter()
+            .map(|mode| TagEffect::AddMode(mode.clone(), self.weight))
+            .collect();
+        effects.extend(self.remove_modes.iter().cloned().map(TagEffect::RemoveMode));
+        effects.extend(self.scope.clone().map(TagEffect::Scope));
+        effects.extend(self.tone.clone().map(TagEffect::Tone));
+        effects.extend(self.depth.clone().map(TagEffect::Depth));
+        effects.extend(self.add_states.iter().cloned().map(TagEffect::AddState));
+        effects.extend(self.add_intents.iter().cloned().map(TagEffect::AddIntent));
+        if self.confidence != 0.0 {
+            let mut dimensions = Vec::new();
+            if !self.add_modes.is_empty() || !self.remove_modes.is_empty() {
+                dimensions.push(TagDimension::Mode);
             }
-            // Japanese Tone Detection
-            if structure.is_polite {
-                tone = ToneHint::Gentle; // Was Formal
-            } else if structure.is_direct {
-                tone = ToneHint::Direct; // Was Casual
+            if self.tone.is_some() {
+                dimensions.push(TagDimension::Tone);
             }
-            confidence += 0.1;
+            if self.depth.is_some() {
+                dimensions.push(TagDimension::Depth);
+            }
+            if !self.add_states.is_empty() {
+                dimensions.push(TagDimension::UserState);
+            }
+            if dimensions.is_empty() {
+                dimensions.push(TagDimension::Mode);
+            }
+            effects.extend(
+                dimensions
+                    .into_iter()
+                    .map(|dimension| TagEffect::Confidence(dimension, self.confidence)),
+            );
         }
+        effects
+    }
+}
+
+/// Tags accumulated while the rules run; visible to later rules as `tags.*`.
+/// A single-valued hint and the rule that set it.
+struct Hint<T> {
+    value: T,
+    priority: i32,
+    set_by: Option<String>,
+}
 
-        // Rule 9: Explicit requests
-        if structure.request_summary {
-            modes.insert(AnswerMode::Summarize);
-            scope = ScopeHint::Broad;
-            confidence += 0.3; // Explicit request is strong
+impl<T> Hint<T> {
+    fn new(value: T) -> Self {
+        Self {
+            value,
+            priority: i32::MIN,
+            set_by: None,
         }
-        if structure.request_implementation {
-            modes.insert(AnswerMode::Complete);
-            modes.insert(AnswerMode::Structure);
-            tone = ToneHint::Direct; // Was Casual
-            confidence += 0.3; // Explicit request is strong
+    }
+
+    /// Replace the value unless a higher-priority rule set it. On success,
+    /// returns the rule that was overridden, if any.
+    fn offer(&mut self, value: T, priority: i32, rule_id: &str) -> Result<Option<String>, ()> {
+        if priority < self.priority {
+            return Err(());
         }
+        self.value = value;
+        self.priority = priority;
+        Ok(self.set_by.replace(rule_id.to_string()))
+    }
+}
+
+struct TagState {
+    /// Mode scores in the order the modes were first added.
+    modes: Vec<(AnswerMode, f32)>,
+    scope: Hint<ScopeHint>,
+    tone: Hint<ToneHint>,
+    depth: Hint<DepthHint>,
+    states: BTreeSet<UserState>,
+    intents: BTreeSet<PragmaticIntent>,
+    confidence: TagConfidence,
+}
 
-        // Fallback if no modes
-        if modes.is_empty() {
-            modes.insert(AnswerMode::Explore);
+impl TagState {
+    /// `key` may address into a map, e.g. `mode_scores.summarize`.
+    fn lookup(&self, key: &str) -> Value {
+        let (key, rest) = key.split_once('.').unwrap_or((key, ""));
+        let value = match key {
+            "answer_mode" => json!(self.ranked_modes()),
+            "mode_scores" => json!(self.mode_scores()),
+            "scope_hint" => json!(self.scope.value),
+            "tone_hint" => json!(self.tone.value),
+            "depth_hint" => json!(self.depth.value),
+            "user_state" => json!(self.states),
+            "pragmatic_intent" => json!(self.intents),
+            "confidence" => json!(self.confidence),
+            _ => Value::Null,
+        };
+        if rest.is_empty() {
+            value
+        } else {
+            let pointer = format!("/{}", rest.replace('.', "/"));
+            value.pointer(&pointer).cloned().unwrap_or(Value::Null)
         }
+    }
 
-        let answer_mode: Vec<AnswerMode> = modes.clone().into_iter().collect();
+    /// Highest score first; ties in `AnswerMode` order, so the ranking does
+    /// not depend on which rule happened to add a mode first.
+    fn ranked_modes(&self) -> Vec<AnswerMode> {
+        let mut ranked = self.modes.clone();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked.into_iter().map(|(mode, _)| mode).collect()
+    }
 
-        // User State Detection
-        let mut user_states = HashSet::new();
+    fn mode_scores(&self) -> BTreeMap<AnswerMode, f32> {
+        self.modes.iter().cloned().collect()
+    }
 
-        // Hesitant: Low speed + many pauses
-        if timing.avg_chars_per_sec < 2.0 && timing.long_pause_count > 2 {
-            user_states.insert(UserState::Hesitant);
+    /// Apply a fired rule's effects. Returns the rules whose hints it replaced
+    /// and the effects that lost to a higher-priority hint.
+    fn fire(
+        &mut self,
+        rule_id: &str,
+        priority: i32,
+        effects: &[TagEffect],
+    ) -> (Vec<String>, Vec<TagEffect>) {
+        let mut overrides = Vec::new();
+        let mut suppressed = Vec::new();
+        for effect in effects {
+            let outcome = match effect.clone() {
+                TagEffect::Scope(scope) => self.scope.offer(scope, priority, rule_id),
+                TagEffect::Tone(tone) => self.tone.offer(tone, priority, rule_id),
+                TagEffect::Depth(depth) => self.depth.offer(depth, priority, rule_id),
+                other => {
+                    self.apply(other);
+                    Ok(None)
+                }
+            };
+            match outcome {
+                Ok(Some(previous)) if previous != rule_id => overrides.push(previous),
+                Ok(_) => {}
+                Err(()) => suppressed.push(effect.clone()),
+            }
         }
+        (overrides, suppressed)
+    }
 
-        // Flowing: High speed + few pauses
-        if timing.avg_chars_per_sec > 5.0 && timing.long_pause_count == 0 {
-            user_states.insert(UserState::Flowing);
+    fn apply(&mut self, effect: TagEffect) {
+        match effect {
+            TagEffect::AddMode(mode, score) => {
+                match self.modes.iter_mut().find(|(m, _)| *m == mode) {
+                    Some((_, total)) => *total += score,
+                    None => self.modes.push((mode, score)),
+                }
+            }
+            TagEffect::RemoveMode(mode) => self.modes.retain(|(m, _)| *m != mode),
+            TagEffect::Scope(_) | TagEffect::Tone(_) | TagEffect::Depth(_) => {
+                unreachable!("hints go through TagState::fire")
+            }
+            TagEffect::AddState(state) => {
+                self.states.insert(state);
+            }
+            TagEffect::AddIntent(intent) => {
+                self.intents.insert(intent);
+            }
+            TagEffect::Confidence(dimension, delta) => *self.confidence.get_mut(dimension) += delta,
         }
+    }
+}
 
-        // Editing: High backspace count
-        if editing.backspace_count > 10 || editing.selection_edit_count > 2 {
-            user_states.insert(UserState::Editing);
-        }
+#[derive(Debug, Clone)]
+pub struct RuleEngine {
+    config: RuleConfig,
+}
 
-        // Pasting: High paste ratio
-        if source.paste_ratio > 0.5 {
-            user_states.insert(UserState::Pasting);
-        }
+impl Default for RuleEngine {
+    fn default() -> Self {
+        Self::builtin().clone()
+    }
+}
 
-        // Scattered: Many bursts + short segments (heuristic)
-        if timing.typing_bursts > 5 && timing.avg_chars_per_sec < 3.0 {
-            user_states.insert(UserState::Scattered);
-        }
+impl RuleEngine {
+    /// The rule set shipped in `config/rules.toml`.
+    pub fn builtin() -> &'static RuleEngine {
+        static BUILTIN: OnceLock<RuleEngine> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            RuleEngine::from_toml_str(DEFAULT_RULES).expect("built-in rules must parse")
+        })
+    }
+
+    pub fn from_toml_str(toml_str: &str) -> Result<Self, String> {
+        let config: RuleConfig = toml::from_str(toml_str).map_err(|e| e.to_string())?;
+        Self::new(config)
+    }
+
+    /// Load a rule set from a TOML file, e.g. a tuned copy of `config/rules.toml`.
+    pub fn from_config(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_toml_str(&content)
+    }
 
-        // Focused: High speed + few edits
-        if timing.avg_chars_per_sec > 4.0 && editing.backspace_count < 5 {
-            user_states.insert(UserState::Focused);
+    pub fn new(config: RuleConfig) -> Result<Self, String> {
+        for rule in &config.rules {
+            for condition in rule.when.iter().chain(&rule.any) {
+                let namespace = condition.feature.split('.').next().unwrap_or_default();
+                if !NAMESPACES.contains(&namespace) {
+                    return Err(format!(
+                        "Rule '{}': unknown feature '{}'",
+                        rule.id, condition.feature
+                    ));
+                }
+            }
         }
+        Ok(Self { config })
+    }
+
+    pub fn config(&self) -> &RuleConfig {
+        &self.config
+    }
 
-        let user_state: Vec<UserState> = user_states.clone().into_iter().collect();
+    /// Run the declarative rules, then `plugins` in order. With `trace`, every
+    /// rule that fired is listed in `rule_trace` with the effects it applied.
+    pub fn apply(&self, features: &Features, plugins: &[Arc<dyn Rule>], trace: bool) -> AnswerTags {
+        let context = features.to_value();
 
-        // Pragmatic Intent Detection
-        let mut pragmatic_intents = HashSet::new();
+        let mut state = TagState {
+            modes: Vec::new(),
+            scope: Hint::new(ScopeHint::Narrow),
+            tone: Hint::new(ToneHint::Neutral),
+            depth: Hint::new(DepthHint::Normal),
+            states: BTreeSet::new(),
+            intents: BTreeSet::new(),
+            confidence: TagConfidence::uniform(self.config.base_confidence),
+        };
+        let mut rule_trace = Vec::new();
 
-        // Solution Focused: Flowing (Fast) + Mixed/Paste (Context provided)
-        if user_states.contains(&UserState::Flowing) || user_states.contains(&UserState::Pasting) {
-            pragmatic_intents.insert(PragmaticIntent::SolutionFocused);
+        for rule in &self.config.rules {
+            let value_of = |condition: &Condition| match condition.feature.split_once('.') {
+                Some(("tags", key)) => state.lookup(key),
+                _ => {
+                    let pointer = format!("/{}", condition.feature.replace('.', "/"));
+                    context.pointer(&pointer).cloned().unwrap_or(Value::Null)
+                }
+            };
+            let all_hold = rule.when.iter().all(|c| c.holds(&value_of(c)));
+            let any_holds = rule.any.is_empty() || rule.any.iter().any(|c| c.holds(&value_of(c)));
+            if all_hold && any_holds {
+                let effects = rule.effects();
+                let (overrides, suppressed) = state.fire(&rule.id, rule.priority, &effects);
+                if trace {
+                    rule_trace.push(RuleFire {
+                        rule_id: rule.id.clone(),
+                        description: rule.description.clone(),
+                        contribution: effects,
+                        overrides,
+                        suppressed,
+                    });
+                }
+            }
         }
 
-        // Debugging: Editing + Pasting (Fixing code)
-        if user_states.contains(&UserState::Editing) && user_states.contains(&UserState::Pasting) {
-            pragmatic_intents.insert(PragmaticIntent::Debugging);
+        for plugin in plugins {
+            let effects = plugin.evaluate(features);
+            if effects.is_empty() {
+                continue;
+            }
+            let (overrides, suppressed) = state.fire(plugin.id(), plugin.priority(), &effects);
+            if trace {
+                rule_trace.push(RuleFire {
+                    rule_id: plugin.id().to_string(),
+                    description: plugin.description().to_string(),
+                    contribution: effects,
+                    overrides,
+                    suppressed,
+                });
+            }
         }
 
-        // Expertise Seeking: Focused (Fast & Precise) + Long duration (Deep thought)
-        if user_states.contains(&UserState::Focused) && timing.total_duration_ms > 10_000 {
-            pragmatic_intents.insert(PragmaticIntent::ExpertiseSeeking);
+        AnswerTags {
+            answer_mode: state.ranked_modes(),
+            mode_scores: state.mode_scores(),
+            scope_hint: state.scope.value,
+            tone_hint: state.tone.value,
+            depth_hint: state.depth.value,
+            user_state: state.states.into_iter().collect(),
+            pragmatic_intent: state.intents.into_iter().collect(),
+            confidence: TagConfidence {
+                mode: state.confidence.mode.min(1.0),
+                tone: state.confidence.tone.min(1.0),
+                depth: state.confidence.depth.min(1.0),
+                user_state: state.confidence.user_state.min(1.0),
+            },
+            // Decided by the caller once confidence is calibrated
+            clarify_before_answering: false,
+            rule_trace,
+            classifier: None,
         }
+    }
+}
+
+/// Rule configurations compared side by side. Each session is assigned a
+/// variant deterministically from its key, so the same key always gets the
+/// same thresholds; `IflCore::with_rule_experiment` keys on the message id.
+#[derive(Debug, Clone)]
+pub struct RuleExperiment {
+    pub id: String,
+    variants: Vec<(String, Arc<RuleEngine>)>,
+}
 
-        // Ambiguity Resolution: Hesitant (Unsure) + Editing (Rewriting)
-        if user_states.contains(&UserState::Hesitant) && user_states.contains(&UserState::Editing) {
-            pragmatic_intents.insert(PragmaticIntent::AmbiguityResolution);
+impl RuleExperiment {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            variants: Vec::new(),
         }
+    }
+
+    pub fn with_variant(mut self, name: &str, rules: RuleEngine) -> Self {
+        self.variants.push((name.to_string(), Arc::new(rules)));
+        self
+    }
 
-        // Concept Exploration: Scattered (Brainstorming) + Explore Mode
-        if user_states.contains(&UserState::Scattered) || modes.contains(&AnswerMode::Explore) {
-            pragmatic_intents.insert(PragmaticIntent::ConceptExploration);
+    pub fn variant_names(&self) -> Vec<&str> {
+        self.variants
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// The variant for a session key (user or conversation id).
+    pub fn assign(&self, session_key: &str) -> Result<(&str, Arc<RuleEngine>), String> {
+        if self.variants.is_empty() {
+            return Err(format!("Experiment {} has no variants", self.id));
         }
+        let bucket =
+            content_hash(&format!("{}:{}", self.id, session_key)) as usize % self.variants.len();
+        let (name, rules) = &self.variants[bucket];
+        Ok((name, rules.clone()))
+    }
+}
 
-        let pragmatic_intent: Vec<PragmaticIntent> = pragmatic_intents.into_iter().collect();
+/// A numeric feature of `Features::to_value` by dotted path.
+#[cfg(feature = "ml")]
+pub(crate) fn feature_number(context: &Value, path: &str) -> Option<f64> {
+    let pointer = format!("/{}", path.replace('.', "/"));
+    context.pointer(&pointer).and_then(as_number)
+}
 
-        AnswerTags {
-            answer_mode,
-            scope_hint: scope,
-            tone_hint: tone,
-            depth_hint: depth,
-            user_state,
-            pragmatic_intent,
-            confidence: confidence.min(1.0),
+/// Numbers compare as numbers, booleans as 0/1 and arrays by their length.
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        Value::Array(items) => Some(items.len() as f64),
+        _ => None,
+    }
+}
+
+fn compare(actual: &Value, expected: &Value, test: impl Fn(f64, f64) -> bool) -> bool {
+    match (as_number(actual), as_number(expected)) {
+        (Some(a), Some(e)) => test(a, e),
+        _ => false,
+    }
+}
+
+fn equals(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Number(_) | Value::Array(_), Value::Number(_)) => {
+            compare(actual, expected, |a, e| (a - e).abs() < f64::EPSILON)
         }
+        _ => actual == expected,
+    }
+}
+
+fn contains(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Array(items), _) => items.contains(expected),
+        (Value::String(text), Value::String(needle)) => text.contains(needle.as_str()),
+        _ => false,
     }
 }
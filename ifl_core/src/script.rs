@@ -0,0 +1,205 @@
+use crate::profile::{TagDimension, TagEffect};
+use crate::rules::{Features, Rule};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST, FLOAT};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// Budget of one evaluation; a script that runs longer contributes nothing.
+const DEFAULT_TIME_LIMIT: Duration = Duration::from_millis(20);
+/// Hard cap on Rhai operations, so even a slow clock can't let a loop spin.
+const MAX_OPERATIONS: u64 = 200_000;
+
+thread_local! {
+    /// Deadline of the evaluation running on this thread.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Effects a script builds up through the `tags` variable.
+#[derive(Debug, Clone, Default)]
+pub struct TagsBuilder {
+    effects: Vec<TagEffect>,
+}
+
+impl TagsBuilder {
+    pub fn effects(&self) -> &[TagEffect] {
+        &self.effects
+    }
+}
+
+/// A rule written in Rhai, for prototyping without recompiling.
+///
+/// The script sees `features` (the same map rule conditions address:
+/// `features.structure.char_count`, `features.derived.depth_score`, ...) and
+/// changes `tags`:
+///
+/// ```rhai
+/// if features.structure.code_ratio > 0.5 && features.structure.question_like {
+///     tags.add_mode("debug", 1.5);
+///     tags.depth("deep");
+/// }
+/// ```
+///
+/// Builder methods: `add_mode(mode, weight)`, `remove_mode`, `scope`, `tone`,
+/// `depth`, `add_state`, `add_intent` and `confidence(dimension, delta)`,
+/// taking the snake_case names used in the profile JSON.
+///
+/// Scripts are sandboxed: no module imports or `eval`, bounded operations,
+/// sizes and recursion, and a wall-clock limit per message. A script that
+/// fails or times out contributes no effects.
+pub struct ScriptRule {
+    id: String,
+    description: String,
+    priority: i32,
+    time_limit: Duration,
+    engine: Engine,
+    ast: AST,
+}
+
+impl ScriptRule {
+    pub fn new(id: &str, source: &str) -> Result<Self, String> {
+        let engine = Self::sandboxed_engine();
+        let ast = engine
+            .compile(source)
+            .map_err(|e| format!("Script {}: {}", id, e))?;
+        Ok(Self {
+            id: id.to_string(),
+            description: String::new(),
+            priority: 0,
+            time_limit: DEFAULT_TIME_LIMIT,
+            engine,
+            ast,
+        })
+    }
+
+    pub fn from_file(id: &str, path: &str) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::new(id, &source)
+    }
+
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = description.to_string();
+        self
+    }
+
+    /// Priority of the tone/scope/depth hints the script sets.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_time_limit(mut self, time_limit: Duration) -> Self {
+        self.time_limit = time_limit;
+        self
+    }
+
+    /// Run the script, reporting errors instead of dropping them.
+    pub fn run(&self, features: &Features) -> Result<Vec<TagEffect>, String> {
+        let features = rhai::serde::to_dynamic(features.to_value()).map_err(|e| e.to_string())?;
+        let mut scope = Scope::new();
+        scope.push_constant("features", features);
+        scope.push("tags", TagsBuilder::default());
+
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.time_limit)));
+        let result = self.engine.run_ast_with_scope(&mut scope, &self.ast);
+        DEADLINE.with(|deadline| deadline.set(None));
+        result.map_err(|e| format!("Script {}: {}", self.id, e))?;
+
+        let tags = scope
+            .get_value::<TagsBuilder>("tags")
+            .ok_or_else(|| format!("Script {}: `tags` was replaced", self.id))?;
+        Ok(tags.effects)
+    }
+
+    fn sandboxed_engine() -> Engine {
+        let mut engine = Engine::new();
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(16)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(10_000)
+            .set_max_array_size(1_000)
+            .set_max_map_size(1_000)
+            .disable_symbol("eval")
+            .on_progress(|_| {
+                let expired = DEADLINE.with(|d| d.get().is_some_and(|at| Instant::now() > at));
+                expired.then(|| Dynamic::from("time limit exceeded"))
+            });
+
+        engine
+            .register_type_with_name::<TagsBuilder>("Tags")
+            .register_fn(
+                "add_mode",
+                |tags: &mut TagsBuilder, mode: &str, weight: FLOAT| {
+                    let effect = TagEffect::AddMode(parse(mode)?, weight as f32);
+                    tags.effects.push(effect);
+                    Ok::<_, Box<EvalAltResult>>(())
+                },
+            )
+            .register_fn("add_mode", |tags: &mut TagsBuilder, mode: &str| {
+                tags.effects.push(TagEffect::AddMode(parse(mode)?, 1.0));
+                Ok::<_, Box<EvalAltResult>>(())
+            })
+            .register_fn("remove_mode", |tags: &mut TagsBuilder, mode: &str| {
+                tags.effects.push(TagEffect::RemoveMode(parse(mode)?));
+                Ok::<_, Box<EvalAltResult>>(())
+            })
+            .register_fn("scope", |tags: &mut TagsBuilder, scope: &str| {
+                tags.effects.push(TagEffect::Scope(parse(scope)?));
+                Ok::<_, Box<EvalAltResult>>(())
+            })
+            .register_fn("tone", |tags: &mut TagsBuilder, tone: &str| {
+                tags.effects.push(TagEffect::Tone(parse(tone)?));
+                Ok::<_, Box<EvalAltResult>>(())
+            })
+            .register_fn("depth", |tags: &mut TagsBuilder, depth: &str| {
+                tags.effects.push(TagEffect::Depth(parse(depth)?));
+                Ok::<_, Box<EvalAltResult>>(())
+            })
+            .register_fn("add_state", |tags: &mut TagsBuilder, state: &str| {
+                tags.effects.push(TagEffect::AddState(parse(state)?));
+                Ok::<_, Box<EvalAltResult>>(())
+            })
+            .register_fn("add_intent", |tags: &mut TagsBuilder, intent: &str| {
+                tags.effects.push(TagEffect::AddIntent(parse(intent)?));
+                Ok::<_, Box<EvalAltResult>>(())
+            })
+            .register_fn(
+                "confidence",
+                |tags: &mut TagsBuilder, dimension: &str, delta: FLOAT| {
+                    let dimension: TagDimension = parse(dimension)?;
+                    tags.effects
+                        .push(TagEffect::Confidence(dimension, delta as f32));
+                    Ok::<_, Box<EvalAltResult>>(())
+                },
+            );
+        engine
+    }
+}
+
+impl Rule for ScriptRule {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn evaluate(&self, features: &Features) -> Vec<TagEffect> {
+        self.run(features).unwrap_or_default()
+    }
+}
+
+/// A tag name as written in the profile JSON, e.g. `"clarify_question"`.
+fn parse<T: DeserializeOwned>(name: &str) -> Result<T, Box<EvalAltResult>> {
+    serde_json::from_value(Value::String(name.to_string()))
+        .map_err(|_| format!("Unknown tag '{}'", name).into())
+}
@@ -0,0 +1,349 @@
+//! The analyzer and the model behind an HTTP and WebSocket API, for an app
+//! that runs it next to itself as a sidecar; `ifl serve` serves it.
+//!
+//! Every route takes and returns JSON; failures are `{"error": "..."}` with
+//! a 4xx or 5xx status.
+
+use crate::llm_client::LlmClient;
+use crate::{IflCore, InputEvent, InputProfile};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+pub struct Server {
+    core: IflCore,
+    client: LlmClient,
+    /// Messages started through the API and not yet finalized or discarded.
+    open: Mutex<HashSet<String>>,
+    max_sessions: usize,
+}
+
+/// An error as the API reports it.
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+fn bad_request(message: String) -> ApiError {
+    ApiError(StatusCode::BAD_REQUEST, message)
+}
+
+/// One event or several, as `POST /messages/{id}/events` takes them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Events {
+    One(InputEvent),
+    Many(Vec<InputEvent>),
+}
+
+impl Events {
+    fn into_vec(self) -> Vec<InputEvent> {
+        match self {
+            Events::One(event) => vec![event],
+            Events::Many(events) => events,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TextBody {
+    text: String,
+}
+
+impl Server {
+    /// At most `max_sessions` messages (at least one) may be open at once.
+    pub fn new(core: IflCore, client: LlmClient, max_sessions: usize) -> Self {
+        Self {
+            core,
+            client,
+            open: Mutex::new(HashSet::new()),
+            max_sessions: max_sessions.max(1),
+        }
+    }
+
+    fn open(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        // A panic while holding the set cannot leave it half-updated
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start(&self) -> Result<String, ApiError> {
+        let mut open = self.open();
+        if open.len() >= self.max_sessions {
+            return Err(ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!(
+                    "{} messages are open, the most allowed; finalize or discard one first",
+                    self.max_sessions
+                ),
+            ));
+        }
+        let id = self.core.start_message().map_err(bad_request)?;
+        open.insert(id.clone());
+        Ok(id)
+    }
+
+    fn check(&self, id: &str) -> Result<(), ApiError> {
+        if self.open().contains(id) {
+            Ok(())
+        } else {
+            Err(ApiError(
+                StatusCode::NOT_FOUND,
+                format!("Message {} not found", id),
+            ))
+        }
+    }
+
+    fn push(&self, id: &str, events: Vec<InputEvent>) -> Result<usize, ApiError> {
+        self.check(id)?;
+        let count = events.len();
+        for event in events {
+            self.core.push_event(id, event).map_err(bad_request)?;
+        }
+        Ok(count)
+    }
+
+    fn preview(&self, id: &str, text: &str) -> Result<InputProfile, ApiError> {
+        self.check(id)?;
+        let json = self.core.preview_message(id, text).map_err(bad_request)?;
+        serde_json::from_str(&json).map_err(|e| bad_request(e.to_string()))
+    }
+
+    fn finalize(&self, id: &str, text: &str) -> Result<InputProfile, ApiError> {
+        self.check(id)?;
+        let json = self.core.finalize_message(id, text).map_err(bad_request)?;
+        self.open().remove(id);
+        serde_json::from_str(&json).map_err(|e| bad_request(e.to_string()))
+    }
+
+    fn discard(&self, id: &str) -> Result<(), ApiError> {
+        self.check(id)?;
+        self.core.discard_message(id).map_err(bad_request)?;
+        self.open().remove(id);
+        Ok(())
+    }
+}
+
+/// The routes of the API, for `axum::serve` or to nest in an app's own
+/// router.
+pub fn router(server: Arc<Server>) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/messages", post(start))
+        .route("/messages/{id}", axum::routing::delete(discard))
+        .route("/messages/{id}/events", post(push))
+        .route("/messages/{id}/preview", post(preview))
+        .route("/messages/{id}/finalize", post(finalize))
+        .route("/messages/{id}/generate", post(generate))
+        .route("/ws", get(websocket))
+        .with_state(server)
+}
+
+async fn health(State(server): State<Arc<Server>>) -> Json<Value> {
+    Json(json!({
+        "status": "ok",
+        "open_messages": server.open().len(),
+        "max_sessions": server.max_sessions,
+        "model": server.client.model(),
+    }))
+}
+
+async fn start(State(server): State<Arc<Server>>) -> Result<impl IntoResponse, ApiError> {
+    let id = server.start()?;
+    Ok((StatusCode::CREATED, Json(json!({ "message_id": id }))))
+}
+
+async fn push(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<String>,
+    Json(events): Json<Events>,
+) -> Result<Json<Value>, ApiError> {
+    let accepted = server.push(&id, events.into_vec())?;
+    Ok(Json(json!({ "accepted": accepted })))
+}
+
+async fn preview(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<String>,
+    Json(body): Json<TextBody>,
+) -> Result<Json<InputProfile>, ApiError> {
+    server.preview(&id, &body.text).map(Json)
+}
+
+async fn finalize(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<String>,
+    Json(body): Json<TextBody>,
+) -> Result<Json<InputProfile>, ApiError> {
+    server.finalize(&id, &body.text).map(Json)
+}
+
+/// Finalize the message and answer it with the model.
+async fn generate(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<String>,
+    Json(body): Json<TextBody>,
+) -> Result<Json<Value>, ApiError> {
+    let profile = server.finalize(&id, &body.text)?;
+    let response = server
+        .client
+        .generate_response(&body.text, &profile)
+        .await
+        .map_err(|e| ApiError(StatusCode::BAD_GATEWAY, e.to_string()))?;
+    Ok(Json(json!({ "profile": profile, "response": response })))
+}
+
+async fn discard(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    server.discard(&id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A frame the client sends over `/ws`: the events since the last frame
+/// and the text as it stands now, answered with a preview; with `submit`
+/// the message is finalized instead, and with `generate` also answered,
+/// token by token.
+#[derive(Deserialize)]
+struct ClientFrame {
+    #[serde(default)]
+    events: Vec<InputEvent>,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    submit: bool,
+    #[serde(default)]
+    generate: bool,
+}
+
+async fn websocket(State(server): State<Arc<Server>>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| converse(server, socket))
+}
+
+/// One message after another over a socket: `{"message_id"}` as each
+/// starts, `{"profile"}` for every frame, `{"token"}`s and `{"response"}`
+/// when one is generated, and `{"error"}` for a frame that failed. A
+/// message left open when the socket closes is discarded, and an answer
+/// still being generated is stopped.
+async fn converse(server: Arc<Server>, socket: WebSocket) {
+    let (mut sink, mut stream) = socket.split();
+    // Tokens are sent while the answer is still being generated
+    let (outgoing, mut queued) = mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        while let Some(value) = queued.recv().await {
+            if sink
+                .send(Message::Text(value.to_string().into()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut id: Option<String> = None;
+    // Frames that came in while an answer was being generated
+    let mut pending = VecDeque::new();
+    loop {
+        let current = match &id {
+            Some(current) => current.clone(),
+            None => match server.start() {
+                Ok(started) => {
+                    let _ = outgoing.send(json!({ "message_id": started }));
+                    id.insert(started).clone()
+                }
+                // No room for another message: say so and hang up
+                Err(ApiError(_, e)) => {
+                    let _ = outgoing.send(json!({ "error": e }));
+                    break;
+                }
+            },
+        };
+        let message = match pending.pop_front() {
+            Some(message) => message,
+            None => match stream.next().await {
+                Some(Ok(message)) => message,
+                _ => break,
+            },
+        };
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let frame: ClientFrame = match serde_json::from_str(text.as_str()) {
+            Ok(frame) => frame,
+            Err(e) => {
+                let _ = outgoing.send(json!({ "error": e.to_string() }));
+                continue;
+            }
+        };
+        if let Err(ApiError(_, e)) = server.push(&current, frame.events) {
+            let _ = outgoing.send(json!({ "error": e }));
+            continue;
+        }
+        if !frame.submit {
+            let reply = match server.preview(&current, &frame.text) {
+                Ok(profile) => json!({ "profile": profile }),
+                Err(ApiError(_, e)) => json!({ "error": e }),
+            };
+            let _ = outgoing.send(reply);
+            continue;
+        }
+
+        let profile = match server.finalize(&current, &frame.text) {
+            Ok(profile) => profile,
+            Err(ApiError(_, e)) => {
+                let _ = outgoing.send(json!({ "error": e }));
+                continue;
+            }
+        };
+        id = None;
+        let _ = outgoing.send(json!({ "profile": profile, "final": true }));
+        if frame.generate {
+            let tokens = outgoing.clone();
+            let generation =
+                server
+                    .client
+                    .generate_response_stream(&frame.text, &profile, |token| {
+                        let _ = tokens.send(json!({ "token": token }));
+                    });
+            tokio::pin!(generation);
+            // The socket is read meanwhile: a client that hangs up drops the
+            // generation, which stops the model, instead of waiting it out
+            let result = loop {
+                tokio::select! {
+                    result = &mut generation => break Some(result),
+                    incoming = stream.next() => match incoming {
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break None,
+                        Some(Ok(message)) => pending.push_back(message),
+                    },
+                }
+            };
+            let reply = match result {
+                Some(Ok(response)) => json!({ "response": response }),
+                Some(Err(e)) => json!({ "error": e.to_string() }),
+                None => break,
+            };
+            let _ = outgoing.send(reply);
+        }
+    }
+
+    if let Some(id) = id {
+        let _ = server.discard(&id);
+    }
+    drop(outgoing);
+    let _ = writer.await;
+}
@@ -0,0 +1,392 @@
+use crate::event::{DeleteKind, InputEvent};
+use crate::profile::UserState;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Timestamp of the first simulated event.
+const START_TS: u64 = 1000;
+/// Words an editing writer tries before settling on the right one.
+const DRAFT_WORDS: &[&str] = &["thing", "stuff", "maybe", "really", "somehow"];
+/// Asides an editing writer types and then deletes again.
+const ASIDES: &[&str] = &[
+    " (I mean, something like that)",
+    " or whatever works best here",
+    " if that even makes sense",
+];
+
+/// A kind of writer, for simulating how a message was typed rather than
+/// just what it says: the behavior the rules read user state from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Persona {
+    /// Slow, with long pauses, and a phrase deleted and typed again.
+    Hesitant,
+    /// Fast and steady; the odd typo fixed at once.
+    Flowing,
+    /// Replaces words through selections and deletes whole asides.
+    Editing,
+    /// Short bursts of typing between long stops, looking around the text.
+    Scattered,
+    /// Types Japanese through an IME between English runs, reconverting
+    /// now and then.
+    Bilingual,
+}
+
+impl Persona {
+    pub const ALL: [Persona; 5] = [
+        Persona::Hesitant,
+        Persona::Flowing,
+        Persona::Editing,
+        Persona::Scattered,
+        Persona::Bilingual,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Persona::Hesitant => "hesitant",
+            Persona::Flowing => "flowing",
+            Persona::Editing => "editing",
+            Persona::Scattered => "scattered",
+            Persona::Bilingual => "bilingual",
+        }
+    }
+
+    /// The user state the rules should find in this persona's typing. A
+    /// bilingual writer has none of its own; it exercises the language and
+    /// IME handling.
+    pub fn expected_state(self) -> Option<UserState> {
+        match self {
+            Persona::Hesitant => Some(UserState::Hesitant),
+            Persona::Flowing => Some(UserState::Flowing),
+            Persona::Editing => Some(UserState::Editing),
+            Persona::Scattered => Some(UserState::Scattered),
+            Persona::Bilingual => None,
+        }
+    }
+}
+
+impl FromStr for Persona {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Persona::ALL
+            .into_iter()
+            .find(|persona| persona.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Persona::ALL.iter().map(|p| p.name()).collect();
+                format!(
+                    "Unknown persona '{}'; expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// The events of `persona` writing `text` and sending it. The same seed
+/// always gives the same events, and they rebuild `text` exactly
+/// (`event::reconstruct_text`).
+pub fn simulate(text: &str, persona: Persona, seed: u64) -> Vec<InputEvent> {
+    let mut writer = Writer::new(seed);
+    match persona {
+        Persona::Hesitant => writer.hesitant(text),
+        Persona::Flowing => {
+            for ch in text.chars() {
+                writer.fluent(ch);
+            }
+        }
+        Persona::Editing => writer.editing(text),
+        Persona::Scattered => writer.scattered(text),
+        Persona::Bilingual => writer.bilingual(text),
+    }
+    writer.submit()
+}
+
+/// SplitMix64, kept here rather than taken from a crate so that a seed
+/// means the same events in every version.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `low..=high`.
+    fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low + 1)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.next() as usize % items.len()]
+    }
+}
+
+/// Builds the events, keeping the cursor at the end of the text between
+/// steps.
+struct Writer {
+    rng: Rng,
+    ts: u64,
+    events: Vec<InputEvent>,
+    /// Characters in the text so far.
+    len: usize,
+}
+
+impl Writer {
+    fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng(seed),
+            ts: START_TS,
+            events: Vec::new(),
+            len: 0,
+        }
+    }
+
+    fn wait(&mut self, low: u64, high: u64) {
+        self.ts += self.rng.between(low, high);
+    }
+
+    fn push(&mut self, event: InputEvent) {
+        self.events.push(event);
+    }
+
+    /// Type `ch` after a gap of `low..=high` ms.
+    fn key(&mut self, ch: char, low: u64, high: u64) {
+        self.wait(low, high);
+        self.push(InputEvent::KeyInsert { ch, ts: self.ts });
+        self.len += 1;
+    }
+
+    fn backspace(&mut self, count: usize, low: u64, high: u64) {
+        for _ in 0..count {
+            self.wait(low, high);
+            self.push(InputEvent::KeyDelete {
+                kind: DeleteKind::Backspace,
+                count: 1,
+                ts: self.ts,
+            });
+            self.len -= 1;
+        }
+    }
+
+    /// Delete the last `text` typed, reporting it as the frontends do.
+    fn delete(&mut self, text: &str, low: u64, high: u64) {
+        self.push(InputEvent::GhostText {
+            text: text.to_string(),
+            ts: self.ts,
+        });
+        self.backspace(text.chars().count(), low, high);
+    }
+
+    /// Fast typing, with a typo fixed at once now and then.
+    fn fluent(&mut self, ch: char) {
+        if ch.is_ascii_alphabetic() && self.rng.chance(0.03) {
+            self.key(neighbor(ch), 90, 160);
+            self.backspace(1, 150, 300);
+        }
+        self.key(ch, 90, 160);
+    }
+
+    fn hesitant(&mut self, text: &str) {
+        let words: Vec<&str> = text.split_inclusive(char::is_whitespace).collect();
+        // One phrase long enough to count is deleted and typed again
+        let candidates: Vec<usize> = (0..words.len().saturating_sub(1))
+            .filter(|&i| {
+                format!("{}{}", words[i], words[i + 1])
+                    .trim()
+                    .chars()
+                    .count()
+                    >= 8
+            })
+            .collect();
+        let second_guess = (!candidates.is_empty())
+            .then(|| candidates[self.rng.next() as usize % candidates.len()] + 1);
+        for (i, word) in words.iter().enumerate() {
+            if i > 0 && (self.rng.chance(0.35) || ends_sentence(words[i - 1])) {
+                self.wait(1800, 5000);
+            }
+            for ch in word.chars() {
+                self.key(ch, 300, 750);
+            }
+            if second_guess == Some(i) {
+                let phrase = format!("{}{}", words[i - 1], words[i]);
+                self.wait(1600, 3000);
+                self.delete(&phrase, 90, 160);
+                self.wait(2000, 4000);
+                for ch in phrase.chars() {
+                    self.key(ch, 300, 750);
+                }
+            }
+        }
+    }
+
+    fn editing(&mut self, text: &str) {
+        let words: Vec<&str> = text.split_inclusive(char::is_whitespace).collect();
+        // Words first drafted as something else: about a third, and at
+        // least three when there are enough, as the selection-edit rule
+        // needs
+        let eligible: Vec<usize> = (0..words.len())
+            .filter(|&i| words[i].trim().chars().count() >= 3)
+            .collect();
+        let mut replaced: Vec<usize> = eligible
+            .iter()
+            .copied()
+            .filter(|_| self.rng.chance(0.3))
+            .collect();
+        for &i in &eligible {
+            if replaced.len() >= 3 {
+                break;
+            }
+            if !replaced.contains(&i) {
+                replaced.push(i);
+            }
+        }
+        // An aside typed after one word and deleted again
+        let breaks: Vec<usize> = (0..words.len())
+            .filter(|&i| words[i].ends_with(char::is_whitespace))
+            .collect();
+        let aside_after =
+            (!breaks.is_empty()).then(|| breaks[self.rng.next() as usize % breaks.len()]);
+
+        for (i, word) in words.iter().enumerate() {
+            if replaced.contains(&i) {
+                let draft = self.rng.pick(DRAFT_WORDS);
+                for ch in draft.chars() {
+                    self.key(ch, 120, 260);
+                }
+                self.wait(400, 1200);
+                let start = self.len - draft.chars().count();
+                self.push(InputEvent::SelectionChange {
+                    start,
+                    end: self.len,
+                    ts: self.ts,
+                });
+                // Typing over the selection replaces it
+                self.len = start;
+            }
+            for ch in word.chars() {
+                self.key(ch, 120, 260);
+            }
+            if aside_after == Some(i) {
+                let aside = self.rng.pick(ASIDES);
+                for ch in aside.chars() {
+                    self.key(ch, 120, 260);
+                }
+                self.wait(800, 2000);
+                self.delete(aside, 60, 110);
+            }
+        }
+    }
+
+    fn scattered(&mut self, text: &str) {
+        let chars: Vec<char> = text.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            if i > 0 {
+                self.wait(1700, 4500);
+                // Looking back over what is there before going on
+                if self.len > 0 && self.rng.chance(0.3) {
+                    let position = self.rng.between(0, self.len as u64 - 1) as usize;
+                    self.push(InputEvent::CursorMove {
+                        position,
+                        ts: self.ts,
+                    });
+                    self.wait(500, 1500);
+                    self.push(InputEvent::CursorMove {
+                        position: self.len,
+                        ts: self.ts,
+                    });
+                }
+            }
+            let burst = self.rng.between(2, 7) as usize;
+            for &ch in chars.iter().skip(i).take(burst) {
+                self.key(ch, 100, 220);
+            }
+            i += burst;
+        }
+    }
+
+    fn bilingual(&mut self, text: &str) {
+        let chars: Vec<char> = text.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let japanese = is_japanese(chars[i]);
+            let run = chars[i..]
+                .iter()
+                .take_while(|&&ch| is_japanese(ch) == japanese)
+                .count();
+            if i > 0 {
+                // Switching the IME on or off
+                self.wait(400, 1000);
+            }
+            if japanese {
+                self.compose(&chars[i..i + run]);
+            } else {
+                for &ch in &chars[i..i + run] {
+                    self.fluent(ch);
+                }
+            }
+            i += run;
+        }
+    }
+
+    /// Japanese through an IME, a few characters per conversion; each
+    /// character takes about two romaji keystrokes.
+    fn compose(&mut self, run: &[char]) {
+        let mut i = 0;
+        while i < run.len() {
+            let size = (self.rng.between(2, 6) as usize).min(run.len() - i);
+            let chunk = &run[i..i + size];
+            self.push(InputEvent::CompositionStart { ts: self.ts });
+            for &ch in chunk {
+                self.key(ch, 180, 380);
+            }
+            // Picking the candidate, sometimes the wrong one first
+            self.wait(250, 700);
+            if self.rng.chance(0.15) {
+                self.backspace(chunk.len(), 60, 100);
+                for &ch in chunk {
+                    self.key(ch, 120, 250);
+                }
+                self.wait(250, 700);
+            }
+            self.push(InputEvent::CompositionEnd { ts: self.ts });
+            i += size;
+        }
+    }
+
+    fn submit(mut self) -> Vec<InputEvent> {
+        self.wait(300, 900);
+        self.push(InputEvent::Submit { ts: self.ts });
+        self.events
+    }
+}
+
+/// A letter next to `ch` in the alphabet, as a mistyped key.
+fn neighbor(ch: char) -> char {
+    match ch {
+        'z' => 'x',
+        'Z' => 'X',
+        ch => (ch as u8 + 1) as char,
+    }
+}
+
+fn ends_sentence(word: &str) -> bool {
+    word.trim_end().ends_with(['.', '?', '!', ','])
+}
+
+/// Kana, kanji and Japanese punctuation: what goes through the IME.
+fn is_japanese(ch: char) -> bool {
+    matches!(ch,
+        '\u{3000}'..='\u{303F}' // punctuation
+        | '\u{3040}'..='\u{30FF}' // hiragana, katakana
+        | '\u{4E00}'..='\u{9FFF}' // kanji
+        | '\u{FF00}'..='\u{FFEF}') // full-width forms
+}
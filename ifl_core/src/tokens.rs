@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+/// Tokenizer heuristics per model family. Counts are approximate: they are
+/// meant for budgeting and thresholds, not for exact context accounting.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenizerFamily {
+    #[default]
+    Generic,
+    /// OpenAI BPE vocabularies (cl100k/o200k)
+    Gpt,
+    /// Llama 3 (128k tiktoken-style vocabulary)
+    Llama,
+    /// SentencePiece 32k vocabularies (Llama 2, Mistral) with byte fallback
+    Mistral,
+    Qwen,
+    Gemma,
+}
+
+impl TokenizerFamily {
+    /// Guess the family from a model name such as `llama3.2:3b` or `gpt-4o`.
+    pub fn for_model(model: &str) -> Self {
+        let model = model.to_lowercase();
+        if model.contains("qwen") {
+            Self::Qwen
+        } else if model.contains("gemma") {
+            Self::Gemma
+        } else if model.contains("mistral")
+            || model.contains("mixtral")
+            || model.contains("llama2")
+            || model.contains("llama-2")
+        {
+            Self::Mistral
+        } else if model.contains("llama") {
+            Self::Llama
+        } else if model.starts_with("gpt") || model.starts_with("o1") || model.starts_with("o3") {
+            Self::Gpt
+        } else {
+            Self::Generic
+        }
+    }
+
+    /// (characters per token for Latin/ASCII text, tokens per CJK character)
+    fn ratios(self) -> (f32, f32) {
+        match self {
+            Self::Generic => (4.0, 1.0),
+            Self::Gpt => (4.0, 0.9),
+            Self::Llama => (4.2, 0.8),
+            Self::Mistral => (3.5, 1.5),
+            Self::Qwen => (4.0, 0.7),
+            Self::Gemma => (4.2, 0.7),
+        }
+    }
+
+    pub fn estimate(self, text: &str) -> usize {
+        let cjk = text.chars().filter(|&c| is_cjk(c)).count();
+        self.estimate_counts(text.chars().count() - cjk, cjk)
+    }
+
+    /// Estimate from character counts already gathered by the caller.
+    pub fn estimate_counts(self, other_chars: usize, cjk_chars: usize) -> usize {
+        let (chars_per_token, tokens_per_cjk) = self.ratios();
+        (other_chars as f32 / chars_per_token + cjk_chars as f32 * tokens_per_cjk).ceil() as usize
+    }
+}
+
+pub(crate) fn is_cjk(c: char) -> bool {
+    let u = c as u32;
+    (0x3040..=0x30FF).contains(&u) // Hiragana, Katakana
+        || (0x4E00..=0x9FFF).contains(&u) // CJK ideographs
+        || (0xAC00..=0xD7AF).contains(&u) // Hangul syllables
+        || (0xFF00..=0xFFEF).contains(&u) // Full-width forms
+}
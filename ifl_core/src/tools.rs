@@ -0,0 +1,64 @@
+use crate::backend::{ToolCall, ToolSpec};
+use serde_json::{json, Value};
+
+/// Runs a tool call: the arguments the model passed, in, the result out.
+pub type ToolHandler = Box<dyn Fn(&Value) -> Result<Value, String> + Send + Sync>;
+
+/// Tools offered to the model, each with the Rust callback that runs it.
+/// `LlmClient::chat_with_tools` declares them on the request and feeds each
+/// call's result back until the model answers.
+#[derive(Default)]
+pub struct Toolbox {
+    tools: Vec<(ToolSpec, ToolHandler)>,
+}
+
+impl Toolbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offer `name`, described to the model by `description`, taking
+    /// arguments matching the JSON schema `parameters`. Replaces a tool of
+    /// the same name.
+    pub fn with_tool(
+        mut self,
+        name: &str,
+        description: &str,
+        parameters: Value,
+        handler: impl Fn(&Value) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.tools.retain(|(spec, _)| spec.name != name);
+        self.tools.push((
+            ToolSpec {
+                name: name.to_string(),
+                description: description.to_string(),
+                parameters,
+            },
+            Box::new(handler),
+        ));
+        self
+    }
+
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        self.tools.iter().map(|(spec, _)| spec.clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Run `call` and return what to send back to the model: the result,
+    /// with strings as is and anything else as JSON, or `{"error": ...}` so
+    /// the model can recover from a failed or unknown call.
+    pub fn call(&self, call: &ToolCall) -> String {
+        let result = match self.tools.iter().find(|(spec, _)| spec.name == call.name) {
+            Some((_, handler)) => handler(&call.arguments),
+            None => Err(format!("Unknown tool: {}", call.name)),
+        };
+        match result {
+            Ok(Value::String(text)) => text,
+            Ok(value) => value.to_string(),
+            Err(e) => json!({ "error": e }).to_string(),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Runs every scenario in `tests/cases/*.yaml`: an event script, the final
+//! text, and the tags the engine is expected to produce. Add a file there to
+//! pin a behavior while tuning rules.
+//!
+//! ```yaml
+//! name: pasted article
+//! steps:                      # type / paste / pause / backspace
+//!   - { type: type, text: "Check this out:", interval_ms: 100 }
+//!   - { type: paste, text: "..." }
+//! final_text: "..."           # optional; defaults to what the steps produce
+//! expect:                     # every key is optional
+//!   answer_mode: [summarize]  # must contain (not_answer_mode: must not)
+//!   primary_mode: summarize
+//!   tone_hint: calm           # also scope_hint, depth_hint
+//!   user_state: [pasting]     # must contain (not_user_state: must not)
+//!   pragmatic_intent: [task_delegation]
+//! ```
+
+use ifl_core::profile::{AnswerMode, DepthHint, PragmaticIntent, ScopeHint, ToneHint, UserState};
+use ifl_core::{DeleteKind, IflCore, InputEvent, InputProfile};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Step {
+    /// Types `text` one character every `interval_ms`.
+    Type {
+        text: String,
+        #[serde(default = "default_interval")]
+        interval_ms: u64,
+    },
+    Paste {
+        text: String,
+    },
+    Pause {
+        ms: u64,
+    },
+    Backspace {
+        count: u32,
+    },
+}
+
+fn default_interval() -> u64 {
+    120
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Expect {
+    /// Modes that must be present.
+    answer_mode: Vec<AnswerMode>,
+    /// Modes that must be absent.
+    not_answer_mode: Vec<AnswerMode>,
+    primary_mode: Option<AnswerMode>,
+    tone_hint: Option<ToneHint>,
+    scope_hint: Option<ScopeHint>,
+    depth_hint: Option<DepthHint>,
+    user_state: Vec<UserState>,
+    not_user_state: Vec<UserState>,
+    pragmatic_intent: Vec<PragmaticIntent>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    name: String,
+    steps: Vec<Step>,
+    /// Defaults to the text the steps produce.
+    final_text: Option<String>,
+    expect: Expect,
+}
+
+impl Scenario {
+    fn run(&self) -> InputProfile {
+        let core = IflCore::new();
+        let id = core.start_message().unwrap();
+        let mut ts = 1000;
+        let mut text = String::new();
+        for step in &self.steps {
+            match step {
+                Step::Type {
+                    text: typed,
+                    interval_ms,
+                } => {
+                    for ch in typed.chars() {
+                        core.push_event(&id, InputEvent::KeyInsert { ch, ts })
+                            .unwrap();
+                        ts += interval_ms;
+                    }
+                    text.push_str(typed);
+                }
+                Step::Paste { text: pasted } => {
+                    core.push_event(&id, InputEvent::paste(pasted, ts)).unwrap();
+                    ts += 200;
+                    text.push_str(pasted);
+                }
+                Step::Pause { ms } => ts += ms,
+                Step::Backspace { count } => {
+                    core.push_event(
+                        &id,
+                        InputEvent::KeyDelete {
+                            kind: DeleteKind::Backspace,
+                            count: *count,
+                            ts,
+                        },
+                    )
+                    .unwrap();
+                    ts += 100;
+                    for _ in 0..*count {
+                        text.pop();
+                    }
+                }
+            }
+        }
+        core.push_event(&id, InputEvent::Submit { ts }).unwrap();
+        let final_text = self.final_text.as_deref().unwrap_or(&text);
+        serde_json::from_str(&core.finalize_message(&id, final_text).unwrap()).unwrap()
+    }
+
+    /// Every unmet expectation, as a readable message.
+    fn check(&self, profile: &InputProfile) -> Vec<String> {
+        let tags = &profile.tags;
+        let expect = &self.expect;
+        let mut failures = Vec::new();
+        for mode in &expect.answer_mode {
+            if !tags.answer_mode.contains(mode) {
+                failures.push(format!("missing mode {:?} in {:?}", mode, tags.answer_mode));
+            }
+        }
+        for mode in &expect.not_answer_mode {
+            if tags.answer_mode.contains(mode) {
+                failures.push(format!("unexpected mode {:?}", mode));
+            }
+        }
+        if let Some(mode) = &expect.primary_mode {
+            if tags.answer_mode.first() != Some(mode) {
+                failures.push(format!(
+                    "primary mode {:?}, expected {:?}",
+                    tags.answer_mode.first(),
+                    mode
+                ));
+            }
+        }
+        if let Some(tone) = &expect.tone_hint {
+            if &tags.tone_hint != tone {
+                failures.push(format!("tone {:?}, expected {:?}", tags.tone_hint, tone));
+            }
+        }
+        if let Some(scope) = &expect.scope_hint {
+            if &tags.scope_hint != scope {
+                failures.push(format!("scope {:?}, expected {:?}", tags.scope_hint, scope));
+            }
+        }
+        if let Some(depth) = &expect.depth_hint {
+            if &tags.depth_hint != depth {
+                failures.push(format!("depth {:?}, expected {:?}", tags.depth_hint, depth));
+            }
+        }
+        for state in &expect.user_state {
+            if !tags.user_state.contains(state) {
+                failures.push(format!(
+                    "missing state {:?} in {:?}",
+                    state, tags.user_state
+                ));
+            }
+        }
+        for state in &expect.not_user_state {
+            if tags.user_state.contains(state) {
+                failures.push(format!("unexpected state {:?}", state));
+            }
+        }
+        for intent in &expect.pragmatic_intent {
+            if !tags.pragmatic_intent.contains(intent) {
+                failures.push(format!(
+                    "missing intent {:?} in {:?}",
+                    intent, tags.pragmatic_intent
+                ));
+            }
+        }
+        failures
+    }
+}
+
+#[test]
+fn test_scenario_files() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cases");
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "yaml"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scenarios in {}", dir.display());
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let file = path.file_name().unwrap().to_string_lossy();
+        let content = std::fs::read_to_string(path).unwrap();
+        let scenario: Scenario = match serde_yaml::from_str(&content) {
+            Ok(scenario) => scenario,
+            Err(e) => {
+                failures.push(format!("{}: invalid scenario: {}", file, e));
+                continue;
+            }
+        };
+        let profile = scenario.run();
+        for failure in scenario.check(&profile) {
+            failures.push(format!("{} ({}): {}", file, scenario.name, failure));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} scenario expectation(s) failed:\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}